@@ -0,0 +1,122 @@
+//! Benchmarks of `solve` on levels of `extra/` and of its hot paths. Run with `cargo bench`.
+#[macro_use]
+extern crate criterion;
+extern crate hexcells_solver;
+
+use criterion::{black_box, Criterion};
+use hexcells_solver::constraint;
+use hexcells_solver::defn::{Cell, Color, Defn, Modifier};
+use hexcells_solver::misc;
+use hexcells_solver::{of_string, solve, Coords, Env, Outcome};
+
+/// Levels of increasing difficulty, with the `technique_summary` of their solve: compound steps,
+/// then global steps over a few and over many constraints.
+static LEVELS: [(&str, &str, &str); 3] = [
+    (
+        "ml_1",
+        include_str!("../extra/ml_1.txt"),
+        "needs compound up to size 2",
+    ),
+    (
+        "ihoc",
+        include_str!("../extra/ihoc.txt"),
+        "needs global over 3 constraints",
+    ),
+    (
+        "the_trial",
+        include_str!("../extra/the_trial.txt"),
+        "needs global over 15 constraints",
+    ),
+];
+
+/// A hexagon where every hidden cell is blue and next to a revealed cell, none of the levels of
+/// `extra/` is solved with trivial steps only.
+fn trivial_level() -> Defn {
+    let mut defn = Defn::new();
+    for coords in Coords::new(0, 0, 0).neighbors_within(3) {
+        let cell = if (coords.q() - coords.r()) % 3 == 0 {
+            Cell::Zone6 {
+                revealed: true,
+                m: Modifier::Anywhere,
+            }
+        } else {
+            Cell::Zone0 {
+                revealed: false,
+                color: Color::Blue,
+            }
+        };
+        defn.insert(coords, cell);
+    }
+    defn
+}
+
+fn bench_solve(c: &mut Criterion) {
+    let mut group = c.benchmark_group("solve");
+    group.sample_size(10);
+    let levels = LEVELS
+        .iter()
+        .map(|(name, strdefn, summary)| (*name, of_string(strdefn).unwrap(), *summary));
+    let trivial = ("trivial", trivial_level(), "solvable with trivial only");
+    for (name, defn, summary) in std::iter::once(trivial).chain(levels) {
+        let outcome = solve(&mut Env::new(60), &defn, None);
+        assert_eq!(outcome.technique_summary(), summary, "{}", name);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let outcome = solve(&mut Env::new(60), black_box(&defn), None);
+                assert!(matches!(outcome, Outcome::Solved(_)));
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_merge(c: &mut Criterion) {
+    // A hexagon of hidden cells, blue and black in turn
+    let center = Coords::new(0, 0, 0);
+    let mut defn = Defn::new();
+    for coords in center.neighbors_within(3) {
+        let cell = if (coords.q() - coords.r()) % 2 == 0 {
+            Cell::Zone6 {
+                revealed: false,
+                m: Modifier::Anywhere,
+            }
+        } else {
+            Cell::Zone0 {
+                revealed: false,
+                color: Color::Blue,
+            }
+        };
+        defn.insert(coords, cell);
+    }
+    let neighbor = center.neighbors6()[0];
+    let zone6 = constraint::zone6(&defn, center, Modifier::Anywhere);
+    let together = constraint::zone6(&defn, neighbor, Modifier::Together);
+    let zone18 = constraint::zone18(&defn, neighbor);
+
+    c.bench_function("merge/zone6_together", |b| {
+        b.iter(|| black_box(&zone6).merge(black_box(&together)))
+    });
+    c.bench_function("merge/zone6_zone18", |b| {
+        b.iter(|| black_box(&zone6).merge(black_box(&zone18)))
+    });
+}
+
+/// Every binomial coefficient for `n` in `ns`.
+fn all_n_choose_k(ns: std::ops::RangeInclusive<u64>) -> Vec<Option<u64>> {
+    ns.flat_map(|n| (0..=n).map(move |k| misc::n_choose_k(black_box(n), black_box(k))))
+        .collect()
+}
+
+fn bench_n_choose_k(c: &mut Criterion) {
+    // The table covers up to `n = 68`, the first row that overflows a `u64`
+    c.bench_function("n_choose_k/table", |b| b.iter(|| all_n_choose_k(0..=68)));
+    c.bench_function("n_choose_k/beyond_table", |b| {
+        b.iter(|| all_n_choose_k(69..=80))
+    });
+    c.bench_function("n_choose_k_u128", |b| {
+        b.iter(|| misc::n_choose_k_u128(black_box(120), black_box(60)))
+    });
+}
+
+criterion_group!(benches, bench_solve, bench_merge, bench_n_choose_k);
+criterion_main!(benches);
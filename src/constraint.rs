@@ -1,5 +1,9 @@
 /// Conversion of game constraints from [Defn] to [Multiverse] ready for solving:
 /// [line], [zone6] and [zone18]
+///
+/// A scope only holds the cells of the grid that have a color. The off-board cells, the
+/// `Cell::Empty` and the `Cell::Line` ones are left out, and for the `{n}` and `-n-` zones they
+/// break the ring of neighbors.
 use itertools::Itertools;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
@@ -11,15 +15,16 @@ use defn::Orientation;
 use misc::Coords;
 use multiverse::Layout;
 use multiverse::Multiverse;
+use multiverse::State;
 
 /// This multiverse constructor is common for Zone6 anywhere, Line anywhere and Zone18
 /// The output contains a single layout
-fn distribute_anywhere(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse {
-    if scope_vec.len() == 0 {
+fn distribute_anywhere(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    if scope_vec.is_empty() {
         assert!(blue_count == 0);
         return Multiverse::empty();
     }
-    assert!(scope_vec.len() > 0);
+    assert!(!scope_vec.is_empty());
     assert!(scope_vec.len() >= blue_count);
     let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
     let layout = Layout::new(BTreeMap::from([(scope_set.clone(), blue_count as u16)]));
@@ -27,10 +32,19 @@ fn distribute_anywhere(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse
     Multiverse::new(scope_set, layouts)
 }
 
+/// The multiverse without solution over `scope_vec`, for a `{n}` or `-n-` modifier that the grid
+/// doesn't satisfy. Without cells it is empty rather than stuck.
+fn stuck(scope_vec: &[Coords]) -> Multiverse {
+    Multiverse::new(scope_vec.iter().cloned().collect(), vec![])
+}
+
 /// This multiverse constructor is for Line together
 /// The output has one layout per solution
-fn distribute_together(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse {
-    assert!(scope_vec.len() > 0);
+fn distribute_together(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    if scope_vec.is_empty() {
+        assert!(blue_count == 0);
+        return Multiverse::empty();
+    }
     assert!(scope_vec.len() >= blue_count);
     let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
     let solution_count = {
@@ -47,10 +61,9 @@ fn distribute_together(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse
     for i0 in 0..solution_count {
         let mut blues = BTreeSet::new();
         let mut blacks = scope_set.clone();
-        for i in i0..(i0 + blue_count) {
-            let coords = scope_vec[i];
-            assert!(blacks.remove(&coords));
-            blues.insert(coords);
+        for coords in &scope_vec[i0..(i0 + blue_count)] {
+            assert!(blacks.remove(coords));
+            blues.insert(*coords);
         }
         assert_eq!(blues.len(), blue_count);
         assert_eq!(blacks.len() + blues.len(), scope_vec.len());
@@ -69,24 +82,20 @@ fn distribute_together(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse
 }
 
 /// This multiverse constructor is for Line separated
-/// It is the only constructor that creates layouts with overlapping solutions
-fn distribute_separated(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse {
-    assert!(blue_count >= 2);
+/// It is the only constructor that creates layouts with overlapping solutions. The output is
+/// stuck if the blues can't be separated.
+fn distribute_separated(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    if blue_count < 2 || scope_vec.len() <= blue_count {
+        return stuck(scope_vec);
+    }
     assert!(scope_vec.len() >= 3);
-    assert!(scope_vec.len() > blue_count);
     let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
     let pivot_position_count = scope_vec.len() - 2;
     let mut layouts = vec![];
     for ipivot in 1..(1 + pivot_position_count) {
-        let mut before = BTreeSet::new();
+        let before: BTreeSet<_> = scope_vec[..ipivot].iter().cloned().collect();
         let pivot = BTreeSet::from([scope_vec[ipivot]]);
-        let mut after = BTreeSet::new();
-        for i in 0..ipivot {
-            before.insert(scope_vec[i]);
-        }
-        for i in (ipivot + 1)..scope_vec.len() {
-            after.insert(scope_vec[i]);
-        }
+        let after: BTreeSet<_> = scope_vec[(ipivot + 1)..].iter().cloned().collect();
         assert_eq!(before.len() + 1 + after.len(), scope_vec.len());
         for i in 1..blue_count {
             let j = blue_count - i;
@@ -146,28 +155,55 @@ fn has_compatible_contiguity(
     true
 }
 
+/// Whether the actual colors of the grid satisfy the `{n}` or `-n-` modifier of the `Zone6` or
+/// `Line` cell at `coords`, as the constructors of this module understand them. Always true for
+/// the other cells. The constructors may give a stuck multiverse on a grid where this doesn't
+/// hold.
+pub(crate) fn modifier_holds(defn: &defn::Defn, coords: Coords) -> bool {
+    let is_blue = |c: &Coords| defn.get(c).and_then(defn::color_of_cell) == Some(Color::Blue);
+    match defn[&coords] {
+        defn::Cell::Zone6 { m, .. } if m != Modifier::Anywhere => {
+            // The gaps of the ring count as black
+            let (blues, blacks): (BTreeSet<usize>, BTreeSet<usize>) =
+                (0..6).partition(|i| is_blue(&coords.neighbors6()[*i]));
+            if blues.is_empty() || blacks.is_empty() {
+                return m == Modifier::Together;
+            }
+            has_compatible_contiguity(&blues, &blacks, m == Modifier::Together)
+        }
+        defn::Cell::Line { m, o } if m != Modifier::Anywhere => {
+            let scope = defn::scope_of_line(defn, coords, o);
+            let blues: Vec<_> = (0..scope.len()).filter(|i| is_blue(&scope[*i])).collect();
+            let together = match (blues.first(), blues.last()) {
+                (Some(first), Some(last)) => last - first == blues.len() - 1,
+                _ => true,
+            };
+            together == (m == Modifier::Together)
+        }
+        _ => true,
+    }
+}
+
 /// This multiverse constructor is for Zone6 together and Zone6 separated
-/// The output contains one layout per solution
+/// The output contains one layout per solution, it is stuck if there is none
 fn distribute_in_ring(
     scope_arr: &[(Coords, bool); 6],
     blue_count: usize,
     together: bool,
 ) -> Multiverse {
+    let scope_vec: Vec<_> = scope_arr
+        .iter()
+        .filter_map(|(coords, is_gap)| if *is_gap { None } else { Some(*coords) })
+        .collect();
     if together {
-        let scope_vec: Vec<_> = scope_arr
-            .iter()
-            .filter_map(|(coords, is_gap)| if *is_gap { None } else { Some(*coords) })
-            .collect();
         if blue_count <= 1 || blue_count == scope_vec.len() {
             return distribute_anywhere(&scope_vec, blue_count);
         }
-    } else {
-        assert!(blue_count >= 2);
+    } else if blue_count < 2 || blue_count == 6 {
+        // The blues can't be separated
+        return stuck(&scope_vec);
     }
-    let scope_set: BTreeSet<_> = scope_arr
-        .iter()
-        .filter_map(|(c, is_gap)| if *is_gap { None } else { Some(*c) })
-        .collect();
+    let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
     let mut layouts = vec![];
     let idxs: BTreeSet<_> = (0..6).collect();
     for blues in idxs.iter().combinations(blue_count) {
@@ -200,10 +236,130 @@ fn distribute_in_ring(
         }
         layouts.push(Layout::new(bc.into_iter().collect()));
     }
-    assert!(layouts.len() > 0);
     Multiverse::new(scope_set, layouts)
 }
 
+pub fn zone6(defn: &defn::Defn, coords: Coords, modifier: Modifier) -> Multiverse {
+    let mut blue_count = 0;
+    let neighborhood = coords.neighbors6();
+    let scope_arr = neighborhood.map(|c| match defn.get(&c).and_then(defn::color_of_cell) {
+        None => (c, true),
+        Some(Color::Blue) => {
+            blue_count += 1;
+            (c, false)
+        }
+        Some(Color::Black) => (c, false),
+    });
+    match modifier {
+        Modifier::Anywhere => {
+            let scope: Vec<_> = scope_arr
+                .iter()
+                .filter_map(|(c, is_gap)| if *is_gap { None } else { Some(*c) })
+                .collect();
+            distribute_anywhere(&scope, blue_count)
+        }
+        Modifier::Together => distribute_in_ring(&scope_arr, blue_count, true),
+        Modifier::Separated => distribute_in_ring(&scope_arr, blue_count, false),
+    }
+}
+
+/// The colorings of the colored cells of `scope` with exactly `count` blues, whatever their
+/// actual colors in `defn`. The cells of `scope` that are empty or off-board are ignored. This is
+/// the constraint of a zone18 or of the global blue count, over any region.
+///
+/// Panics if `count` is larger than the number of colored cells of `scope`.
+pub fn exactly(defn: &defn::Defn, scope: BTreeSet<Coords>, count: u32) -> Multiverse {
+    let scope_vec: Vec<_> = scope
+        .into_iter()
+        .filter(|c| defn.get(c).and_then(defn::color_of_cell).is_some())
+        .collect();
+    assert!(
+        scope_vec.len() >= count as usize,
+        "Can't place {} blues among {} cells",
+        count,
+        scope_vec.len()
+    );
+    distribute_anywhere(&scope_vec, count as usize)
+}
+
+/// The number of blue cells of `scope` in `defn`.
+fn blue_count(defn: &defn::Defn, scope: &BTreeSet<Coords>) -> u32 {
+    scope
+        .iter()
+        .filter(|c| defn.get(c).and_then(defn::color_of_cell) == Some(Color::Blue))
+        .count() as u32
+}
+
+pub fn zone18(defn: &defn::Defn, coords: Coords) -> Multiverse {
+    let scope: BTreeSet<_> = coords.neighbors18().iter().cloned().collect();
+    let count = blue_count(defn, &scope);
+    exactly(defn, scope, count)
+}
+
+/// The constraint of the line at `coords`: the colored cells met when walking the board from
+/// `coords` in the direction of `orientation`, up to its far edge. Gaps of empty cells don't stop
+/// the walk. As in the game, the cells behind the line are not counted.
+pub fn line(
+    defn: &defn::Defn,
+    coords: Coords,
+    orientation: Orientation,
+    modifier: Modifier,
+) -> Multiverse {
+    let scope = defn::scope_of_line(defn, coords, orientation);
+    let blue_count = scope
+        .iter()
+        .filter(|c| defn::color_of_cell(&defn[c]) == Some(Color::Blue))
+        .count();
+    match modifier {
+        Modifier::Anywhere => distribute_anywhere(&scope, blue_count),
+        Modifier::Together => distribute_together(&scope, blue_count),
+        Modifier::Separated => distribute_separated(&scope, blue_count),
+    }
+}
+
+/// The constraint `mv` of the number borne by the cell at `coords`, as seen by a player who
+/// doesn't know the color of that cell yet: `mv` holds if `coords` turns out to be `color`, the
+/// color of the cells that bear that number, and the other cells of its scope are free otherwise.
+/// The scope of the result is the one of `mv` along with `coords`.
+pub fn conditional(coords: Coords, color: Color, mv: &Multiverse) -> Multiverse {
+    assert!(!mv.scope.contains(&coords));
+    if mv.state() == State::Empty {
+        return mv.clone();
+    }
+    let blues = |color| match color {
+        Color::Blue => 1,
+        Color::Black => 0,
+    };
+    let other_color = match color {
+        Color::Blue => Color::Black,
+        Color::Black => Color::Blue,
+    };
+    let mut layouts: Vec<_> = mv
+        .layouts
+        .iter()
+        .map(|lay| {
+            let mut bc = lay.binomial_coefs.clone();
+            bc.insert(BTreeSet::from([coords]), blues(color));
+            Layout::new(bc)
+        })
+        .collect();
+    for blue_count in 0..=mv.scope.len() as u16 {
+        layouts.push(Layout::new(BTreeMap::from([
+            (BTreeSet::from([coords]), blues(other_color)),
+            (mv.scope.clone(), blue_count),
+        ])));
+    }
+    let mut scope = mv.scope.clone();
+    scope.insert(coords);
+    Multiverse::new(scope, layouts)
+}
+
+pub fn global_blue_count(defn: &defn::Defn) -> Multiverse {
+    let scope: BTreeSet<_> = defn.keys().cloned().collect();
+    let count = blue_count(defn, &scope);
+    exactly(defn, scope, count)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -214,19 +370,23 @@ mod tests {
         misc::n_choose_k(n, k).unwrap()
     }
 
+    /// A hidden cell without a number.
+    fn hidden(color: Color) -> defn::Cell {
+        defn::Cell::Zone0 {
+            revealed: false,
+            color,
+        }
+    }
+
     fn mock_zone6_anywhere(center: &Coords, blue_count: usize) -> Multiverse {
-        distribute_anywhere(&center.neighbors6().iter().cloned().collect(), blue_count)
+        distribute_anywhere(&center.neighbors6(), blue_count)
     }
 
     fn mock_line_together(topmost: &Coords, cell_count: usize, blue_count: usize) -> Multiverse {
         // Towards down
         let mut scope_vec = vec![];
         for i in 0..(cell_count as isize) {
-            scope_vec.push(Coords::new(
-                topmost.q() + 0 * i,
-                topmost.r() + 1 * i,
-                topmost.s() - 1 * i,
-            ))
+            scope_vec.push(Coords::new(topmost.q(), topmost.r() + i, topmost.s() - i))
         }
         distribute_together(&scope_vec, blue_count)
     }
@@ -243,11 +403,7 @@ mod tests {
         // Towards down
         let mut scope_vec = vec![];
         for i in 0..(cell_count as isize) {
-            scope_vec.push(Coords::new(
-                topmost.q() + 0 * i,
-                topmost.r() + 1 * i,
-                topmost.s() - 1 * i,
-            ))
+            scope_vec.push(Coords::new(topmost.q(), topmost.r() + i, topmost.s() - i))
         }
         distribute_separated(&scope_vec, blue_count)
     }
@@ -305,6 +461,120 @@ mod tests {
         test_two_zone6_horizontal_neighbors(6, 6, 10, 1);
     }
 
+    #[test]
+    pub fn test_off_board_neighbors() {
+        // A corner cell with 2 colored neighbors, the other 4 are empty, a line or off-board
+        let center = Coords::new(0, 0, 0);
+        let n = center.neighbors6();
+        let defn = defn::Defn::from([
+            (
+                center,
+                defn::Cell::Zone6 {
+                    revealed: true,
+                    m: Modifier::Anywhere,
+                },
+            ),
+            (n[0], defn::Cell::Zone18 { revealed: false }),
+            (n[1], hidden(Color::Black)),
+            (n[2], defn::Cell::Empty),
+            (
+                n[3],
+                defn::Cell::Line {
+                    o: Orientation::Bottom,
+                    m: Modifier::Anywhere,
+                },
+            ),
+        ]);
+        let scope = BTreeSet::from([n[0], n[1]]);
+
+        let mv = zone6(&defn, center, Modifier::Anywhere);
+        assert_eq!(mv.scope, scope);
+        assert_eq!(2, mv.solution_count_upper_bound().unwrap());
+        let mv = zone6(&defn, center, Modifier::Together);
+        assert_eq!(mv.scope, scope);
+        assert_eq!(2, mv.solution_count_upper_bound().unwrap());
+        let mv = zone18(&defn, center);
+        assert_eq!(mv.scope, scope);
+        assert_eq!(2, mv.solution_count_upper_bound().unwrap());
+    }
+
+    #[test]
+    pub fn test_exactly() {
+        let center = Coords::new(0, 0, 0);
+        let n = center.neighbors6();
+        let defn = defn::Defn::from([
+            (center, defn::Cell::Zone18 { revealed: true }),
+            (n[0], hidden(Color::Blue)),
+            (n[1], hidden(Color::Black)),
+            (n[2], hidden(Color::Black)),
+            (n[3], defn::Cell::Empty),
+        ]);
+        // The empty and off-board cells drop out of the scope
+        let region: BTreeSet<_> = n.iter().cloned().collect();
+        let mv = exactly(&defn, region.clone(), 2);
+        assert_eq!(mv.scope, BTreeSet::from([n[0], n[1], n[2]]));
+        assert_eq!(mv.solution_count_upper_bound(), Some(3));
+        // The actual colors don't matter
+        let mv = exactly(&defn, BTreeSet::from([n[1], n[2]]), 2);
+        assert_eq!(
+            mv.invariants(),
+            BTreeMap::from([(n[1], Color::Blue), (n[2], Color::Blue)])
+        );
+        assert_eq!(exactly(&defn, BTreeSet::new(), 0).state(), State::Empty);
+        assert_eq!(
+            exactly(&defn, region, 1),
+            zone6(&defn, center, Modifier::Anywhere)
+        );
+        // The zone18 cell at the center is blue too
+        let everything: BTreeSet<_> = defn.keys().cloned().collect();
+        assert_eq!(exactly(&defn, everything, 2), global_blue_count(&defn));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't place 3 blues among 2 cells")]
+    pub fn test_exactly_too_many_blues() {
+        let n = Coords::new(0, 0, 0).neighbors6();
+        let defn = defn::Defn::from([
+            (n[0], defn::Cell::Zone18 { revealed: false }),
+            (n[1], defn::Cell::Zone18 { revealed: false }),
+        ]);
+        exactly(&defn, BTreeSet::from([n[0], n[1], n[2]]), 3);
+    }
+
+    #[test]
+    pub fn test_line_spans_the_board() {
+        // A line pointing down a column, with a gap of 20 empty cells, and a cell behind the line
+        let at = |i| Coords::new(0, i, -i);
+        let mut defn = defn::Defn::from([
+            (
+                at(0),
+                defn::Cell::Line {
+                    o: Orientation::Bottom,
+                    m: Modifier::Anywhere,
+                },
+            ),
+            (at(-1), hidden(Color::Black)),
+            (at(1), hidden(Color::Blue)),
+            (at(2), hidden(Color::Blue)),
+            (at(23), hidden(Color::Blue)),
+        ]);
+        for i in 3..23 {
+            defn.insert(at(i), defn::Cell::Empty);
+        }
+
+        let mv = line(&defn, at(0), Orientation::Bottom, Modifier::Anywhere);
+        assert_eq!(mv.scope, BTreeSet::from([at(1), at(2), at(23)]));
+        assert_eq!(
+            mv.invariants(),
+            BTreeMap::from([
+                (at(1), Color::Blue),
+                (at(2), Color::Blue),
+                (at(23), Color::Blue)
+            ])
+        );
+        assert_eq!(defn::number_of_cell(&defn, &at(0)), Some(3));
+    }
+
     #[test]
     pub fn test_line_together() {
         // A line of len 5 with 3 together blues
@@ -378,6 +648,12 @@ mod tests {
         let mv = mv0.merge(&mv1);
         assert_eq!(1, mv.solution_count_upper_bound().unwrap());
         assert_eq!(9, mv.invariants().len());
+
+        // Less than 2 blues, or no room for a black between them
+        for (cell_count, blue_count) in [(3, 1), (3, 0), (3, 3), (2, 2)] {
+            let mv = mock_line_separated(&Coords::new(0, 0, 0), cell_count, blue_count);
+            assert_eq!(mv.state(), State::Stuck);
+        }
     }
 
     #[test]
@@ -441,6 +717,122 @@ mod tests {
         let mv = mv0.merge(&mv1);
         assert_eq!(6, mv.solution_count_upper_bound().unwrap());
         assert_eq!(4, mv.invariants().len());
+
+        // Less than 2 blues, or no black between them
+        for blue_count in [0, 1, 5, 6] {
+            let mv = mock_ring_separated(&Coords::new(0, 0, 0), blue_count);
+            assert_eq!(mv.state(), State::Stuck);
+        }
+    }
+
+    #[test]
+    pub fn test_learn() {
+        // A line of len 5 with 3 together blues
+        let top = Coords::new(0, 0, 0);
+        let cells: Vec<_> = (0..5).map(|i| Coords::new(0, i, -i)).collect();
+        let mv0 = mock_line_together(&top, 5, 3);
+
+        // The topmost is blue: only the topmost solution remains
+        let mv = mv0.learn(&cells[0], Color::Blue);
+        assert_eq!(mv.state(), State::Running);
+        assert_eq!(4, mv.scope.len());
+        assert_eq!(1, mv.solution_count_upper_bound().unwrap());
+        assert_eq!(4, mv.invariants().len());
+
+        // The topmost is black: the 2 middle cells of the remaining 4 are blue
+        let mv = mv0.learn(&cells[0], Color::Black);
+        assert_eq!(2, mv.solution_count_upper_bound().unwrap());
+        assert_eq!(
+            mv.invariants(),
+            BTreeMap::from([(cells[2], Color::Blue), (cells[3], Color::Blue)])
+        );
+
+        // Learning a color that contradicts all solutions
+        let mv = mv0.learn(&cells[2], Color::Black);
+        assert_eq!(mv.state(), State::Stuck);
+
+        // Learning all the cells
+        let mut mv = mv0.clone();
+        for (i, c) in cells.iter().enumerate() {
+            let color = if (1..4).contains(&i) {
+                Color::Blue
+            } else {
+                Color::Black
+            };
+            mv = mv.learn(c, color);
+        }
+        assert_eq!(mv.state(), State::Empty);
+
+        // Learning the wrong color for the last cell
+        let mut mv = mv0.learn(&cells[0], Color::Black);
+        for c in &cells[1..4] {
+            mv = mv.learn(c, Color::Blue);
+        }
+        assert_eq!(mv.scope.len(), 1);
+        assert_eq!(mv.learn(&cells[4], Color::Blue).state(), State::Stuck);
+
+        // Learning all but one cell leaves that cell alone in the scope, with its color as the
+        // only invariant
+        let c = Coords::new(0, 0, 0);
+        let neighbors = c.neighbors6();
+        let mut mv = mock_zone6_anywhere(&c, 2);
+        for (i, n) in neighbors[..5].iter().enumerate() {
+            mv.learn_mut(n, if i < 2 { Color::Blue } else { Color::Black });
+        }
+        assert_eq!(mv.scope, BTreeSet::from([neighbors[5]]));
+        assert_eq!(mv.state(), State::Running);
+        assert_eq!(
+            mv.invariants(),
+            BTreeMap::from([(neighbors[5], Color::Black)])
+        );
+        mv.learn_mut(&neighbors[5], Color::Black);
+        assert_eq!(mv.state(), State::Empty);
+
+        // In place
+        let mut mv = mv0.clone();
+        mv.learn_mut(&cells[0], Color::Black);
+        assert_eq!(
+            mv.invariants(),
+            mv0.learn(&cells[0], Color::Black).invariants()
+        );
+        mv.learn_mut(&cells[2], Color::Black);
+        assert_eq!(mv.state(), State::Stuck);
+        assert_eq!(mv.scope.len(), 4);
+
+        // Each solution gives one color to a cell, layouts with keys of several cells included
+        let mv0 = mock_line_separated(&top, 5, 3).merge(&mock_zone6_anywhere(&cells[1], 2));
+        let count = |mv: &Multiverse| mv.solution_count_upper_bound().unwrap();
+        for c in &mv0.scope {
+            let (blue, black) = (mv0.learn(c, Color::Blue), mv0.learn(c, Color::Black));
+            assert_eq!(count(&blue) + count(&black), count(&mv0));
+            assert_eq!(mv0.allows(c, Color::Blue), blue.state() != State::Stuck);
+            assert_eq!(mv0.allows(c, Color::Black), black.state() != State::Stuck);
+            let mut mv = mv0.clone();
+            mv.learn_mut(c, Color::Blue);
+            assert_eq!(mv, blue);
+        }
+    }
+
+    #[test]
+    pub fn test_conditional() {
+        let center = Coords::new(0, 0, 0);
+        let n = center.neighbors6();
+        let mv = conditional(center, Color::Black, &mock_zone6_anywhere(&center, 6));
+        assert_eq!(mv.scope.len(), 7);
+        assert!(mv.invariants().is_empty());
+        // Once the cell is black its number holds
+        assert_eq!(
+            mv.learn(&center, Color::Black).invariants(),
+            n.iter().map(|c| (*c, Color::Blue)).collect()
+        );
+        // Otherwise its neighbors can take any color
+        let mv = mv.learn(&center, Color::Blue);
+        assert_eq!(mv.solution_count_upper_bound(), Some(64));
+        assert_ne!(mv.learn(&n[0], Color::Black).state(), State::Stuck);
+        assert_eq!(
+            conditional(center, Color::Blue, &Multiverse::empty()).state(),
+            State::Empty
+        );
     }
 
     #[test]
@@ -449,15 +841,15 @@ mod tests {
         let empty = Multiverse::empty();
         assert_eq!(empty.state(), State::Empty);
         assert_eq!(0, empty.solution_count_upper_bound().unwrap());
-        assert!(empty.invariants().len() == 0);
+        assert!(empty.invariants().is_empty());
         let empty = Multiverse::new(BTreeSet::new(), vec![]);
         assert_eq!(empty.state(), State::Empty);
         assert_eq!(0, empty.solution_count_upper_bound().unwrap());
-        assert!(empty.invariants().len() == 0);
+        assert!(empty.invariants().is_empty());
         let empty = empty.merge(&empty);
         assert_eq!(empty.state(), State::Empty);
         assert_eq!(0, empty.solution_count_upper_bound().unwrap());
-        assert!(empty.invariants().len() == 0);
+        assert!(empty.invariants().is_empty());
 
         // Intersection with empty
         let c = Coords::new(0, 0, 0);
@@ -465,7 +857,7 @@ mod tests {
         let running = empty.merge(&running);
         assert_eq!(running.state(), State::Running);
         assert_eq!(nk(6, 3), running.solution_count_upper_bound().unwrap());
-        assert!(running.invariants().len() == 0);
+        assert!(running.invariants().is_empty());
 
         // Stuck
         let stuck = Multiverse::new(BTreeSet::from([c]), vec![]);
@@ -479,101 +871,89 @@ mod tests {
         let mv = running2.merge(&running);
         assert_eq!(mv.state(), State::Running);
         assert_eq!(nk(6, 3).pow(2), mv.solution_count_upper_bound().unwrap());
-        assert!(mv.invariants().len() == 0);
-    }
-}
-
-pub fn zone6(defn: &defn::Defn, coords: Coords, modifier: Modifier) -> Multiverse {
-    let mut blue_count = 0;
-    let neighborhood = coords.neighbors6();
-    let scope_arr = neighborhood.map(|c| match defn.get(&c).and_then(defn::color_of_cell) {
-        None => (c, true),
-        Some(Color::Blue) => {
-            blue_count += 1;
-            (c, false)
-        }
-        Some(Color::Black) => (c, false),
-    });
-    match modifier {
-        Modifier::Anywhere => {
-            let scope = scope_arr
-                .iter()
-                .filter_map(|(c, is_gap)| if *is_gap { None } else { Some(*c) })
-                .collect();
-            distribute_anywhere(&scope, blue_count)
-        }
-        Modifier::Together => distribute_in_ring(&scope_arr, blue_count, true),
-        Modifier::Separated => distribute_in_ring(&scope_arr, blue_count, false),
+        assert!(mv.invariants().is_empty());
     }
-}
 
-pub fn zone18(defn: &defn::Defn, coords: Coords) -> Multiverse {
-    let mut scope = Vec::new();
-    let mut blue_count = 0;
-    for c in coords.neighbors18() {
-        match defn.get(&c).and_then(defn::color_of_cell) {
-            None => (),
-            Some(Color::Blue) => {
-                blue_count += 1;
-                scope.push(c);
-            }
-            Some(Color::Black) => {
-                scope.push(c);
-            }
+    #[test]
+    pub fn test_merge_states() {
+        let c = Coords::new(0, 0, 0);
+        let c2 = Coords::new(10, 0, -10);
+        let empty = Multiverse::empty();
+        let running = mock_zone6_anywhere(&c, 3);
+        let stuck = Multiverse::new(BTreeSet::from([c2]), vec![]);
+        let all = [&empty, &running, &stuck];
+        for (a, b) in all.iter().cartesian_product(all.iter()) {
+            let mv = a.merge(b);
+            assert_eq!(mv.scope, a.scope.union(&b.scope).cloned().collect());
+            let expected = match (a.state(), b.state()) {
+                (State::Stuck, _) | (_, State::Stuck) => State::Stuck,
+                (State::Running, _) | (_, State::Running) => State::Running,
+                (State::Empty, State::Empty) => State::Empty,
+            };
+            assert_eq!(mv.state(), expected);
         }
+        // Merging with empty is a no-op
+        assert_eq!(running.merge(&empty).layouts.len(), running.layouts.len());
+        assert_eq!(empty.merge(&running).layouts.len(), running.layouts.len());
+
+        // Their merge holds 3 layouts
+        let mv0 = mock_zone6_anywhere(&c, 3);
+        let mv1 = mock_zone6_anywhere(&c.neighbors6()[0], 3);
+        assert!(mv0.try_merge(&mv1, 2).is_err());
+        assert_eq!(mv0.try_merge(&mv1, 3).unwrap().layouts.len(), 3);
+        assert_eq!(mv0.merge(&mv1).layouts.len(), 3);
+
+        // Two running multiverses that contradict each other
+        let line = |blue_count| distribute_anywhere(&c.neighbors6()[..2], blue_count);
+        assert_eq!(line(0).merge(&line(2)).state(), State::Stuck);
     }
-    distribute_anywhere(&scope, blue_count)
-}
 
-pub fn line(
-    defn: &defn::Defn,
-    coords: Coords,
-    orientation: Orientation,
-    modifier: Modifier,
-) -> Multiverse {
-    let (dq, dr, ds) = match orientation {
-        Orientation::Bottom => (0, 1, -1),
-        Orientation::BottomRight => (1, 0, -1),
-        Orientation::BottomLeft => (-1, 1, 0),
-    };
-    let (q, r, s) = (coords.q(), coords.r(), coords.s());
-    let mut scope = Vec::new();
-    let mut blue_count = 0;
-    for i in 0..33 {
-        // 33 is more than the max diagonal len of a grid
-        let c = Coords::new(q + dq * i, r + dr * i, s + ds * i);
-        match defn.get(&c).and_then(defn::color_of_cell) {
-            None => (),
-            Some(Color::Blue) => {
-                blue_count += 1;
-                scope.push(c);
-            }
-            Some(Color::Black) => {
-                scope.push(c);
-            }
-        }
-    }
-    match modifier {
-        Modifier::Anywhere => distribute_anywhere(&scope, blue_count),
-        Modifier::Together => distribute_together(&scope, blue_count),
-        Modifier::Separated => distribute_separated(&scope, blue_count),
+    #[test]
+    pub fn test_multiverse_equality() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        let hash = |mv: &Multiverse| {
+            let mut hasher = DefaultHasher::new();
+            mv.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        // One layout per solution
+        let c = Coords::new(0, 0, 0);
+        let ring = c.neighbors6().map(|coords| (coords, false));
+        let mv = distribute_in_ring(&ring, 2, true);
+        assert_eq!(mv.layouts.len(), 6);
+        let mut layouts = mv.layouts.clone();
+        layouts.reverse();
+        layouts.push(layouts[0].clone());
+        let shuffled = Multiverse::new(mv.scope.clone(), layouts);
+        assert_eq!(mv, shuffled);
+        assert_eq!(hash(&mv), hash(&shuffled));
+
+        // The same constraint built through merges and learns in different orders
+        let [a, b, ..] = c.neighbors6();
+        let ab = mv.learn(&a, Color::Blue).learn(&b, Color::Black);
+        let ba = mv.learn(&b, Color::Black).learn(&a, Color::Blue);
+        assert_eq!(ab, ba);
+        assert_eq!(hash(&ab), hash(&ba));
+        let other = mock_zone6_anywhere(&a, 3);
+        assert_eq!(mv.merge(&other), other.merge(&mv));
+
+        assert_ne!(mv, distribute_in_ring(&ring, 3, true));
+        assert_ne!(mv, mv.learn(&a, Color::Blue));
     }
-}
 
-pub fn global_blue_count(defn: &defn::Defn) -> Multiverse {
-    let mut scope = Vec::new();
-    let mut blue_count = 0;
-    for (c, cell) in defn {
-        match defn::color_of_cell(cell) {
-            None => (),
-            Some(Color::Blue) => {
-                blue_count += 1;
-                scope.push(*c);
-            }
-            Some(Color::Black) => {
-                scope.push(*c);
-            }
-        }
+    #[test]
+    pub fn test_large_scope() {
+        // C(70, 35) overflows a u64, only the solution count gives up
+        let cells: Vec<_> = (0..70).map(|q| Coords::new(q, 0, -q)).collect();
+        assert_eq!(misc::n_choose_k(70, 35), None);
+        let mv = distribute_anywhere(&cells, 35);
+        assert_eq!(mv.solution_count_upper_bound(), None);
+        assert!(mv.invariants().is_empty());
+        let mv = mv.merge(&distribute_anywhere(&cells[..2], 2));
+        assert_eq!(mv.state(), State::Running);
+        assert_eq!(mv.invariants().len(), 2);
+        assert_eq!(mv.solution_count_upper_bound(), None);
     }
-    distribute_anywhere(&scope, blue_count)
 }
@@ -1,7 +1,14 @@
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::error::Error;
+use std::fmt;
 
-use misc::Coords;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json;
+
+use constraint;
+use misc::{Coords, HexDir};
 
 type Grid33<T> = [[T; 33]; 33];
 
@@ -11,32 +18,137 @@ type Grid33<T> = [[T; 33]; 33];
 /// It is passed to the solver for solving.
 pub type Defn = BTreeMap<Coords, Cell>;
 
-fn char_grid_of_string(strdefn: &str) -> Result<Grid33<(char, char)>, Box<dyn Error>> {
+/// Number of lines preceding the 33 lines of the grid in a string definition.
+const HEADER_LINES: usize = 5;
+
+/// First line of the header of a `.hexcells` level file.
+const MAGIC: &str = "Hexcells level v1";
+
+/// The header of a `.hexcells` level file: the magic line, the title, the author and two lines of
+/// description. Everything is empty for a bare grid.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: String,
+    pub author: String,
+    /// The two lines of description, joined by `\n`. The second one is left out when it is
+    /// empty, so that a description of a single line survives `to_string_with_metadata`.
+    pub description: String,
+}
+
+/// The ways a string definition can be rejected by `of_string`.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The string definition has neither 38 lines nor the 33 lines of a bare grid, holds the
+    /// actual number of lines.
+    LineCount(usize),
+    /// A line of the grid doesn't have 66 chars.
+    LineLength {
+        /// 1-based line number in the string definition.
+        line: usize,
+        /// Number of chars of the line, not of bytes.
+        len: usize,
+    },
+    /// A cell of the string definition that could not be lexed or parsed.
+    Cell {
+        /// 1-based line number in the string definition.
+        line: usize,
+        /// 1-based column number in that line.
+        column: usize,
+        /// The two chars that make up the offending cell.
+        token: String,
+        message: String,
+    },
+    /// The cells don't lie on a single hexagon tiling.
+    Alignment,
+    /// A SixCells JSON level that could not be converted by `from_sixcells_json`.
+    SixCells(String),
+    /// A line of the state given to `of_string_with_state` that could not be applied.
+    State {
+        /// 1-based line number in the state.
+        line: usize,
+        message: String,
+    },
+}
+
+impl Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::LineCount(count) => write!(
+                f,
+                "Wrong number of line in strdefn. Got {}, expected 38, or 33 without header",
+                count
+            ),
+            ParseError::LineLength { line, len } => write!(
+                f,
+                "All lines should have len 66, found one with len {} on line {}",
+                len, line
+            ),
+            ParseError::Cell {
+                line,
+                column,
+                token,
+                message,
+            } => write!(
+                f,
+                "Line {}, column {}, token '{}': {}",
+                line, column, token, message
+            ),
+            ParseError::Alignment => write!(f, "Input grid is incompatible with cube coordinates. This happens because the level is made of at least 2 zones that are completely disjoint and that don't lie on the same hexagon tiling"),
+            ParseError::SixCells(message) => write!(f, "Invalid SixCells level: {}", message),
+            ParseError::State { line, message } => {
+                write!(f, "Line {} of the state: {}", line, message)
+            }
+        }
+    }
+}
+
+/// Split the header from the grid. The header is only parsed if it starts with the magic line,
+/// otherwise it is skipped as before. Also returns the number of lines preceding the grid.
+fn metadata_of_string(strdefn: &str) -> Result<(Metadata, Vec<&str>, usize), ParseError> {
+    let lines: Vec<_> = strdefn.trim().split('\n').collect();
+    match lines.len() {
+        33 => Ok((Metadata::default(), lines, 0)),
+        38 => {
+            let (header, grid) = lines.split_at(HEADER_LINES);
+            let metadata = if header[0].trim() == MAGIC {
+                Metadata {
+                    title: header[1].trim().to_string(),
+                    author: header[2].trim().to_string(),
+                    description: match header[4].trim() {
+                        "" => header[3].trim().to_string(),
+                        second => format!("{}\n{}", header[3].trim(), second),
+                    },
+                }
+            } else {
+                Metadata::default()
+            };
+            Ok((metadata, grid.to_vec(), HEADER_LINES))
+        }
+        count => Err(ParseError::LineCount(count)),
+    }
+}
+
+fn char_grid_of_string(
+    lines: &[&str],
+    header_lines: usize,
+) -> Result<Grid33<(char, char)>, ParseError> {
     let mut grid = [[('_', '_'); 33]; 33];
-    let strdefn: Vec<_> = strdefn.trim().split('\n').collect();
-    if strdefn.len() != 38 {
-        return Err(format!(
-            "Wrong number of line in strdefn. Got {}, expected 38",
-            strdefn.len()
-        )
-        .into());
-    }
-    let strdefn = &strdefn[5..];
-    assert_eq!(strdefn.len(), 33);
-    for (i, line) in strdefn.iter().enumerate() {
-        let line = line.trim();
+    assert_eq!(lines.len(), 33);
+    for (i, line) in lines.iter().enumerate() {
+        let line: Vec<_> = line.trim().chars().collect();
+        let length_error = ParseError::LineLength {
+            line: i + header_lines + 1,
+            len: line.len(),
+        };
         if line.len() != 66 {
-            return Err(format!(
-                "All lines should have len 66, found one with len {}",
-                line.len()
-            )
-            .into());
+            return Err(length_error);
         }
-        let line: Vec<_> = line.chars().collect();
         for (j, chunk) in line.chunks(2).enumerate() {
             let (left, right) = match chunk {
                 [left, right] => (left, right),
-                _ => std::panic::panic_any(0),
+                _ => return Err(length_error),
             };
             grid[i][j] = (*left, *right)
         }
@@ -62,20 +174,41 @@ enum TokenRight {
     N,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Modifier {
     Anywhere,
     Together,
     Separated,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The direction in which a line constraint extends from its cell, i.e. one of the three axes of
+/// the grid, always towards the bottom of the screen.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Orientation {
+    /// `\` in the ASCII format
     BottomRight,
+    /// `|` in the ASCII format
     Bottom,
+    /// `/` in the ASCII format
     BottomLeft,
 }
 
+impl Orientation {
+    /// The direction from a cell of a line to the next one.
+    pub fn dir(&self) -> HexDir {
+        match self {
+            Orientation::Bottom => HexDir::Bottom,
+            Orientation::BottomRight => HexDir::BottomRight,
+            Orientation::BottomLeft => HexDir::BottomLeft,
+        }
+    }
+
+    /// Cube coordinates delta between two consecutive cells of a line.
+    pub fn step(&self) -> (isize, isize, isize) {
+        self.dir().step()
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Color {
     Black,
@@ -83,16 +216,32 @@ pub enum Color {
 }
 
 /// `Cell` is the type of a single cell in a Hexcells level definition
-#[derive(Copy, Clone, Debug)]
+/// In the game the number of a cell depends on its color: a black cell counts its 6 neighbors and
+/// a blue cell the 18 cells within 2 steps. Hence `Zone6` is always black and `Zone18` always
+/// blue, neither the string definition nor the SixCells format can describe another pairing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Cell {
     Empty,
-    Zone0 { revealed: bool, color: Color },
-    Zone6 { revealed: bool, m: Modifier },
-    Zone18 { revealed: bool },
-    Line { o: Orientation, m: Modifier },
+    Zone0 {
+        revealed: bool,
+        color: Color,
+    },
+    /// A black cell with the number of blue cells among its 6 neighbors.
+    Zone6 {
+        revealed: bool,
+        m: Modifier,
+    },
+    /// A blue cell with the number of blue cells within 2 steps.
+    Zone18 {
+        revealed: bool,
+    },
+    Line {
+        o: Orientation,
+        m: Modifier,
+    },
 }
 
-fn lex_left(c: char) -> Result<TokenLeft, Box<dyn Error>> {
+fn lex_left(c: char) -> Result<TokenLeft, String> {
     type L = TokenLeft;
     match c {
         '.' => Ok(L::Dot),
@@ -103,18 +252,18 @@ fn lex_left(c: char) -> Result<TokenLeft, Box<dyn Error>> {
         '/' => Ok(L::Slash),
         '\\' => Ok(L::Backslash),
         '|' => Ok(L::Pipe),
-        _ => Err(format!("Unknown left token:'{}'", c).into()),
+        _ => Err(format!("Unknown left token:'{}'", c)),
     }
 }
 
-fn lex_right(c: char) -> Result<TokenRight, Box<dyn Error>> {
+fn lex_right(c: char) -> Result<TokenRight, String> {
     type R = TokenRight;
     match c {
         '.' => Ok(R::Dot),
         '+' => Ok(R::Plus),
         'c' => Ok(R::C),
         'n' => Ok(R::N),
-        _ => Err(format!("Unknown right token:'{}'", c).into()),
+        _ => Err(format!("Unknown right token:'{}'", c)),
     }
 }
 
@@ -129,14 +278,14 @@ fn parse_modifier(r: TokenRight) -> Modifier {
     }
 }
 
-fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, Box<dyn Error>> {
+fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, String> {
     type L = TokenLeft;
     type R = TokenRight;
     type O = Orientation;
     type C = Color;
     match (l, r) {
         (L::Dot, R::Dot) => Ok(Cell::Empty),
-        (L::Dot, _right) => Err("Invalid pair A".into()),
+        (L::Dot, _right) => Err(String::from("Invalid pair A")),
         (L::SmallO, right @ (R::Plus | R::C | R::N)) => Ok(Cell::Zone6 {
             revealed: false,
             m: parse_modifier(right),
@@ -158,14 +307,16 @@ fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, Box<dyn Error>> {
             color: C::Blue,
         }),
         (L::SmallX, R::Plus) => Ok(Cell::Zone18 { revealed: false }),
-        (L::SmallX, _right @ (R::C | R::N)) => Err("Invalid pair B".into()),
+        (L::SmallX, _right @ (R::C | R::N)) => Err(String::from("Invalid pair B")),
         (L::BigX, R::Dot) => Ok(Cell::Zone0 {
             revealed: true,
             color: C::Blue,
         }),
         (L::BigX, R::Plus) => Ok(Cell::Zone18 { revealed: true }),
-        (L::BigX, _right @ (R::C | R::N)) => Err("Invalid pair C".into()),
-        (_left @ (L::Slash | L::Backslash | L::Pipe), R::Dot) => Err("Invalid pair D".into()),
+        (L::BigX, _right @ (R::C | R::N)) => Err(String::from("Invalid pair C")),
+        (_left @ (L::Slash | L::Backslash | L::Pipe), R::Dot) => {
+            Err(String::from("Invalid pair D"))
+        }
         (L::Slash, right @ (R::Plus | R::C | R::N)) => Ok(Cell::Line {
             o: O::BottomLeft,
             m: parse_modifier(right),
@@ -181,13 +332,23 @@ fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, Box<dyn Error>> {
     }
 }
 
-fn cell_grid_of_char_grid(src: Grid33<(char, char)>) -> Result<Grid33<Cell>, Box<dyn Error>> {
+fn cell_grid_of_char_grid(
+    src: Grid33<(char, char)>,
+    header_lines: usize,
+) -> Result<Grid33<Cell>, ParseError> {
     let mut dst = [[Cell::Empty; 33]; 33];
     for (i, row) in src.iter().enumerate() {
         for (j, (left, right)) in row.iter().enumerate() {
-            let left = lex_left(*left)?;
-            let right = lex_right(*right)?;
-            let cell = parse_cell(left, right)?;
+            let error = |column: usize, message: String| ParseError::Cell {
+                line: i + header_lines + 1,
+                column,
+                token: format!("{}{}", left, right),
+                message,
+            };
+            let left_column = 2 * j + 1;
+            let left = lex_left(*left).map_err(|err| error(left_column, err))?;
+            let right = lex_right(*right).map_err(|err| error(left_column + 1, err))?;
+            let cell = parse_cell(left, right).map_err(|err| error(left_column, err))?;
             dst[i][j] = cell
         }
     }
@@ -204,7 +365,7 @@ enum Alignment {
 /// In the 2d grid representation, half of the element are void, they are placeholders that lie
 /// between two actual puzzle cells. These cells are expected to be `Empty`. `alignment` chooses
 /// which subset of the string definition is void.
-fn of_cell_grid(grid: Grid33<Cell>, alignment: Alignment) -> Result<Defn, Box<dyn Error>> {
+fn of_cell_grid(grid: Grid33<Cell>, alignment: Alignment) -> Result<Defn, ParseError> {
     let (icorrection, jcorrection) = match alignment {
         Alignment::Even => (1, 0),
         Alignment::Odd => (0, 0),
@@ -229,7 +390,7 @@ fn of_cell_grid(grid: Grid33<Cell>, alignment: Alignment) -> Result<Defn, Box<dy
                     map.insert(c, *cell);
                 }
                 (false, _) => {
-                    return Err("Bad alignment in hexcells definition".into());
+                    return Err(ParseError::Alignment);
                 }
             }
         }
@@ -239,25 +400,574 @@ fn of_cell_grid(grid: Grid33<Cell>, alignment: Alignment) -> Result<Defn, Box<dy
 
 /// Takes a string definition as found on reddit and lex/parse/type it to `Defn`. If the result is
 /// `Ok` then the grid is a valid Hexcells puzzle.
-pub fn of_string(strdefn: &str) -> Result<Defn, Box<dyn Error>> {
+/// An invalid cell in the grid is reported as a [ParseError::Cell].
+pub fn of_string(strdefn: &str) -> Result<Defn, ParseError> {
+    Ok(of_string_with_metadata(strdefn)?.0)
+}
+
+/// Same as `of_string`, along with the header of the level. The string definition is either an
+/// unmodified `.hexcells` file of 38 lines or a bare grid of 33 lines.
+pub fn of_string_with_metadata(strdefn: &str) -> Result<(Defn, Metadata), ParseError> {
+    // Step 0: Separate the header from the 33 lines of the grid.
+    let (metadata, lines, header_lines) = metadata_of_string(strdefn)?;
+
     // Step 1: Turn the string into 33x33 array of (char, char).
-    let grid = char_grid_of_string(strdefn)?;
+    let grid = char_grid_of_string(&lines, header_lines)?;
 
     // Step 2: Lex and parse the (char, char) to Cell.
     // - The lexing step is a direct translation of the left/right chars to TokenLeft/TokenRight.
     // - The parsing step is an exhaustive pattern matching of the tokens to a final Cell type.
-    let grid = cell_grid_of_char_grid(grid)?;
+    let grid = cell_grid_of_char_grid(grid, header_lines)?;
 
     // Step 3: Turn the 33x33 Cell array to a Defn.
     match of_cell_grid(grid, Alignment::Even) {
         Err(_) => (),
-        Ok(x) => return Ok(x),
+        Ok(x) => return Ok((x, metadata)),
     };
     match of_cell_grid(grid, Alignment::Odd) {
         Err(_) => (),
-        Ok(x) => return Ok(x),
+        Ok(x) => return Ok((x, metadata)),
+    };
+    Err(ParseError::Alignment)
+}
+
+fn char_of_modifier(m: Modifier) -> char {
+    match m {
+        Modifier::Anywhere => '+',
+        Modifier::Together => 'c',
+        Modifier::Separated => 'n',
+    }
+}
+
+/// The reverse of `lex_left`, `lex_right` and `parse_cell`.
+fn chars_of_cell(cell: &Cell) -> (char, char) {
+    type O = Orientation;
+    match *cell {
+        Cell::Empty => ('.', '.'),
+        Cell::Zone0 { revealed, color } => match (revealed, color) {
+            (false, Color::Black) => ('o', '.'),
+            (true, Color::Black) => ('O', '.'),
+            (false, Color::Blue) => ('x', '.'),
+            (true, Color::Blue) => ('X', '.'),
+        },
+        Cell::Zone6 { revealed, m } => (if revealed { 'O' } else { 'o' }, char_of_modifier(m)),
+        Cell::Zone18 { revealed } => (if revealed { 'X' } else { 'x' }, '+'),
+        Cell::Line { o, m } => {
+            let left = match o {
+                O::BottomLeft => '/',
+                O::BottomRight => '\\',
+                O::Bottom => '|',
+            };
+            (left, char_of_modifier(m))
+        }
+    }
+}
+
+/// Position of `coords` in the 33x33 grid of the string definition. This is the reverse of the
+/// conversion in `of_cell_grid`.
+fn grid_position(coords: &Coords, alignment: &Alignment) -> Option<(usize, usize)> {
+    let icorrection = match alignment {
+        Alignment::Even => 1,
+        Alignment::Odd => 0,
+    };
+    let i = 2 * coords.r() + coords.q() - icorrection;
+    let j = coords.q();
+    if (0..33).contains(&i) && (0..33).contains(&j) {
+        Some((i as usize, j as usize))
+    } else {
+        None
+    }
+}
+
+/// Turn a `Defn` back to the 38-line string definition consumed by `of_string`, such that
+/// `of_string(&to_string(&defn))` is equal to `defn`.
+/// Panics if `defn` doesn't fit in the 33x33 grid.
+pub fn to_string(defn: &Defn) -> String {
+    to_string_with_metadata(defn, &Metadata::default())
+}
+
+/// Same as `to_string`, with `metadata` in the header.
+pub fn to_string_with_metadata(defn: &Defn, metadata: &Metadata) -> String {
+    let mut grid = [[('.', '.'); 33]; 33];
+    let alignment = [Alignment::Even, Alignment::Odd]
+        .iter()
+        .find(|alignment| defn.keys().all(|c| grid_position(c, alignment).is_some()))
+        .expect("Defn doesn't fit in a 33x33 grid");
+    for (coords, cell) in defn {
+        let (i, j) = grid_position(coords, alignment).expect("Unreachable");
+        grid[i][j] = chars_of_cell(cell);
+    }
+    let mut lines = vec![
+        String::from(MAGIC),
+        metadata.title.clone(),
+        metadata.author.clone(),
+    ];
+    lines.extend(metadata.description.splitn(2, '\n').map(String::from));
+    lines.resize(HEADER_LINES, String::new());
+    for row in grid.iter() {
+        lines.push(row.iter().flat_map(|(left, right)| [*left, *right]).collect());
+    }
+    lines.join("\n")
+}
+
+/// Turn the cells known by a player to a string, one cell per line: `x` for blue or `o` for black
+/// followed by the `q r s` coordinates of the cell, e.g. `x 3 -1 -2`. This is the reverse of
+/// `state_of_string`.
+pub fn state_to_string(known: &BTreeMap<Coords, Color>) -> String {
+    known
+        .iter()
+        .map(|(coords, color)| {
+            let c = match color {
+                Color::Blue => 'x',
+                Color::Black => 'o',
+            };
+            format!("{} {} {} {}\n", c, coords.q(), coords.r(), coords.s())
+        })
+        .collect()
+}
+
+/// The cells of a state along with their 1-based line number, see `state_of_string`.
+fn state_lines(strstate: &str) -> Result<Vec<(usize, Coords, Color)>, ParseError> {
+    let mut cells = vec![];
+    for (i, line) in strstate.lines().enumerate() {
+        let error = |message: &str| ParseError::State {
+            line: i + 1,
+            message: message.to_string(),
+        };
+        let (color, q, r, s) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => continue,
+            [color, q, r, s] => (color, q, r, s),
+            _ => return Err(error("Expected a color and 3 coordinates")),
+        };
+        let color = match color {
+            "x" => Color::Blue,
+            "o" => Color::Black,
+            _ => return Err(error("Expected `x` or `o` for the color")),
+        };
+        let coord = |token: &str| {
+            token
+                .parse::<isize>()
+                .map_err(|_| error("Expected an integer coordinate"))
+        };
+        let coords = Coords::try_new(coord(q)?, coord(r)?, coord(s)?)
+            .ok_or_else(|| error("Invalid coordinates"))?;
+        cells.push((i + 1, coords, color));
+    }
+    Ok(cells)
+}
+
+/// Parse the cells known by a player, as written by `state_to_string`. Empty lines are skipped.
+pub fn state_of_string(strstate: &str) -> Result<BTreeMap<Coords, Color>, ParseError> {
+    let mut known = BTreeMap::new();
+    for (line, coords, color) in state_lines(strstate)? {
+        if known.insert(coords, color).is_some() {
+            return Err(ParseError::State {
+                line,
+                message: "Duplicate cell".to_string(),
+            });
+        }
+    }
+    Ok(known)
+}
+
+/// Same as `of_string`, with the cells of `strstate` revealed, see `state_of_string`. The
+/// `Progress` of the result starts from these cells, `solve` continues from there. Fails if a cell
+/// of the state isn't a colored cell of the grid or doesn't have the color of the grid.
+pub fn of_string_with_state(strdefn: &str, strstate: &str) -> Result<Defn, ParseError> {
+    let mut defn = of_string(strdefn)?;
+    for (line, coords, color) in state_lines(strstate)? {
+        let error = |message: &str| ParseError::State {
+            line,
+            message: message.to_string(),
+        };
+        if defn.get(&coords).and_then(color_of_cell) != Some(color) {
+            return Err(error("Not a cell of that color in the grid"));
+        }
+        match defn.get_mut(&coords) {
+            Some(Cell::Zone0 { revealed, .. })
+            | Some(Cell::Zone6 { revealed, .. })
+            | Some(Cell::Zone18 { revealed }) => *revealed = true,
+            _ => panic!("Unreachable"),
+        }
+    }
+    Ok(defn)
+}
+
+/// The colored cells counted by the number of a `Zone6`, `Zone18` or `Line` cell. `None` for the
+/// other cells.
+fn scope_of_cell(defn: &Defn, coords: &Coords) -> Option<Vec<Coords>> {
+    let candidates: Vec<_> = match defn.get(coords)? {
+        Cell::Empty | Cell::Zone0 { .. } => return None,
+        Cell::Zone6 { .. } => coords.neighbors6().to_vec(),
+        Cell::Zone18 { .. } => coords.neighbors18().to_vec(),
+        Cell::Line { o, .. } => return Some(scope_of_line(defn, *coords, *o)),
+    };
+    let scope = candidates
+        .into_iter()
+        .filter(|c| defn.get(c).and_then(color_of_cell).is_some())
+        .collect();
+    Some(scope)
+}
+
+/// The colored cells met when walking the board from `coords` in the direction of `orientation`,
+/// in that order, whatever the cell at `coords`.
+pub(crate) fn scope_of_line(defn: &Defn, coords: Coords, orientation: Orientation) -> Vec<Coords> {
+    let (dq, dr, ds) = orientation.step();
+    let (q, r, s) = (coords.q(), coords.r(), coords.s());
+    // 33 is more than the max diagonal len of a grid
+    (0..33)
+        .map(|i| Coords::new(q + dq * i, r + dr * i, s + ds * i))
+        .filter(|c| defn.get(c).and_then(color_of_cell).is_some())
+        .collect()
+}
+
+/// The number displayed on a `Zone6`, `Zone18` or `Line` cell, i.e. the number of blue cells in
+/// its scope. `None` for the other cells.
+pub fn number_of_cell(defn: &Defn, coords: &Coords) -> Option<usize> {
+    let is_blue = |c: &&Coords| defn.get(c).and_then(color_of_cell) == Some(Color::Blue);
+    Some(scope_of_cell(defn, coords)?.iter().filter(is_blue).count())
+}
+
+/// The number of a cell as displayed in the game, e.g. `{2}` when the blues are together.
+pub fn label_of_cell(defn: &Defn, coords: &Coords) -> Option<String> {
+    let n = number_of_cell(defn, coords)?;
+    let m = match defn[coords] {
+        Cell::Zone6 { m, .. } | Cell::Line { m, .. } => m,
+        _ => Modifier::Anywhere,
+    };
+    let label = match m {
+        Modifier::Anywhere => n.to_string(),
+        Modifier::Together => format!("{{{}}}", n),
+        Modifier::Separated => format!("-{}-", n),
+    };
+    Some(label)
+}
+
+/// Distance from the center to a corner of a hexagon in the SixCells coordinates.
+const SIXCELLS_SIZE: f64 = 1.;
+
+/// A cell of a SixCells level. `kind` is 0 for black and 1 for blue. `value` is absent for the
+/// cells that don't display a number. `together` is absent unless the number is `{n}` or `-n-`.
+/// `members` are the ids of the cells counted by `value`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SixCellsCell {
+    id: usize,
+    kind: u8,
+    revealed: bool,
+    #[serde(default)]
+    value: Option<usize>,
+    #[serde(default)]
+    together: Option<bool>,
+    x: f64,
+    y: f64,
+    #[serde(default)]
+    members: Vec<usize>,
+}
+
+/// A line constraint of a SixCells level. `angle` is in degrees, 0 pointing to the bottom.
+#[derive(Debug, Serialize, Deserialize)]
+struct SixCellsColumn {
+    angle: i32,
+    value: usize,
+    #[serde(default)]
+    together: Option<bool>,
+    x: f64,
+    y: f64,
+    #[serde(default)]
+    members: Vec<usize>,
+}
+
+/// A SixCells level. `information` is the description of `Metadata`, the three are left out of
+/// the JSON when empty.
+#[derive(Debug, Serialize, Deserialize)]
+struct SixCellsLevel {
+    version: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    title: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    author: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    information: String,
+    cells: Vec<SixCellsCell>,
+    columns: Vec<SixCellsColumn>,
+}
+
+fn together_of_modifier(m: Modifier) -> Option<bool> {
+    match m {
+        Modifier::Anywhere => None,
+        Modifier::Together => Some(true),
+        Modifier::Separated => Some(false),
+    }
+}
+
+fn modifier_of_together(together: Option<bool>) -> Modifier {
+    match together {
+        None => Modifier::Anywhere,
+        Some(true) => Modifier::Together,
+        Some(false) => Modifier::Separated,
+    }
+}
+
+fn angle_of_orientation(o: Orientation) -> i32 {
+    match o {
+        Orientation::BottomRight => -60,
+        Orientation::Bottom => 0,
+        Orientation::BottomLeft => 60,
+    }
+}
+
+/// Turn a `Defn` into a level of the SixCells editor: https://github.com/oprypin/sixcells
+/// The positions of the cells are the ones of `Coords::to_pixel`.
+pub fn to_sixcells_json(defn: &Defn) -> String {
+    to_sixcells_json_with_metadata(defn, &Metadata::default())
+}
+
+/// Same as `to_sixcells_json`, with the title, the author and the description of `metadata`.
+pub fn to_sixcells_json_with_metadata(defn: &Defn, metadata: &Metadata) -> String {
+    let ids: BTreeMap<Coords, usize> = defn
+        .iter()
+        .filter(|(_, cell)| color_of_cell(cell).is_some())
+        .enumerate()
+        .map(|(id, (coords, _))| (*coords, id))
+        .collect();
+    let members = |coords: &Coords| match scope_of_cell(defn, coords) {
+        None => vec![],
+        Some(scope) => scope.iter().map(|c| ids[c]).collect(),
+    };
+    let mut cells = vec![];
+    let mut columns = vec![];
+    for (coords, cell) in defn.iter() {
+        let (x, y) = coords.to_pixel(SIXCELLS_SIZE);
+        let (kind, revealed, together) = match *cell {
+            Cell::Empty => continue,
+            Cell::Line { o, m } => {
+                columns.push(SixCellsColumn {
+                    angle: angle_of_orientation(o),
+                    value: number_of_cell(defn, coords).unwrap(),
+                    together: together_of_modifier(m),
+                    x,
+                    y,
+                    members: members(coords),
+                });
+                continue;
+            }
+            Cell::Zone0 { revealed, color } => (color, revealed, None),
+            Cell::Zone6 { revealed, m } => (Color::Black, revealed, together_of_modifier(m)),
+            Cell::Zone18 { revealed } => (Color::Blue, revealed, None),
+        };
+        cells.push(SixCellsCell {
+            id: ids[coords],
+            kind: match kind {
+                Color::Black => 0,
+                Color::Blue => 1,
+            },
+            revealed,
+            value: number_of_cell(defn, coords),
+            together,
+            x,
+            y,
+            members: members(coords),
+        });
+    }
+    let level = SixCellsLevel {
+        version: 1,
+        title: metadata.title.clone(),
+        author: metadata.author.clone(),
+        information: metadata.description.clone(),
+        cells,
+        columns,
+    };
+    serde_json::to_string(&level).unwrap()
+}
+
+/// Parse a level produced by the SixCells editor, such that
+/// `from_sixcells_json(&to_sixcells_json(&defn))` is equal to `defn`. The `members` are recomputed
+/// from the positions, but each `value` must match the colors of the level.
+pub fn from_sixcells_json(json: &str) -> Result<Defn, ParseError> {
+    Ok(from_sixcells_json_with_metadata(json)?.0)
+}
+
+/// Same as `from_sixcells_json`, along with the title, the author and the description of the
+/// level.
+pub fn from_sixcells_json_with_metadata(json: &str) -> Result<(Defn, Metadata), ParseError> {
+    let error = |message: String| ParseError::SixCells(message);
+    let level: SixCellsLevel = serde_json::from_str(json).map_err(|err| error(err.to_string()))?;
+    let mut defn = Defn::new();
+    let mut values = vec![];
+    let mut insert = |x: f64, y: f64, cell: Cell, value: Option<usize>| {
+        let coords = Coords::from_pixel(x, y, SIXCELLS_SIZE);
+        if defn.insert(coords, cell).is_some() {
+            return Err(error(format!("Two items at {}", coords)));
+        }
+        if let Some(value) = value {
+            values.push((coords, value));
+        }
+        Ok(())
+    };
+    for c in level.cells.iter() {
+        let cell = match (c.kind, c.value, c.together) {
+            (0, None, None) => Cell::Zone0 {
+                revealed: c.revealed,
+                color: Color::Black,
+            },
+            (0, Some(_), together) => Cell::Zone6 {
+                revealed: c.revealed,
+                m: modifier_of_together(together),
+            },
+            (1, None, None) => Cell::Zone0 {
+                revealed: c.revealed,
+                color: Color::Blue,
+            },
+            (1, Some(_), None) => Cell::Zone18 {
+                revealed: c.revealed,
+            },
+            _ => return Err(error(format!("Unexpected cell {:?}", c))),
+        };
+        insert(c.x, c.y, cell, c.value)?;
+    }
+    for c in level.columns.iter() {
+        let o = match c.angle {
+            -60 => Orientation::BottomRight,
+            0 => Orientation::Bottom,
+            60 => Orientation::BottomLeft,
+            _ => return Err(error(format!("Unexpected column {:?}", c))),
+        };
+        let m = modifier_of_together(c.together);
+        insert(c.x, c.y, Cell::Line { o, m }, Some(c.value))?;
+    }
+    for (coords, value) in values {
+        let number = number_of_cell(&defn, &coords);
+        if number != Some(value) {
+            return Err(error(format!(
+                "The value at {} is {} but {:?} blue cells are in its scope",
+                coords, value, number
+            )));
+        }
+    }
+    let metadata = Metadata {
+        title: level.title,
+        author: level.author,
+        description: level.information,
+    };
+    Ok((defn, metadata))
+}
+
+/// Render `defn` as ASCII art with the flat-top layout of the game. Each cell is 4 chars wide:
+/// - `o`/`x` for a hidden black/blue cell, `O`/`X` once revealed,
+/// - followed by the number and the modifier (`+`, `c` or `n`) for cells that carry one,
+/// - `/`, `\\` or `|` followed by the number and the modifier for lines,
+/// - `.` for an empty cell within the bounding box of the board.
+pub fn render_ascii(defn: &Defn) -> String {
+    render(defn, &|_, slot| slot)
+}
+
+/// Render the progress of a solver as in `render_ascii`, with the cells of `blues` and `blacks`
+/// drawn as revealed. With `colored`, ANSI escape codes paint the blue cells in blue, the black
+/// cells in dark grey and dim the cells that are still unknown.
+pub fn render_progress(
+    defn: &Defn,
+    blues: &BTreeSet<Coords>,
+    blacks: &BTreeSet<Coords>,
+    colored: bool,
+) -> String {
+    let mut defn = defn.clone();
+    for coords in blues.iter().chain(blacks.iter()) {
+        match defn.get_mut(coords) {
+            Some(Cell::Zone0 { revealed, .. })
+            | Some(Cell::Zone6 { revealed, .. })
+            | Some(Cell::Zone18 { revealed }) => *revealed = true,
+            _ => (),
+        }
+    }
+    if !colored {
+        return render_ascii(&defn);
+    }
+    render(&defn, &|coords, slot| {
+        let code = if blues.contains(coords) {
+            "34"
+        } else if blacks.contains(coords) {
+            "90"
+        } else if defn.get(coords).and_then(color_of_cell).is_some() {
+            "2"
+        } else {
+            return slot;
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, slot)
+    })
+}
+
+/// The smallest and largest value of `f` over the non-empty cells of `defn`, `None` if there are
+/// none.
+fn extent(defn: &Defn, f: impl Fn(&Coords) -> isize) -> Option<(isize, isize)> {
+    defn.iter()
+        .filter(|(_, cell)| !matches!(cell, Cell::Empty))
+        .map(|(coords, _)| f(coords))
+        .fold(None, |acc, x| match acc {
+            None => Some((x, x)),
+            Some((min, max)) => Some((min.min(x), max.max(x))),
+        })
+}
+
+/// A `(q, r, s)` triple that doesn't have to add up to 0, see `bounds`.
+pub type Corner = (isize, isize, isize);
+
+/// The smallest and the largest `(q, r, s)` among the non-empty cells of `defn`, each coordinate
+/// being bounded on its own. `None` if all the cells are empty.
+/// The two corners are in general not cells of the grid, their coordinates don't add up to 0.
+pub fn bounds(defn: &Defn) -> Option<(Corner, Corner)> {
+    let (qmin, qmax) = extent(defn, |c| c.q())?;
+    let (rmin, rmax) = extent(defn, |c| c.r())?;
+    let (smin, smax) = extent(defn, |c| c.s())?;
+    Some(((qmin, rmin, smin), (qmax, rmax, smax)))
+}
+
+/// The width and height of the non-empty cells of `defn` in the flat-top layout of the game, in
+/// cells. The width is the number of columns, the height the number of cells of the tallest
+/// column, a column shifted by half a cell counting as one more cell. `(0, 0)` if all the cells
+/// are empty.
+pub fn dimensions(defn: &Defn) -> (usize, usize) {
+    match (extent(defn, |c| c.q()), extent(defn, line_of)) {
+        (Some((qmin, qmax)), Some((lmin, lmax))) => (
+            (qmax - qmin + 1) as usize,
+            ((lmax - lmin + 1) / 2 + 1) as usize,
+        ),
+        _ => (0, 0),
+    }
+}
+
+/// A cell is drawn at column `q` and at line `2r + q`, so that the columns are staggered by half
+/// a cell.
+fn line_of(coords: &Coords) -> isize {
+    2 * coords.r() + coords.q()
+}
+
+/// Lay out the cells of `defn`, `style` gets a chance to decorate each 4 chars cell.
+fn render(defn: &Defn, style: &dyn Fn(&Coords, String) -> String) -> String {
+    let (qmin, qmax) = match extent(defn, |c| c.q()) {
+        None => return String::new(),
+        Some(extent) => extent,
     };
-    Err("Input grid is incompatible with cube coordinates. This happens because the level is made of at least 2 zones that are completely disjoint and that don't lie on the same hexagon tiling".into())
+    let (lmin, lmax) = extent(defn, line_of).expect("Unreachable");
+    let mut lines = vec![];
+    for l in lmin..=lmax {
+        let mut line = String::new();
+        for q in qmin..=qmax {
+            if (l - q) % 2 != 0 {
+                line.push_str("    ");
+                continue;
+            }
+            let coords = Coords::new(q, (l - q) / 2, -q - (l - q) / 2);
+            let (left, right) = chars_of_cell(defn.get(&coords).unwrap_or(&Cell::Empty));
+            let right = if right == '.' { ' ' } else { right };
+            let number = match number_of_cell(defn, &coords) {
+                None => String::new(),
+                Some(n) => n.to_string(),
+            };
+            line.push_str(&style(&coords, format!("{}{:>2}{}", left, number, right)));
+        }
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
 }
 
 pub fn color_of_cell(cell: &Cell) -> Option<Color> {
@@ -269,3 +979,778 @@ pub fn color_of_cell(cell: &Cell) -> Option<Color> {
         Cell::Zone18 { .. } => Some(Color::Blue),
     }
 }
+
+/// Whether a colored cell is revealed, `None` for the other cells.
+fn revealed_of_cell(cell: &Cell) -> Option<bool> {
+    match cell {
+        Cell::Empty | Cell::Line { .. } => None,
+        Cell::Zone0 { revealed, .. } | Cell::Zone6 { revealed, .. } | Cell::Zone18 { revealed } => {
+            Some(*revealed)
+        }
+    }
+}
+
+/// The colored cells of `defn` that are not revealed, i.e. the ones to discover.
+pub fn unknowns(defn: &Defn) -> impl Iterator<Item = (Coords, &Cell)> {
+    defn.iter()
+        .filter(|(_, cell)| revealed_of_cell(cell) == Some(false))
+        .map(|(coords, cell)| (*coords, cell))
+}
+
+/// The colored cells of `defn` that are revealed from the start.
+pub fn revealed(defn: &Defn) -> impl Iterator<Item = (Coords, &Cell)> {
+    defn.iter()
+        .filter(|(_, cell)| revealed_of_cell(cell) == Some(true))
+        .map(|(coords, cell)| (*coords, cell))
+}
+
+/// The cells of `defn` that carry a constraint: the lines, and the `Zone6` and `Zone18` cells
+/// whether they are revealed or not.
+pub fn constraints(defn: &Defn) -> impl Iterator<Item = (Coords, &Cell)> {
+    defn.iter()
+        .filter(|(_, cell)| {
+            matches!(
+                cell,
+                Cell::Line { .. } | Cell::Zone6 { .. } | Cell::Zone18 { .. }
+            )
+        })
+        .map(|(coords, cell)| (*coords, cell))
+}
+
+/// The number of cells of a `Defn` for each kind of cell, and for each color of the colored cells
+/// depending on whether they are revealed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CellCounts {
+    pub empty: usize,
+    pub line: usize,
+    pub zone0: usize,
+    pub zone6: usize,
+    pub zone18: usize,
+    pub blue_revealed: usize,
+    pub blue_hidden: usize,
+    pub black_revealed: usize,
+    pub black_hidden: usize,
+}
+
+impl CellCounts {
+    /// The number of blue cells, the global blue count of the grid.
+    pub fn blue(&self) -> usize {
+        self.blue_revealed + self.blue_hidden
+    }
+
+    /// The number of black cells.
+    pub fn black(&self) -> usize {
+        self.black_revealed + self.black_hidden
+    }
+}
+
+/// Tally the cells of `defn`, see `CellCounts`.
+pub fn cell_counts(defn: &Defn) -> CellCounts {
+    let mut counts = CellCounts::default();
+    for cell in defn.values() {
+        let revealed = match cell {
+            Cell::Empty => {
+                counts.empty += 1;
+                continue;
+            }
+            Cell::Line { .. } => {
+                counts.line += 1;
+                continue;
+            }
+            Cell::Zone0 { revealed, .. } => {
+                counts.zone0 += 1;
+                *revealed
+            }
+            Cell::Zone6 { revealed, .. } => {
+                counts.zone6 += 1;
+                *revealed
+            }
+            Cell::Zone18 { revealed } => {
+                counts.zone18 += 1;
+                *revealed
+            }
+        };
+        match (color_of_cell(cell).expect("Unreachable"), revealed) {
+            (Color::Blue, true) => counts.blue_revealed += 1,
+            (Color::Blue, false) => counts.blue_hidden += 1,
+            (Color::Black, true) => counts.black_revealed += 1,
+            (Color::Black, false) => counts.black_hidden += 1,
+        }
+    }
+    counts
+}
+
+/// The ways `DefnBuilder::build` can reject a grid.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// Two cells were set at these coords.
+    Collision(Coords),
+    /// The grid doesn't have the number of blue cells given to `DefnBuilder::global_blues`.
+    GlobalBlues { expected: usize, actual: usize },
+    /// The blue cells of the scope of the cell at these coords don't match its `{n}` or `-n-`
+    /// modifier.
+    Modifier(Coords),
+}
+
+impl Error for BuildError {}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Collision(coords) => write!(f, "Two cells at {}", coords),
+            BuildError::GlobalBlues { expected, actual } => write!(
+                f,
+                "Expected {} blue cells, the grid has {}",
+                expected, actual
+            ),
+            BuildError::Modifier(coords) => write!(
+                f,
+                "The blue cells around {} don't match its modifier",
+                coords
+            ),
+        }
+    }
+}
+
+/// Build a `Defn` cell by cell instead of parsing a string definition. As in the string
+/// definition, the numbers of the cells follow from the colors of the cells around them.
+#[derive(Debug, Clone, Default)]
+pub struct DefnBuilder {
+    cells: Vec<(Coords, Cell)>,
+    global_blues: Option<usize>,
+}
+
+impl DefnBuilder {
+    pub fn new() -> DefnBuilder {
+        DefnBuilder::default()
+    }
+
+    /// Put `cell` at `coords`.
+    pub fn set(mut self, coords: Coords, cell: Cell) -> DefnBuilder {
+        self.cells.push((coords, cell));
+        self
+    }
+
+    /// Make `build` check that the grid has `n` blue cells.
+    pub fn global_blues(mut self, n: usize) -> DefnBuilder {
+        self.global_blues = Some(n);
+        self
+    }
+
+    /// Check that no coords were set twice, that the number of blue cells is the expected one
+    /// and that the blue cells around each `{n}` or `-n-` are laid out accordingly.
+    pub fn build(self) -> Result<Defn, BuildError> {
+        let mut defn = Defn::new();
+        for (coords, cell) in self.cells {
+            if defn.insert(coords, cell).is_some() {
+                return Err(BuildError::Collision(coords));
+            }
+        }
+        if let Some(expected) = self.global_blues {
+            let actual = cell_counts(&defn).blue();
+            if actual != expected {
+                return Err(BuildError::GlobalBlues { expected, actual });
+            }
+        }
+        for (coords, _) in constraints(&defn) {
+            if !constraint::modifier_holds(&defn, coords) {
+                return Err(BuildError::Modifier(coords));
+            }
+        }
+        Ok(defn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use defn::{
+        bounds, cell_counts, color_of_cell, constraints, dimensions, from_sixcells_json,
+        from_sixcells_json_with_metadata, of_string, of_string_with_metadata, of_string_with_state,
+        render_ascii, render_progress, revealed, state_of_string, state_to_string,
+        to_sixcells_json, to_sixcells_json_with_metadata, to_string, to_string_with_metadata,
+        unknowns, BuildError, Cell, CellCounts, Color, Defn, DefnBuilder, Metadata, Modifier,
+        Orientation, ParseError,
+    };
+    use misc::Coords;
+    use std::cmp::Ordering;
+    use std::collections::BTreeSet;
+
+    static ML_1: &str = include_str!("../extra/ml_1.txt");
+    static FIXTURES: [&str; 10] = [
+        include_str!("../extra/explore_black_cells.txt"),
+        include_str!("../extra/gg_1.txt"),
+        include_str!("../extra/gg_2.txt"),
+        include_str!("../extra/hlh.txt"),
+        include_str!("../extra/ihoc.txt"),
+        ML_1,
+        include_str!("../extra/ml_2.txt"),
+        include_str!("../extra/ml_3.txt"),
+        include_str!("../extra/the_trial.txt"),
+        include_str!("../extra/vanilla.txt"),
+    ];
+
+    /// Replace the char at 1-based `line` and `column` of `strdefn`.
+    fn corrupt(strdefn: &str, line: usize, column: usize, c: char) -> String {
+        let mut lines: Vec<String> = strdefn.lines().map(String::from).collect();
+        let mut chars: Vec<char> = lines[line - 1].chars().collect();
+        chars[column - 1] = c;
+        lines[line - 1] = chars.into_iter().collect();
+        lines.join("\n")
+    }
+
+    #[test]
+    pub fn test_parse_error_location() {
+        assert!(of_string(ML_1).is_ok());
+
+        let location = |err| match err {
+            ParseError::Cell {
+                line,
+                column,
+                token,
+                ..
+            } => (line, column, token),
+            _ => panic!("Unexpected error {}", err),
+        };
+
+        // Unknown left token
+        let err = of_string(&corrupt(ML_1, 6, 3, 'z')).unwrap_err();
+        assert_eq!(location(err), (6, 3, String::from("z.")));
+
+        // Unknown right token
+        let err = of_string(&corrupt(ML_1, 20, 12, '?')).unwrap_err();
+        assert_eq!(location(err), (20, 12, String::from(".?")));
+
+        // Valid tokens that make an invalid pair
+        let err = of_string(&corrupt(ML_1, 38, 65, '|')).unwrap_err();
+        assert_eq!(location(err), (38, 65, String::from("|.")));
+
+        // Malformed grids
+        let lines: Vec<_> = ML_1.lines().collect();
+        let err = of_string(&lines[..37].join("\n")).unwrap_err();
+        assert!(matches!(err, ParseError::LineCount(37)));
+        let err = of_string(&corrupt(ML_1, 10, 66, ' ')).unwrap_err();
+        assert!(matches!(err, ParseError::LineLength { line: 10, len: 65 }));
+
+        // Non-ASCII chars count as one
+        let err = of_string(&corrupt(ML_1, 10, 3, 'é')).unwrap_err();
+        assert_eq!(location(err), (10, 3, String::from("é.")));
+        let mut lines: Vec<_> = ML_1.lines().map(String::from).collect();
+        lines[9] = format!("é{}", ".".repeat(64));
+        assert_eq!(lines[9].len(), 66);
+        let err = of_string(&lines.join("\n")).unwrap_err();
+        assert!(matches!(err, ParseError::LineLength { line: 10, len: 65 }));
+    }
+
+    #[test]
+    pub fn test_to_string_round_trip() {
+        for strdefn in FIXTURES {
+            let defn = of_string(strdefn).unwrap();
+            let strdefn2 = to_string(&defn);
+            assert_eq!(strdefn2.lines().count(), 38);
+            assert_eq!(defn, of_string(&strdefn2).unwrap());
+        }
+    }
+
+    #[test]
+    pub fn test_state() {
+        use env::Env;
+        use solver::{solve, Outcome, Progress};
+        use std::collections::BTreeMap;
+
+        // The cells found by the first steps of a solve, as a player would have them
+        let defn = of_string(ML_1).unwrap();
+        let findings = match solve(&mut Env::new(60), &defn, None) {
+            Outcome::Solved(findings) => findings,
+            _ => panic!("Unexpected outcome"),
+        };
+        let known: BTreeMap<_, _> = findings[..3]
+            .iter()
+            .flat_map(|f| f.cells())
+            .map(|coords| (*coords, color_of_cell(&defn[coords]).unwrap()))
+            .collect();
+        let strstate = state_to_string(&known);
+        assert_eq!(state_of_string(&strstate).unwrap(), known);
+
+        let defn2 = of_string_with_state(ML_1, &strstate).unwrap();
+        let progress = Progress::of_defn(&defn2);
+        assert_eq!(
+            progress.unknown_count() + known.len(),
+            Progress::of_defn(&defn).unknown_count()
+        );
+        assert!(known.keys().all(|coords| progress.is_known(coords)));
+        match solve(&mut Env::new(60), &defn2, None) {
+            Outcome::Solved(findings2) => assert_eq!(findings2.len(), findings.len() - 3),
+            _ => panic!("Unexpected outcome"),
+        }
+
+        let (coords, color) = known.iter().next().unwrap();
+        let wrong = match color {
+            Color::Blue => "o",
+            Color::Black => "x",
+        };
+        let strstate = format!("\n{} {} {} {}", wrong, coords.q(), coords.r(), coords.s());
+        assert!(matches!(
+            of_string_with_state(ML_1, &strstate),
+            Err(ParseError::State { line: 2, .. })
+        ));
+        assert!(of_string_with_state(ML_1, "x 99 0 -99").is_err());
+        for strstate in ["x 0 0", "b 0 0 0", "x 0 0 1", "x 0 0 0\no 0 0 0"] {
+            assert!(state_of_string(strstate).is_err());
+        }
+    }
+
+    #[test]
+    pub fn test_metadata() {
+        let (defn, metadata) = of_string_with_metadata(ML_1).unwrap();
+        assert_eq!(metadata.title, "Minimal Level 2-1");
+        assert_eq!(metadata.author, "TERU-san");
+        assert_eq!(
+            metadata.description,
+            "\nThis puzzle can be solved without guessing."
+        );
+
+        // A bare grid without header
+        let lines: Vec<_> = ML_1.lines().collect();
+        let (bare, empty) = of_string_with_metadata(&lines[5..].join("\n")).unwrap();
+        assert_eq!(bare, defn);
+        assert_eq!(empty, Metadata::default());
+
+        let strdefn = to_string_with_metadata(&defn, &metadata);
+        assert_eq!(strdefn.lines().count(), 38);
+        assert_eq!(
+            of_string_with_metadata(&strdefn).unwrap(),
+            (defn.clone(), metadata)
+        );
+
+        // Without description, or with a single line of it
+        for description in ["", "foo"] {
+            let metadata = Metadata {
+                description: description.to_string(),
+                ..Metadata::default()
+            };
+            let strdefn = to_string_with_metadata(&defn, &metadata);
+            assert_eq!(
+                of_string_with_metadata(&strdefn).unwrap(),
+                (defn.clone(), metadata)
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_sixcells_round_trip() {
+        for strdefn in FIXTURES {
+            let defn = of_string(strdefn).unwrap();
+            assert_eq!(defn, from_sixcells_json(&to_sixcells_json(&defn)).unwrap());
+        }
+
+        // The header of the level goes along
+        let (defn, metadata) = of_string_with_metadata(ML_1).unwrap();
+        let json = to_sixcells_json_with_metadata(&defn, &metadata);
+        assert!(json.contains("\"author\":\"TERU-san\""));
+        assert_eq!(
+            from_sixcells_json_with_metadata(&json).unwrap(),
+            (defn.clone(), metadata)
+        );
+        let (_, empty) = from_sixcells_json_with_metadata(&to_sixcells_json(&defn)).unwrap();
+        assert_eq!(empty, Metadata::default());
+
+        // A wrong number is rejected
+        let json = to_sixcells_json(&of_string(ML_1).unwrap());
+        let json = json.replacen("\"value\":", "\"value\":99,\"_\":", 1);
+        let err = from_sixcells_json(&json).unwrap_err();
+        assert!(matches!(err, ParseError::SixCells(_)));
+    }
+
+    #[test]
+    pub fn test_parse_modifiers() {
+        // vanilla.txt holds 20 `{n}` and 7 `-n-` zones and 4 `-n-` lines
+        let defn = of_string(include_str!("../extra/vanilla.txt")).unwrap();
+        let count = |f: &dyn Fn(&Cell) -> bool| defn.values().filter(|c| f(c)).count();
+        assert_eq!(
+            count(&|c| matches!(
+                c,
+                Cell::Zone6 {
+                    m: Modifier::Together,
+                    ..
+                }
+            )),
+            20
+        );
+        assert_eq!(
+            count(&|c| matches!(
+                c,
+                Cell::Zone6 {
+                    m: Modifier::Separated,
+                    ..
+                }
+            )),
+            7
+        );
+        assert_eq!(
+            count(&|c| matches!(
+                c,
+                Cell::Line {
+                    m: Modifier::Together,
+                    ..
+                }
+            )),
+            0
+        );
+        assert_eq!(
+            count(&|c| matches!(
+                c,
+                Cell::Line {
+                    m: Modifier::Separated,
+                    ..
+                }
+            )),
+            4
+        );
+    }
+
+    #[test]
+    pub fn test_orientation() {
+        // Each step moves down the screen, to the right, straight or to the left
+        let origin = Coords::new(0, 0, 0);
+        for (o, expected_dx) in [
+            (Orientation::BottomRight, Ordering::Greater),
+            (Orientation::Bottom, Ordering::Equal),
+            (Orientation::BottomLeft, Ordering::Less),
+        ] {
+            let (dq, dr, ds) = o.step();
+            assert_eq!(dq + dr + ds, 0);
+            let next = Coords::new(dq, dr, ds);
+            assert!(origin.neighbors6().contains(&next));
+            let (x, y) = next.to_pixel(1.);
+            assert_eq!(x.partial_cmp(&0.), Some(expected_dx));
+            assert!(y > 0.);
+        }
+
+        // Every line of the fixtures points at cells of the grid
+        for strdefn in FIXTURES {
+            let defn = of_string(strdefn).unwrap();
+            for (coords, cell) in &defn {
+                if let Cell::Line { o, .. } = cell {
+                    let (dq, dr, ds) = o.step();
+                    let next = Coords::new(coords.q() + dq, coords.r() + dr, coords.s() + ds);
+                    assert!(defn.get(&next).and_then(color_of_cell).is_some());
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_bounds_and_dimensions() {
+        let blue = Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let mut defn = Defn::from([(Coords::new(5, -5, 0), Cell::Empty)]);
+        assert_eq!(bounds(&defn), None);
+        assert_eq!(dimensions(&defn), (0, 0));
+
+        defn.insert(Coords::new(0, 0, 0), blue);
+        assert_eq!(bounds(&defn), Some(((0, 0, 0), (0, 0, 0))));
+        assert_eq!(dimensions(&defn), (1, 1));
+
+        // A column of 3 cells and a line above a neighbor column, shifted by half a cell
+        defn.insert(Coords::new(0, 1, -1), blue);
+        defn.insert(Coords::new(0, 2, -2), blue);
+        defn.insert(
+            Coords::new(1, -1, 0),
+            Cell::Line {
+                o: Orientation::Bottom,
+                m: Modifier::Anywhere,
+            },
+        );
+        assert_eq!(bounds(&defn), Some(((0, -1, -2), (1, 2, 0))));
+        assert_eq!(dimensions(&defn), (2, 4));
+
+        let defn = of_string(ML_1).unwrap();
+        let ((qmin, _, _), (qmax, _, _)) = bounds(&defn).unwrap();
+        assert_eq!(dimensions(&defn).0 as isize, qmax - qmin + 1);
+    }
+
+    #[test]
+    pub fn test_cell_counts() {
+        let defn = Defn::from([
+            (Coords::new(0, 0, 0), Cell::Empty),
+            (
+                Coords::new(1, -1, 0),
+                Cell::Line {
+                    o: Orientation::Bottom,
+                    m: Modifier::Anywhere,
+                },
+            ),
+            (
+                Coords::new(1, 0, -1),
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Blue,
+                },
+            ),
+            (
+                Coords::new(1, 1, -2),
+                Cell::Zone0 {
+                    revealed: true,
+                    color: Color::Black,
+                },
+            ),
+            (
+                Coords::new(2, 0, -2),
+                Cell::Zone6 {
+                    revealed: true,
+                    m: Modifier::Together,
+                },
+            ),
+            (Coords::new(2, 1, -3), Cell::Zone18 { revealed: true }),
+        ]);
+        let counts = cell_counts(&defn);
+        assert_eq!(
+            counts,
+            CellCounts {
+                empty: 1,
+                line: 1,
+                zone0: 2,
+                zone6: 1,
+                zone18: 1,
+                blue_revealed: 1,
+                blue_hidden: 1,
+                black_revealed: 2,
+                black_hidden: 0,
+            }
+        );
+        assert_eq!((counts.blue(), counts.black()), (2, 2));
+
+        for strdefn in FIXTURES {
+            let defn = of_string(strdefn).unwrap();
+            let counts = cell_counts(&defn);
+            assert_eq!(counts.empty, 0);
+            assert_eq!(
+                counts.line + counts.zone0 + counts.zone6 + counts.zone18,
+                defn.len()
+            );
+            assert_eq!(counts.blue() + counts.black(), defn.len() - counts.line);
+            assert_eq!(
+                unknowns(&defn).count(),
+                counts.blue_hidden + counts.black_hidden
+            );
+            assert_eq!(
+                revealed(&defn).count(),
+                counts.blue_revealed + counts.black_revealed
+            );
+            assert_eq!(
+                constraints(&defn).count(),
+                counts.line + counts.zone6 + counts.zone18
+            );
+        }
+        let coords = |it: &mut dyn Iterator<Item = (Coords, &Cell)>| -> Vec<Coords> {
+            it.map(|(coords, _)| coords).collect()
+        };
+        assert_eq!(coords(&mut unknowns(&defn)), vec![Coords::new(1, 0, -1)]);
+        assert_eq!(
+            coords(&mut revealed(&defn)),
+            vec![
+                Coords::new(1, 1, -2),
+                Coords::new(2, 0, -2),
+                Coords::new(2, 1, -3)
+            ]
+        );
+        assert_eq!(
+            coords(&mut constraints(&defn)),
+            vec![
+                Coords::new(1, -1, 0),
+                Coords::new(2, 0, -2),
+                Coords::new(2, 1, -3)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_defn_builder() {
+        let center = Coords::new(0, 0, 0);
+        let n = center.neighbors6();
+        let blue = Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let black = Cell::Zone0 {
+            revealed: false,
+            color: Color::Black,
+        };
+        let zone6 = |m| Cell::Zone6 { revealed: true, m };
+        let builder = |m| {
+            DefnBuilder::new()
+                .set(center, zone6(m))
+                .set(n[0], blue)
+                .set(n[1], black)
+                .set(n[2], blue)
+        };
+
+        let defn = builder(Modifier::Separated).build().unwrap();
+        assert_eq!(
+            defn,
+            Defn::from([
+                (center, zone6(Modifier::Separated)),
+                (n[0], blue),
+                (n[1], black),
+                (n[2], blue)
+            ])
+        );
+        assert_eq!(
+            builder(Modifier::Together).build(),
+            Err(BuildError::Modifier(center))
+        );
+        assert_eq!(
+            builder(Modifier::Anywhere)
+                .global_blues(2)
+                .build()
+                .map(|d| d.len()),
+            Ok(4)
+        );
+        assert_eq!(
+            builder(Modifier::Anywhere).global_blues(3).build(),
+            Err(BuildError::GlobalBlues {
+                expected: 3,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            builder(Modifier::Anywhere).set(n[1], blue).build(),
+            Err(BuildError::Collision(n[1]))
+        );
+        // A single blue can't be separated
+        let single = DefnBuilder::new()
+            .set(center, zone6(Modifier::Separated))
+            .set(n[0], blue)
+            .set(n[1], black);
+        assert_eq!(single.build(), Err(BuildError::Modifier(center)));
+
+        // The levels of the game are consistent
+        for strdefn in FIXTURES {
+            let defn = of_string(strdefn).unwrap();
+            let blues = defn
+                .values()
+                .filter(|cell| color_of_cell(cell) == Some(Color::Blue))
+                .count();
+            let builder = defn
+                .iter()
+                .fold(DefnBuilder::new(), |builder, (coords, cell)| {
+                    builder.set(*coords, *cell)
+                });
+            assert_eq!(builder.global_blues(blues).build(), Ok(defn));
+        }
+    }
+
+    #[test]
+    pub fn test_blue_constraint() {
+        use constraint;
+        use defn::{lex_left, lex_right, number_of_cell, parse_cell};
+        use solver::Progress;
+
+        // A revealed blue number with a hidden blue cell 2 steps away, out of reach of a zone6
+        let at = Coords::new(0, 0, 0);
+        let far = Coords::new(0, 2, -2);
+        let defn = Defn::from([
+            (at, Cell::Zone18 { revealed: true }),
+            (
+                Coords::new(0, 1, -1),
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Black,
+                },
+            ),
+            (
+                far,
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Blue,
+                },
+            ),
+        ]);
+        assert_eq!(color_of_cell(&defn[&at]), Some(Color::Blue));
+        assert_eq!(number_of_cell(&defn, &at), Some(1));
+        assert!(Progress::of_defn(&defn).blues().contains(&at));
+        let mv = constraint::zone18(&defn, at);
+        assert!(mv.scope.contains(&far));
+        assert_eq!(mv.invariants().len(), 0);
+        assert_eq!(of_string(&to_string(&defn)).unwrap(), defn);
+
+        // A blue cell can't hold the number of a zone6
+        for token in ["xc", "Xn"] {
+            let mut chars = token.chars();
+            let l = lex_left(chars.next().unwrap()).unwrap();
+            let r = lex_right(chars.next().unwrap()).unwrap();
+            assert!(parse_cell(l, r).is_err());
+        }
+    }
+
+    #[test]
+    pub fn test_render_ascii() {
+        let defn = Defn::from([
+            (
+                Coords::new(0, 0, 0),
+                Cell::Zone6 {
+                    revealed: true,
+                    m: Modifier::Anywhere,
+                },
+            ),
+            (
+                Coords::new(0, -1, 1),
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Blue,
+                },
+            ),
+            (
+                Coords::new(1, -1, 0),
+                Cell::Zone0 {
+                    revealed: true,
+                    color: Color::Black,
+                },
+            ),
+            (
+                Coords::new(0, 1, -1),
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Black,
+                },
+            ),
+        ]);
+        assert_eq!(render_ascii(&defn), "x\n    O\nO 1+\n    .\no");
+        assert_eq!(render_ascii(&Defn::new()), "");
+
+        let defn = of_string(ML_1).unwrap();
+        let rendered = render_ascii(&defn);
+        let glyphs = rendered.chars().filter(|c| "oOxX/\\|".contains(*c)).count();
+        assert_eq!(glyphs, defn.len());
+    }
+
+    #[test]
+    pub fn test_render_progress() {
+        let defn = Defn::from([
+            (
+                Coords::new(0, 0, 0),
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Blue,
+                },
+            ),
+            (
+                Coords::new(0, 1, -1),
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Black,
+                },
+            ),
+        ]);
+        let blues = BTreeSet::from([Coords::new(0, 0, 0)]);
+        let none = BTreeSet::new();
+        assert_eq!(render_progress(&defn, &none, &none, false), "x\n\no");
+        assert_eq!(render_progress(&defn, &blues, &none, false), "X\n\no");
+        assert_eq!(
+            render_progress(&defn, &blues, &none, true),
+            "\x1b[34mX   \x1b[0m\n\n\x1b[2mo   \x1b[0m"
+        );
+    }
+}
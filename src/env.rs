@@ -1,6 +1,15 @@
+use std::error::Error;
 use std::fmt;
-use std::time::{Duration, Instant};
-use Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::Instant;
+// `std::time::Instant` panics on wasm32, the clock of the browser stands in for it
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web_time::Instant;
+
+use solver::SolverConfig;
 
 #[derive(Debug)]
 pub struct Timeout;
@@ -13,31 +22,143 @@ impl fmt::Display for Timeout {
     }
 }
 
-/// Represents the runtime environment for the solver, responsible for managing timeouts.
+/// Represents the runtime environment for the solver, responsible for managing timeouts and
+/// carrying the `SolverConfig`.
+/// There are two budgets: the per-step one restarts with `reset_timer` at each step of the solve,
+/// the optional total one runs from the creation of the `Env` and is never restarted.
+/// Cloning it gives an independent timer with the same limit, e.g. one per thread. The clones
+/// share the cancellation flag, cancelling one of them cancels them all.
+#[derive(Clone)]
 pub struct Env {
     start_time: Instant,
     max_duration: Duration,
+    total_start_time: Instant,
+    max_total_duration: Option<Duration>,
+    step_timings: bool,
+    config: SolverConfig,
+    cancelled: Arc<AtomicBool>,
 }
 
 impl Env {
+    /// An environment with a per-step budget of `max_duration` seconds and no total budget.
     pub fn new(max_duration: u64) -> Env {
         let start_time = Instant::now();
         let max_duration = Duration::from_secs(max_duration);
         Env {
             start_time,
             max_duration,
+            total_start_time: start_time,
+            max_total_duration: None,
+            step_timings: false,
+            config: SolverConfig::default(),
+            cancelled: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Record in each step of the solve the time it took, off by default.
+    pub fn with_step_timings(mut self, step_timings: bool) -> Env {
+        self.step_timings = step_timings;
+        self
+    }
+
+    pub fn step_timings(&self) -> bool {
+        self.step_timings
+    }
+
+    /// Let the solver assume the color of a cell and look for a contradiction, off by default.
+    /// Same as setting `enable_trial` in the config.
+    pub fn with_trials(mut self, trials: bool) -> Env {
+        self.config.enable_trial = trials;
+        self
+    }
+
+    pub fn trials(&self) -> bool {
+        self.config.enable_trial
+    }
+
+    /// Same as setting `max_group_size` in the config.
+    pub fn with_max_group_size(mut self, max_group_size: Option<u32>) -> Env {
+        self.config.max_group_size = max_group_size;
+        self
+    }
+
+    pub fn max_group_size(&self) -> Option<u32> {
+        self.config.max_group_size
+    }
+
+    /// Same as setting `max_layouts` in the config.
+    pub fn with_max_layouts(mut self, max_layouts: Option<usize>) -> Env {
+        self.config.max_layouts = max_layouts;
+        self
+    }
+
+    /// Choose which tiers of deductions the solver runs, see `SolverConfig`.
+    pub fn with_config(mut self, config: SolverConfig) -> Env {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &SolverConfig {
+        &self.config
+    }
+
+    /// Also bound the whole solve to `max_total_duration`, counted from the creation of `self`.
+    pub fn with_total_budget(mut self, max_total_duration: Duration) -> Env {
+        self.max_total_duration = Some(max_total_duration);
+        self
+    }
+
+    /// Restart the per-step budget, the total budget is not affected.
     pub fn reset_timer(&mut self) {
         self.start_time = Instant::now();
     }
 
-    pub fn check_timeout(&self) -> Result<(), Box<dyn Error>> {
-        if self.start_time.elapsed() >= self.max_duration {
-            Err(Box::new(Timeout))
+    /// The flag checked by `check_timeout`, setting it from another thread interrupts the solve
+    /// as if it had timed out.
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Same as setting `cancel_flag`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Fails once either budget is exhausted or once the solve has been cancelled.
+    pub fn check_timeout(&self) -> Result<(), Timeout> {
+        let total_exhausted = match self.max_total_duration {
+            Some(max) => self.total_start_time.elapsed() >= max,
+            None => false,
+        };
+        if self.cancelled.load(Ordering::Relaxed)
+            || self.start_time.elapsed() >= self.max_duration
+            || total_exhausted
+        {
+            Err(Timeout)
         } else {
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use env::Env;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    pub fn test_cancel() {
+        let env = Env::new(60);
+        let clone = env.clone();
+        assert!(env.check_timeout().is_ok());
+        let flag = env.cancel_flag();
+        thread::spawn(move || flag.store(true, Ordering::Relaxed))
+            .join()
+            .unwrap();
+        assert!(env.check_timeout().is_err());
+        assert!(clone.check_timeout().is_err());
+        // A fresh env is not affected
+        assert!(Env::new(60).check_timeout().is_ok());
+    }
+}
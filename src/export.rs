@@ -0,0 +1,223 @@
+//! Export of a grid to the DIMACS CNF format read by SAT solvers such as minisat.
+
+use std::collections::BTreeMap;
+
+use defn;
+use defn::Defn;
+use misc::Coords;
+use solver::all_constraints;
+
+/// A DIMACS literal: a variable numbered from 1, negated when below 0.
+type Lit = i64;
+
+/// A formula in conjunctive normal form, under construction.
+struct Cnf {
+    var_count: i64,
+    clauses: Vec<Vec<Lit>>,
+}
+
+impl Cnf {
+    fn new_var(&mut self) -> Lit {
+        self.var_count += 1;
+        self.var_count
+    }
+
+    /// Add `clause`, extended with `guard` so that it only applies when the guard holds.
+    fn add(&mut self, guard: Option<Lit>, mut clause: Vec<Lit>) {
+        clause.extend(guard.map(|g| -g));
+        self.clauses.push(clause);
+    }
+
+    /// At most `k` of `lits` are true, with the sequential counter of Sinz (2005): the auxiliary
+    /// variable `s[i][j]` holds when at least `j + 1` of the first `i + 1` literals are true.
+    fn at_most(&mut self, guard: Option<Lit>, lits: &[Lit], k: usize) {
+        let n = lits.len();
+        if k >= n {
+            return;
+        }
+        if k == 0 {
+            for lit in lits {
+                self.add(guard, vec![-lit]);
+            }
+            return;
+        }
+        let s: Vec<Vec<Lit>> = (0..n - 1)
+            .map(|_| (0..k).map(|_| self.new_var()).collect())
+            .collect();
+        self.add(guard, vec![-lits[0], s[0][0]]);
+        for s0j in &s[0][1..k] {
+            self.add(guard, vec![-s0j]);
+        }
+        for i in 1..n - 1 {
+            self.add(guard, vec![-lits[i], s[i][0]]);
+            self.add(guard, vec![-s[i - 1][0], s[i][0]]);
+            for j in 1..k {
+                self.add(guard, vec![-lits[i], -s[i - 1][j - 1], s[i][j]]);
+                self.add(guard, vec![-s[i - 1][j], s[i][j]]);
+            }
+            self.add(guard, vec![-lits[i], -s[i - 1][k - 1]]);
+        }
+        self.add(guard, vec![-lits[n - 1], -s[n - 2][k - 1]]);
+    }
+
+    /// Exactly `k` of `lits` are true.
+    fn exactly(&mut self, guard: Option<Lit>, lits: &[Lit], k: usize) {
+        self.at_most(guard, lits, k);
+        let negated: Vec<_> = lits.iter().map(|lit| -lit).collect();
+        self.at_most(guard, &negated, lits.len() - k);
+    }
+}
+
+/// Encode `defn` as a DIMACS CNF formula whose models are the colorings of the unknown cells that
+/// satisfy all the constraints, the global blue count included. Variable `i` is true when the
+/// `i`-th unknown cell, in the order of `defn::unknowns`, is blue. A comment line above the header
+/// names the cell of each of these variables, the variables after them are auxiliary.
+///
+/// As in `has_unique_solution`, the constraint of a hidden cell only holds when that cell takes
+/// its actual color, as the player can't see its number before. Each constraint is encoded from
+/// its `Multiverse`: a choice among its layouts, each layout being a conjunction of cardinality
+/// constraints. A grid whose revealed cells already contradict a constraint gives an
+/// unsatisfiable formula.
+pub fn to_cnf(defn: &Defn) -> String {
+    let cells: Vec<Coords> = defn::unknowns(defn).map(|(coords, _)| coords).collect();
+    let var_of_cell: BTreeMap<Coords, Lit> = cells
+        .iter()
+        .enumerate()
+        .map(|(i, coords)| (*coords, i as Lit + 1))
+        .collect();
+    let mut cnf = Cnf {
+        var_count: cells.len() as i64,
+        clauses: vec![],
+    };
+    match all_constraints(defn) {
+        Err(_) => cnf.clauses.push(vec![]),
+        Ok(constraints) => {
+            for mv in constraints {
+                // A single layout applies as is, otherwise one of them is selected
+                let guards: Vec<Option<Lit>> = match mv.layouts.len() {
+                    1 => vec![None],
+                    _ => mv.layouts.iter().map(|_| Some(cnf.new_var())).collect(),
+                };
+                if guards.len() > 1 {
+                    cnf.clauses.push(guards.iter().flatten().cloned().collect());
+                }
+                for (layout, guard) in mv.layouts.iter().zip(guards) {
+                    for (coords_set, blue_count) in &layout.binomial_coefs {
+                        let lits: Vec<_> = coords_set.iter().map(|c| var_of_cell[c]).collect();
+                        cnf.exactly(guard, &lits, *blue_count as usize);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut lines = vec![];
+    for (coords, var) in &var_of_cell {
+        lines.push(format!("c {} {}", var, coords));
+    }
+    lines.push(format!("p cnf {} {}", cnf.var_count, cnf.clauses.len()));
+    for clause in cnf.clauses {
+        let lits: Vec<_> = clause.iter().map(|lit| lit.to_string()).collect();
+        lines.push(format!("{} 0", lits.join(" ")).trim_start().to_string());
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use defn;
+    use env::Env;
+    use export::to_cnf;
+    use solver::has_unique_solution;
+    use std::collections::BTreeMap;
+
+    static ML_1: &str = include_str!("../extra/ml_1.txt");
+
+    /// The variable count and the clauses of a DIMACS CNF formula.
+    fn parse(cnf: &str) -> (usize, Vec<Vec<i64>>) {
+        let mut lines = cnf.lines().filter(|line| !line.starts_with('c'));
+        let header: Vec<_> = lines.next().unwrap().split(' ').collect();
+        assert_eq!(header[..2], ["p", "cnf"]);
+        let clauses: Vec<Vec<i64>> = lines
+            .map(|line| {
+                let lits: Vec<i64> = line.split(' ').map(|lit| lit.parse().unwrap()).collect();
+                assert_eq!(lits.last(), Some(&0));
+                lits[..lits.len() - 1].to_vec()
+            })
+            .collect();
+        assert_eq!(clauses.len(), header[3].parse::<usize>().unwrap());
+        (header[2].parse().unwrap(), clauses)
+    }
+
+    /// A naive DPLL, enough for the formulas of small grids.
+    fn satisfiable(clauses: &[Vec<i64>], mut assignment: BTreeMap<i64, bool>) -> bool {
+        loop {
+            let mut unit = None;
+            for clause in clauses {
+                let value = |lit: &i64| assignment.get(&lit.abs()).map(|v| *v == (*lit > 0));
+                if clause.iter().any(|lit| value(lit) == Some(true)) {
+                    continue;
+                }
+                let free: Vec<_> = clause.iter().filter(|lit| value(lit).is_none()).collect();
+                match free[..] {
+                    [] => return false,
+                    [lit] => {
+                        unit = Some(*lit);
+                        break;
+                    }
+                    _ => unit = unit.or(Some(0)),
+                }
+            }
+            match unit {
+                None => return true,
+                Some(0) => break,
+                Some(lit) => {
+                    assignment.insert(lit.abs(), lit > 0);
+                }
+            }
+        }
+        let var = clauses
+            .iter()
+            .flatten()
+            .map(|lit| lit.abs())
+            .find(|var| !assignment.contains_key(var))
+            .unwrap();
+        [true, false].iter().any(|value| {
+            let mut assignment = assignment.clone();
+            assignment.insert(var, *value);
+            satisfiable(clauses, assignment)
+        })
+    }
+
+    #[test]
+    pub fn test_to_cnf() {
+        let defn = defn::of_string(ML_1).unwrap();
+        let cnf = to_cnf(&defn);
+        let cells: Vec<_> = defn::unknowns(&defn).map(|(coords, _)| coords).collect();
+        let names: Vec<_> = cnf.lines().filter(|line| line.starts_with("c ")).collect();
+        assert_eq!(names.len(), cells.len());
+        assert_eq!(names[0], format!("c 1 {}", cells[0]));
+        let (var_count, clauses) = parse(&cnf);
+        assert!(var_count >= cells.len());
+
+        // The solution of the grid is a model, and the only one
+        let solution: BTreeMap<i64, bool> = cells
+            .iter()
+            .enumerate()
+            .map(|(i, coords)| {
+                let color = defn::color_of_cell(&defn[coords]).unwrap();
+                (i as i64 + 1, color == defn::Color::Blue)
+            })
+            .collect();
+        assert!(satisfiable(&clauses, solution.clone()));
+        let mut clauses = clauses;
+        clauses.push(
+            solution
+                .iter()
+                .map(|(var, blue)| if *blue { -var } else { *var })
+                .collect(),
+        );
+        assert!(has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+        assert!(!satisfiable(&clauses, BTreeMap::new()));
+    }
+}
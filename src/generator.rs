@@ -0,0 +1,430 @@
+//! Generation of random Hexcells puzzles, checked with the solver.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::error::Error;
+use std::fmt;
+
+use defn;
+use defn::{Cell, Color, Defn, Modifier, Orientation};
+use env::Env;
+use misc::Coords;
+use solver::{
+    difficulty_score, has_unique_solution, solve, Outcome, SolveError, SolveProgressEvent,
+};
+
+/// The center of the generated grids, chosen such that they fit in the 33x33 grid of `to_string`.
+const CENTER: (isize, isize, isize) = (16, 0, -16);
+
+/// Largest `size` accepted by `generate`, the lines around the grid must also fit in 33x33.
+pub const MAX_SIZE: usize = 7;
+
+/// Probability of a cell to be blue.
+const BLUE_RATIO: f64 = 0.4;
+
+/// Probability of a blue cell to display the number of blues within a radius of 2.
+const ZONE18_RATIO: f64 = 0.25;
+
+/// Probability of a black cell to hide its number.
+const ZONE0_RATIO: f64 = 0.1;
+
+/// Probability of each possible line around the grid to be present.
+const LINE_RATIO: f64 = 0.15;
+
+/// The accepted `difficulty_score` of a generated grid, bounds included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DifficultyRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl DifficultyRange {
+    pub fn contains(&self, score: f64) -> bool {
+        self.min <= score && score <= self.max
+    }
+}
+
+/// The ways `generate` can fail.
+#[derive(Debug)]
+pub enum GenerateError {
+    /// The solver failed on a candidate grid.
+    Solve(SolveError),
+    /// None of that many candidate grids was accepted.
+    OutOfAttempts(usize),
+    /// `solve` didn't finish a candidate grid whose hidden cells were revealed until it did.
+    Unsolvable,
+    /// The requested size is above `MAX_SIZE`.
+    SizeTooLarge(usize),
+}
+
+impl Error for GenerateError {}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenerateError::Solve(err) => write!(f, "{}", err),
+            GenerateError::OutOfAttempts(attempts) => {
+                write!(f, "No grid within the target after {} attempts", attempts)
+            }
+            GenerateError::Unsolvable => write!(f, "The solver stopped on a revealed grid"),
+            GenerateError::SizeTooLarge(size) => {
+                write!(f, "Size {} is above the maximum of {}", size, MAX_SIZE)
+            }
+        }
+    }
+}
+
+impl From<SolveError> for GenerateError {
+    fn from(err: SolveError) -> GenerateError {
+        GenerateError::Solve(err)
+    }
+}
+
+/// Lay out a hexagon of radius `size`, color it at random and attach the numbers. No cell is
+/// revealed. The numbers don't use the `{n}` and `-n-` modifiers.
+fn random_grid(rng: &mut StdRng, size: usize) -> Defn {
+    let center = Coords::new(CENTER.0, CENTER.1, CENTER.2);
+    let mut defn = Defn::new();
+    for coords in center.neighbors_within(size) {
+        let cell = if rng.gen_bool(BLUE_RATIO) {
+            if rng.gen_bool(ZONE18_RATIO) {
+                Cell::Zone18 { revealed: false }
+            } else {
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Blue,
+                }
+            }
+        } else if rng.gen_bool(ZONE0_RATIO) {
+            Cell::Zone0 {
+                revealed: false,
+                color: Color::Black,
+            }
+        } else {
+            Cell::Zone6 {
+                revealed: false,
+                m: Modifier::Anywhere,
+            }
+        };
+        defn.insert(coords, cell);
+    }
+
+    // A line can stand right before any cell of the border, in each of the 3 orientations
+    let cells: Vec<Coords> = defn.keys().copied().collect();
+    for o in [
+        Orientation::BottomLeft,
+        Orientation::Bottom,
+        Orientation::BottomRight,
+    ] {
+        let (dq, dr, ds) = o.step();
+        for coords in cells.iter() {
+            let line = Coords::new(coords.q() - dq, coords.r() - dr, coords.s() - ds);
+            if !defn.contains_key(&line) && rng.gen_bool(LINE_RATIO) {
+                defn.insert(
+                    line,
+                    Cell::Line {
+                        o,
+                        m: Modifier::Anywhere,
+                    },
+                );
+            }
+        }
+    }
+    defn
+}
+
+/// Set whether the cell at `coords` is revealed.
+pub(crate) fn set_revealed(defn: &mut Defn, coords: &Coords, value: bool) {
+    match defn.get_mut(coords) {
+        Some(Cell::Zone0 { revealed, .. })
+        | Some(Cell::Zone6 { revealed, .. })
+        | Some(Cell::Zone18 { revealed }) => *revealed = value,
+        _ => panic!("No colored cell at {}", coords),
+    }
+}
+
+/// The colored cells of `defn` that are revealed or not, depending on `value`.
+fn cells_with_revealed(defn: &Defn, value: bool) -> Vec<Coords> {
+    if value {
+        defn::revealed(defn).map(|(coords, _)| coords).collect()
+    } else {
+        defn::unknowns(defn).map(|(coords, _)| coords).collect()
+    }
+}
+
+/// The colored cells of `defn` that are not revealed.
+pub(crate) fn hidden_cells(defn: &Defn) -> Vec<Coords> {
+    cells_with_revealed(defn, false)
+}
+
+/// Generate a random puzzle on a hexagon of radius `size`, that `solve` finishes without guessing
+/// and whose `difficulty_score` is within `target`. The same `seed` always gives the same puzzle.
+///
+/// Each candidate grid gets random cells revealed one at a time until `solve` finishes it, then
+/// each of them is hidden again if `solve` still finishes the grid without it, as in `augment`.
+/// The candidate is rejected if `has_unique_solution` doesn't hold or if it is out of `target`.
+/// At most `max_attempts` candidates are drawn, which bounds the search when `target` is out of
+/// reach.
+/// Fails with `SizeTooLarge` if `size` is above `MAX_SIZE`.
+pub fn generate(
+    seed: u64,
+    size: usize,
+    target: DifficultyRange,
+    max_attempts: usize,
+    env: &mut Env,
+) -> Result<Defn, GenerateError> {
+    if size > MAX_SIZE {
+        return Err(GenerateError::SizeTooLarge(size));
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..max_attempts {
+        let mut defn = random_grid(&mut rng, size);
+        let mut hidden = hidden_cells(&defn);
+        hidden.shuffle(&mut rng);
+        let mut revealed = vec![];
+        while known_cells(&defn, env)?.is_some() {
+            // Revealing everything always solves the grid
+            let coords = hidden.pop().expect("Unreachable");
+            set_revealed(&mut defn, &coords, true);
+            revealed.push(coords);
+        }
+        prune(&mut defn, revealed, env)?;
+        let findings_vec = match solve(env, &defn, None) {
+            Outcome::Solved(findings_vec) => findings_vec,
+            Outcome::Unsolvable => return Err(GenerateError::Unsolvable),
+            Outcome::Timeout(_) => return Err(SolveError::Timeout.into()),
+            Outcome::Explosion(_) => return Err(SolveError::Explosion.into()),
+            Outcome::Contradiction { constraints } => {
+                return Err(SolveError::Contradiction(constraints).into())
+            }
+        };
+        if target.contains(difficulty_score(&findings_vec)) && has_unique_solution(&defn, env)? {
+            return Ok(defn);
+        }
+    }
+    Err(GenerateError::OutOfAttempts(max_attempts))
+}
+
+/// Hide the revealed cells of `defn` one at a time, keeping each cell hidden only if
+/// `has_unique_solution` still holds. Returns the reduced grid along with the number of cells that
+/// were hidden. The reduced grid may require guessing even if `defn` didn't.
+pub fn minimize(defn: &Defn, env: &mut Env) -> Result<(Defn, usize), SolveError> {
+    let mut defn = defn.clone();
+    let mut removed = 0;
+    for coords in cells_with_revealed(&defn, true) {
+        set_revealed(&mut defn, &coords, false);
+        if has_unique_solution(&defn, env)? {
+            removed += 1;
+        } else {
+            set_revealed(&mut defn, &coords, true);
+        }
+    }
+    Ok((defn, removed))
+}
+
+/// Solve `defn` and return how many cells are known when the solver stops, `None` if it finishes
+/// the grid.
+fn known_cells(defn: &Defn, env: &mut Env) -> Result<Option<usize>, SolveError> {
+    let mut known = 0;
+    let mut on_progress = |event: &SolveProgressEvent| known = event.known;
+    match solve(env, defn, Some(&mut on_progress)) {
+        Outcome::Solved(_) => Ok(None),
+        Outcome::Unsolvable => Ok(Some(known)),
+        Outcome::Timeout(_) => Err(SolveError::Timeout),
+        Outcome::Explosion(_) => Err(SolveError::Explosion),
+        Outcome::Contradiction { constraints } => Err(SolveError::Contradiction(constraints)),
+    }
+}
+
+/// Find hidden cells of `defn` to reveal such that `solve` finishes it, empty if it already does.
+/// The cells are chosen greedily: each round reveals the cell that finishes the grid or, failing
+/// that, the one that lets the solver discover the most cells. Then each of them is hidden again
+/// if `solve` still finishes the grid without it. The result is minimal, none of its cells can be
+/// left out, but a smaller set may exist.
+pub fn augment(defn: &Defn, env: &mut Env) -> Result<Vec<Coords>, SolveError> {
+    let mut defn = defn.clone();
+    let greedy = augment_greedily(&defn, env)?;
+    for coords in greedy.iter() {
+        set_revealed(&mut defn, coords, true);
+    }
+    prune(&mut defn, greedy, env)
+}
+
+/// Hide again each of `cells`, revealed in `defn`, if `solve` still finishes the grid without it.
+/// Returns the cells that stay revealed.
+fn prune(defn: &mut Defn, cells: Vec<Coords>, env: &mut Env) -> Result<Vec<Coords>, SolveError> {
+    let mut revealed = vec![];
+    for coords in cells {
+        set_revealed(defn, &coords, false);
+        if known_cells(defn, env)?.is_some() {
+            set_revealed(defn, &coords, true);
+            revealed.push(coords);
+        }
+    }
+    Ok(revealed)
+}
+
+/// The greedy rounds of `augment`, in the order of the rounds.
+fn augment_greedily(defn: &Defn, env: &mut Env) -> Result<Vec<Coords>, SolveError> {
+    let mut defn = defn.clone();
+    let mut revealed = vec![];
+    while known_cells(&defn, env)?.is_some() {
+        let mut best: Option<(Option<usize>, Coords)> = None;
+        for coords in hidden_cells(&defn) {
+            set_revealed(&mut defn, &coords, true);
+            let known = known_cells(&defn, env)?;
+            set_revealed(&mut defn, &coords, false);
+            let better = match (known, best) {
+                (_, None) => true,
+                (None, Some((Some(_), _))) => true,
+                (Some(known), Some((Some(best_known), _))) => known > best_known,
+                (_, Some((None, _))) => false,
+            };
+            if better {
+                best = Some((known, coords));
+            }
+            if known.is_none() {
+                break;
+            }
+        }
+        // Revealing every cell always finishes the grid
+        let (_, coords) = best.expect("Unreachable");
+        set_revealed(&mut defn, &coords, true);
+        revealed.push(coords);
+    }
+    Ok(revealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use defn::{of_string, to_string};
+    use env::Env;
+    use generator::{
+        augment, augment_greedily, cells_with_revealed, generate, hidden_cells, minimize,
+        set_revealed, DifficultyRange, GenerateError, MAX_SIZE,
+    };
+    use misc::Coords;
+    use solver::{count_solutions, difficulty_score, has_unique_solution, solve, Outcome};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_generate() {
+        let env = || Env::new(60).with_total_budget(Duration::from_secs(60));
+        let any = DifficultyRange { min: 1., max: 10. };
+        let defn = generate(42, 3, any, 100, &mut env()).unwrap();
+        assert_eq!(defn, generate(42, 3, any, 100, &mut env()).unwrap());
+        assert_eq!(defn, of_string(&to_string(&defn)).unwrap());
+        assert!(!hidden_cells(&defn).is_empty());
+        assert!(has_unique_solution(&defn, &mut env()).unwrap());
+
+        // None of the revealed cells can be left out
+        for coords in cells_with_revealed(&defn, true) {
+            let mut defn = defn.clone();
+            set_revealed(&mut defn, &coords, false);
+            assert!(matches!(
+                solve(&mut env(), &defn, None),
+                Outcome::Unsolvable
+            ));
+        }
+
+        let target = DifficultyRange { min: 1., max: 1.5 };
+        let defn = generate(7, 2, target, 100, &mut env()).unwrap();
+        match solve(&mut env(), &defn, None) {
+            Outcome::Solved(findings_vec) => {
+                assert!(target.contains(difficulty_score(&findings_vec)))
+            }
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        }
+
+        // The scores stay within 1 and 10
+        let out_of_reach = DifficultyRange { min: 11., max: 12. };
+        assert!(matches!(
+            generate(42, 2, out_of_reach, 3, &mut env()),
+            Err(GenerateError::OutOfAttempts(3))
+        ));
+
+        // The grid and its lines must fit in 33x33
+        assert!(matches!(
+            generate(42, MAX_SIZE + 1, any, 100, &mut env()),
+            Err(GenerateError::SizeTooLarge(size)) if size == MAX_SIZE + 1
+        ));
+    }
+
+    #[test]
+    pub fn test_minimize() {
+        let any = DifficultyRange { min: 1., max: 10. };
+        let defn = generate(42, 3, any, 100, &mut Env::new(60)).unwrap();
+        let (minimized, removed) = minimize(&defn, &mut Env::new(60)).unwrap();
+        let revealed = |defn| cells_with_revealed(defn, true).len();
+        assert!(removed > 0);
+        assert_eq!(revealed(&minimized) + removed, revealed(&defn));
+        assert!(revealed(&minimized) > 0);
+        assert_eq!(
+            count_solutions(&minimized, &mut Env::new(60), 2).unwrap(),
+            1
+        );
+
+        // Hiding any of the remaining revealed cells breaks the uniqueness
+        let (again, removed) = minimize(&minimized, &mut Env::new(60)).unwrap();
+        assert_eq!((again, removed), (minimized.clone(), 0));
+        for coords in cells_with_revealed(&minimized, true) {
+            let mut defn = minimized.clone();
+            set_revealed(&mut defn, &coords, false);
+            assert_eq!(count_solutions(&defn, &mut Env::new(60), 2).unwrap(), 2);
+        }
+    }
+
+    #[test]
+    pub fn test_augment() {
+        let any = DifficultyRange { min: 1., max: 10. };
+        let mut defn = generate(42, 3, any, 100, &mut Env::new(60)).unwrap();
+        assert!(augment(&defn, &mut Env::new(60)).unwrap().is_empty());
+
+        for coords in cells_with_revealed(&defn, true) {
+            set_revealed(&mut defn, &coords, false);
+        }
+        assert!(matches!(
+            solve(&mut Env::new(60), &defn, None),
+            Outcome::Unsolvable
+        ));
+        let cells = augment(&defn, &mut Env::new(60)).unwrap();
+        assert!(!cells.is_empty());
+        for coords in cells.iter() {
+            set_revealed(&mut defn, coords, true);
+        }
+        assert!(matches!(
+            solve(&mut Env::new(60), &defn, None),
+            Outcome::Solved(_)
+        ));
+    }
+
+    #[test]
+    pub fn test_augment_minimal() {
+        // On that grid the greedy rounds reveal a cell that the later ones make useless
+        let any = DifficultyRange { min: 1., max: 10. };
+        let mut defn = generate(0, 2, any, 100, &mut Env::new(60)).unwrap();
+        for coords in cells_with_revealed(&defn, true) {
+            set_revealed(&mut defn, &coords, false);
+        }
+        let greedy = augment_greedily(&defn, &mut Env::new(60)).unwrap();
+        let cells = augment(&defn, &mut Env::new(60)).unwrap();
+        assert!(cells.len() < greedy.len());
+        assert!(cells.iter().all(|coords| greedy.contains(coords)));
+
+        // None of the cells can be left out
+        let solved = |cells: &[Coords]| {
+            let mut defn = defn.clone();
+            for coords in cells {
+                set_revealed(&mut defn, coords, true);
+            }
+            matches!(solve(&mut Env::new(60), &defn, None), Outcome::Solved(_))
+        };
+        assert!(solved(&cells));
+        for i in 0..cells.len() {
+            let mut fewer = cells.clone();
+            fewer.remove(i);
+            assert!(!solved(&fewer));
+        }
+    }
+}
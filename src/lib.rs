@@ -0,0 +1,40 @@
+//! Hexcells puzzle solver.
+//!
+//! Parse a puzzle in the sixcells ASCII format with [of_string], then hand the resulting [Defn]
+//! to [solve] along with an [Env] that bounds the runtime.
+
+extern crate itertools;
+#[macro_use]
+extern crate log;
+extern crate once_cell;
+extern crate rand;
+extern crate rayon;
+extern crate serde;
+extern crate serde_json;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(target_arch = "wasm32")]
+extern crate web_time;
+
+pub mod constraint;
+pub mod defn;
+pub mod env;
+pub mod export;
+pub mod generator;
+pub mod misc;
+pub mod multiverse;
+pub mod solver;
+pub mod svg;
+pub mod tsp_solver;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use defn::of_string;
+pub use defn::{BuildError, Cell, Color, Defn, DefnBuilder, ParseError};
+pub use env::Env;
+pub use misc::{Coords, HexDir};
+pub use solver::{
+    difficulty_histogram, difficulty_of_findings_vec, difficulty_score, resume, solve,
+    solve_with_stats, DifficultyHistogram, Findings, MoveError, Outcome, Session, SolveError,
+    SolveProgressEvent, SolveStats, SolverConfig,
+};
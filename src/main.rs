@@ -1,44 +1,93 @@
-extern crate itertools;
-extern crate once_cell;
-extern crate regex;
-extern crate serde;
-
-mod constraint;
-mod defn;
-mod env;
-mod misc;
-mod multiverse;
-mod solver;
-//mod tsp_solver;
+extern crate env_logger;
+extern crate hexcells_solver;
+extern crate rayon;
+#[macro_use]
+extern crate serde_json;
 
+use hexcells_solver::defn;
+use hexcells_solver::env;
+use hexcells_solver::solver;
+use hexcells_solver::tsp_solver;
+use rayon::prelude::*;
 use std::env::args;
 use std::error::Error;
+use std::fs;
 use std::io;
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 use std::time::Instant;
 
-fn main_stdin() -> Result<(), Box<dyn Error>> {
-    let mut strdefn = String::new();
-    let stdin = io::stdin();
-    for _ in 0..38 {
-        let mut line = String::new();
-        stdin.read_line(&mut line)?;
-        strdefn.push_str(&line);
+/// Used when `--timeout-secs` is not given. This is effectively no timeout.
+const DEFAULT_TIMEOUT_SECS: u64 = 3600 * 24 * 30;
+
+/// Options shared by all the modes of the program.
+struct Opts {
+    timeout_secs: u64,
+    json: bool,
+    step_timings: bool,
+    trials: bool,
+    walkthrough: bool,
+    batch: bool,
+    max_group_size: Option<u32>,
+    max_layouts: Option<usize>,
+}
+
+fn env_of_opts(opts: &Opts) -> env::Env {
+    env::Env::new(opts.timeout_secs)
+        .with_total_budget(Duration::from_secs(opts.timeout_secs))
+        .with_step_timings(opts.step_timings)
+        .with_trials(opts.trials)
+        .with_max_group_size(opts.max_group_size)
+        .with_max_layouts(opts.max_layouts)
+}
+
+fn run(strdefn: &str, opts: &Opts) -> Result<(), Box<dyn Error>> {
+    let defn = defn::of_string(strdefn)?;
+    let mut env = env_of_opts(opts);
+
+    if opts.json {
+        let outcome = solver::solve(&mut env, &defn, None);
+        println!("{}", serde_json::to_string_pretty(&outcome)?);
+        return Ok(());
     }
-    let defn = defn::of_string(&strdefn)?;
-    let mut env = env::Env::new(3600 * 24 * 30);
 
+    if opts.walkthrough {
+        let outcome = solver::solve(&mut env, &defn, None);
+        println!("{}", outcome.walkthrough(&defn));
+        return Ok(());
+    }
+
+    // set verbose to false to skip rendering the grid at each step, shown with RUST_LOG=debug
+    let config = solver::SolverConfig {
+        verbose: true,
+        color: io::stderr().is_terminal(),
+        ..env.config().clone()
+    };
+    let mut env = env.with_config(config);
     let start_time = Instant::now(); // get starttime
-    let outcome = solver::solve(&mut env, &defn, true); // set verbose to false to disable debug println
+    let mut on_progress = |event: &solver::SolveProgressEvent| {
+        println!(
+            "Solver loop with visibles:{}, unknown:{}",
+            event.known, event.unknown
+        );
+    };
+    let (outcome, stats) = solver::solve_with_stats(&mut env, &defn, Some(&mut on_progress));
     let elapsed_time = start_time.elapsed();
 
     println!("{}", outcome);
     println!("{:?}", outcome);
+    println!("{:?}", stats);
     println!("Solver Laufzeit: {:.3?} Sekunden", elapsed_time.as_secs_f64());
     Ok(())
 }
 
-/*
-fn main_tsp() -> Result<(), Box<dyn Error>> {
+/// Read a 38-line grid from stdin, or a bare 33-line grid followed by EOF.
+fn read_stdin() -> io::Result<String> {
     let mut strdefn = String::new();
     let stdin = io::stdin();
     for _ in 0..38 {
@@ -46,26 +95,221 @@ fn main_tsp() -> Result<(), Box<dyn Error>> {
         stdin.read_line(&mut line)?;
         strdefn.push_str(&line);
     }
-    let defn = defn::of_string(&strdefn)?;
-    let mut env = env::Env::new(10);
+    Ok(strdefn)
+}
+
+fn main_stdin(opts: &Opts) -> Result<(), Box<dyn Error>> {
+    run(&read_stdin()?, opts)
+}
+
+/// Solve the concatenated 38-line grids of `reader` one after the other, until EOF. A grid that
+/// fails to parse is reported and skipped. With `--json`, prints one JSON object per line and per
+/// grid, holding either its `outcome` or its `error`.
+fn main_batch(reader: &mut dyn BufRead, opts: &Opts) -> Result<(), Box<dyn Error>> {
+    for index in 0.. {
+        let mut strdefn = String::new();
+        for _ in 0..38 {
+            if reader.read_line(&mut strdefn)? == 0 {
+                break;
+            }
+        }
+        if strdefn.trim().is_empty() {
+            return Ok(());
+        }
+        if opts.json {
+            let object = match defn::of_string(&strdefn) {
+                Ok(defn) => {
+                    let outcome = solver::solve(&mut env_of_opts(opts), &defn, None);
+                    json!({ "index": index, "outcome": outcome })
+                }
+                Err(err) => json!({ "index": index, "error": err.to_string() }),
+            };
+            println!("{}", object);
+        } else {
+            println!("Puzzle {}", index);
+            if let Err(err) = run(&strdefn, opts) {
+                println!("Puzzle {} failed: {}", index, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// One row of the summary printed by `main_dir`.
+fn summarize(path: &Path, opts: &Opts) -> serde_json::Value {
+    let file = path.display().to_string();
+    let strdefn = match fs::read_to_string(path) {
+        Ok(strdefn) => strdefn,
+        Err(err) => return json!({ "file": file, "outcome": "Error", "error": err.to_string() }),
+    };
+    let defn = match defn::of_string(&strdefn) {
+        Ok(defn) => defn,
+        Err(err) => return json!({ "file": file, "outcome": "Error", "error": err.to_string() }),
+    };
+    let outcome = solver::solve(&mut env_of_opts(opts), &defn, None);
+    let (kind, findings_vec) = match &outcome {
+        solver::Outcome::Solved(findings_vec) => ("Solved", Some(findings_vec)),
+        solver::Outcome::Timeout(findings_vec) => ("Timeout", Some(findings_vec)),
+        solver::Outcome::Explosion(findings_vec) => ("Explosion", Some(findings_vec)),
+        solver::Outcome::Unsolvable => ("Unsolvable", None),
+        solver::Outcome::Contradiction { .. } => ("Contradiction", None),
+    };
+    let (max_local, max_global) = findings_vec.map_or((None, None), |findings_vec| {
+        solver::difficulty_of_findings_vec(findings_vec)
+    });
+    json!({
+        "file": file,
+        "outcome": kind,
+        "steps": findings_vec.map_or(0, |findings_vec| findings_vec.len()),
+        "max_local": max_local,
+        "max_global": max_global,
+        "score": findings_vec.map(|findings_vec| solver::difficulty_score(findings_vec)),
+    })
+}
+
+/// Solve in parallel all the `.hexcells` and `.txt` files of `dir`, each with its own `Env`, and
+/// print one summary row per file, as CSV or as one JSON object per line with `--json`.
+fn main_dir(dir: &Path, opts: &Opts) -> Result<(), Box<dyn Error>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "hexcells" || ext == "txt")
+    });
+    paths.sort();
+    let rows: Vec<_> = paths.par_iter().map(|path| summarize(path, opts)).collect();
+
+    if opts.json {
+        for row in rows {
+            println!("{}", row);
+        }
+        return Ok(());
+    }
+    let columns = [
+        "file",
+        "outcome",
+        "steps",
+        "max_local",
+        "max_global",
+        "score",
+    ];
+    println!("{}", columns.join(","));
+    for row in rows {
+        let fields: Vec<_> = columns
+            .iter()
+            .map(|column| match &row[column] {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(s) => s.clone(),
+                value => value.to_string(),
+            })
+            .collect();
+        println!("{}", fields.join(","));
+    }
+    Ok(())
+}
+
+fn main_file(path: &Path, opts: &Opts) -> Result<(), Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let lines: Vec<_> = content.lines().collect();
+    if lines.len() == 33 {
+        // A bare grid without header
+        return run(&content, opts);
+    }
+    if lines.len() < 38 {
+        return Err(format!(
+            "File {} has {} lines, expected at least 38, or 33 without header",
+            path.display(),
+            lines.len()
+        )
+        .into());
+    }
+    run(&lines[..38].join("\n"), opts)
+}
+
+fn main_tsp(opts: &Opts) -> Result<(), Box<dyn Error>> {
+    let defn = defn::of_string(&read_stdin()?)?;
+    let mut env = env_of_opts(opts);
     let start_time = Instant::now(); // get starttime
-    let outcome = tsp_solver::run(&mut env, &defn, true);
+    let outcome = tsp_solver::run(&mut env, &defn, true, None);
     let elapsed_time = start_time.elapsed();
 
     println!("{}", outcome);
-    println!("Solver Laufzeit: {:.3?} Sekunden", elapsed_time.as_secs_f64());
+    println!(
+        "Solver Laufzeit: {:.3?} Sekunden",
+        elapsed_time.as_secs_f64()
+    );
     Ok(())
 }
- */
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<_> = args().collect();
-    if args.len() != 2 {
+
+/// Remove `option <n>` from `args` and return `n`.
+fn take_option<T: FromStr>(
+    args: &mut Vec<String>,
+    option: &str,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let i = match args.iter().position(|arg| arg == option) {
+        None => return Ok(None),
+        Some(i) => i,
+    };
+    if i + 1 >= args.len() {
+        return Err(format!("Missing value after {}", option).into());
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    match value.parse() {
+        Ok(n) => Ok(Some(n)),
+        Err(_) => Err(format!("Invalid value for {}: '{}'", option, value).into()),
+    }
+}
+
+/// Remove `flag` from `args` and return whether it was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        None => false,
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+    }
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // Print the message of the error, not its Debug form as returning it from main would.
+    if let Err(err) = try_main() {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn Error>> {
+    let mut args: Vec<_> = args().collect();
+    let opts = Opts {
+        timeout_secs: take_option(&mut args, "--timeout-secs")?.unwrap_or(DEFAULT_TIMEOUT_SECS),
+        json: take_flag(&mut args, "--json"),
+        step_timings: take_flag(&mut args, "--step-timings"),
+        trials: take_flag(&mut args, "--trials"),
+        walkthrough: take_flag(&mut args, "--walkthrough"),
+        batch: take_flag(&mut args, "--batch"),
+        max_group_size: take_option(&mut args, "--max-group-size")?,
+        max_layouts: take_option(&mut args, "--max-layouts")?,
+    };
+    if args.len() == 3 && args[1] == "batch" {
+        main_dir(Path::new(&args[2]), &opts)
+    } else if args.len() != 2 {
         Err("Wrong number of arguments to program".into())
+    } else if opts.batch && args[1] == "-" {
+        main_batch(&mut io::stdin().lock(), &opts)
+    } else if opts.batch && Path::new(&args[1]).is_file() {
+        main_batch(&mut io::BufReader::new(fs::File::open(&args[1])?), &opts)
     } else if args[1] == "-" {
-        main_stdin()
+        main_stdin(&opts)
     } else if args[1] == "tsp" {
-        Err("There seems to be nothing here?!".into())
-        //main_tsp()
+        main_tsp(&opts)
+    } else if Path::new(&args[1]).is_file() {
+        main_file(Path::new(&args[1]), &opts)
     } else {
         Err("Wrong argument to program".into())
     }
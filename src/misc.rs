@@ -1,10 +1,19 @@
+use once_cell::sync::Lazy;
+use serde::de;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fmt;
 
 
 /// Cube coordinates for hexagon tiling.
 /// https://www.redblobgames.com/grids/hexagons/#conversions (use "flat" mode, not "pointy").
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+/// Serialized as a `[q, r, s]` array.
+/// Each of `q`, `r` and `s` must fit in an `i16`, i.e. lie within `-32768..=32767`.
+#[derive(Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub struct Coords {
     /// Grows towards right
     q: i16,
@@ -14,16 +23,69 @@ pub struct Coords {
     // s: i16, Grows towards top-left and is equal to [-(q+r)
 }
 
+/// The directions of the 6 direct neighbors of a cell in the flat-top tiling.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HexDir {
+    Top,
+    TopRight,
+    BottomRight,
+    Bottom,
+    BottomLeft,
+    TopLeft,
+}
+
+impl HexDir {
+    /// Returns the 6 directions, ordered clockwise starting from top.
+    pub fn all() -> [HexDir; 6] {
+        [
+            HexDir::Top,
+            HexDir::TopRight,
+            HexDir::BottomRight,
+            HexDir::Bottom,
+            HexDir::BottomLeft,
+            HexDir::TopLeft,
+        ]
+    }
+
+    /// Cube coordinates delta of a unit step in that direction.
+    pub fn step(&self) -> (isize, isize, isize) {
+        match self {
+            HexDir::Top => (0, -1, 1),
+            HexDir::TopRight => (1, -1, 0),
+            HexDir::BottomRight => (1, 0, -1),
+            HexDir::Bottom => (0, 1, -1),
+            HexDir::BottomLeft => (-1, 1, 0),
+            HexDir::TopLeft => (-1, 0, 1),
+        }
+    }
+
+    pub fn opposite(&self) -> HexDir {
+        HexDir::all()[(*self as usize + 3) % 6]
+    }
+}
+
 impl Coords {
+    /// Panics if `q + r + s != 0` or if a coordinate is out of the `i16` range, see `try_new`.
     pub fn new(q: isize, r: isize, s: isize) -> Coords {
         if q + r + s != 0 {
             panic!("Constructing an invalid Coords")
         }
-        use std::convert::TryInto;
-        Coords {
-            q: q.try_into().unwrap(),
-            r: r.try_into().unwrap(),
+        match Self::try_new(q, r, s) {
+            Some(c) => c,
+            None => panic!("Coords ({}, {}, {}) out of the i16 range", q, r, s),
+        }
+    }
+
+    /// Same as `new`, returns `None` instead of panicking.
+    pub fn try_new(q: isize, r: isize, s: isize) -> Option<Coords> {
+        if q + r + s != 0 {
+            return None;
         }
+        let _: i16 = s.try_into().ok()?;
+        Some(Coords {
+            q: q.try_into().ok()?,
+            r: r.try_into().ok()?,
+        })
     }
 
     pub fn q(&self) -> isize {
@@ -36,42 +98,146 @@ impl Coords {
         -self.q() - self.r()
     }
 
-    /// Returns the coordinates of the 6 direct neighbors, ordered clockwise starting from top.
+    /// Returns the direct neighbor in direction `dir`.
+    pub fn neighbor(&self, dir: HexDir) -> Coords {
+        let (dq, dr, ds) = dir.step();
+        Self::new(self.q() + dq, self.r() + dr, self.s() + ds)
+    }
+
+    /// Returns the coordinates of the 6 direct neighbors, ordered clockwise starting from top,
+    /// i.e. in the order of `HexDir::all`.
     pub fn neighbors6(&self) -> [Coords; 6] {
-        let (q, r, s) = (self.q(), self.r(), self.s());
-        [
-            Self::new(q + 0, r - 1, s + 1), // top
-            Self::new(q + 1, r - 1, s + 0), // top-right
-            Self::new(q + 1, r + 0, s - 1), // bot-right
-            Self::new(q + 0, r + 1, s - 1), // bot
-            Self::new(q - 1, r + 1, s + 0), // bot-left
-            Self::new(q - 1, r + 0, s + 1), // top-left
-        ]
+        HexDir::all().map(|dir| self.neighbor(dir))
     }
 
     /// Returns the coordinates of the 18 closest neighbors in undefined ordered
     pub fn neighbors18(&self) -> [Coords; 18] {
-        let (q, r, s) = (self.q(), self.r(), self.s());
-        [
-            Self::new(q + 0, r - 1, s + 1),
-            Self::new(q + 1, r - 1, s + 0),
-            Self::new(q + 1, r + 0, s - 1),
-            Self::new(q + 0, r + 1, s - 1),
-            Self::new(q - 1, r + 1, s + 0),
-            Self::new(q - 1, r + 0, s + 1),
-            Self::new(q + 0, r - 2, s + 2),
-            Self::new(q + 1, r - 2, s + 1),
-            Self::new(q + 2, r - 2, s + 0),
-            Self::new(q + 2, r - 1, s - 1),
-            Self::new(q + 2, r + 0, s - 2),
-            Self::new(q + 1, r + 1, s - 2),
-            Self::new(q + 0, r + 2, s - 2),
-            Self::new(q - 1, r + 2, s - 1),
-            Self::new(q - 2, r + 2, s + 0),
-            Self::new(q - 2, r + 1, s + 1),
-            Self::new(q - 2, r + 0, s + 2),
-            Self::new(q - 1, r - 1, s + 2),
-        ]
+        self.neighbors_within(2).try_into().expect("Unreachable")
+    }
+
+    /// Returns the `6 * radius` cells at distance `radius`, ordered clockwise starting from top.
+    pub fn ring(&self, radius: usize) -> Vec<Coords> {
+        let radius = radius as isize;
+        let (dq, dr, ds) = HexDir::Top.step();
+        let mut c = Self::new(
+            self.q() + dq * radius,
+            self.r() + dr * radius,
+            self.s() + ds * radius,
+        );
+        let mut res = Vec::with_capacity(6 * radius as usize);
+        // Starting from the top corner, the first side of the ring goes towards bot-right
+        for dir in HexDir::all().iter().cycle().skip(2).take(6) {
+            for _ in 0..radius {
+                res.push(c);
+                c = c.neighbor(*dir);
+            }
+        }
+        res
+    }
+
+    /// Returns the `3 * radius * (radius + 1)` cells at distance `1..=radius`, ordered by
+    /// increasing distance.
+    pub fn neighbors_within(&self, radius: usize) -> Vec<Coords> {
+        (1..=radius).flat_map(|r| self.ring(r)).collect()
+    }
+
+    /// Returns the cell rotated by 60 degrees clockwise around the origin.
+    /// https://www.redblobgames.com/grids/hexagons/#rotation
+    pub fn rotate_cw(&self) -> Coords {
+        Coords::new(-self.r(), -self.s(), -self.q())
+    }
+
+    /// Returns the cell rotated by 60 degrees counterclockwise around the origin.
+    pub fn rotate_ccw(&self) -> Coords {
+        Coords::new(-self.s(), -self.q(), -self.r())
+    }
+
+    /// Returns the mirror image of the cell across the vertical axis that goes through the
+    /// origin. https://www.redblobgames.com/grids/hexagons/#reflection
+    pub fn reflect_q(&self) -> Coords {
+        Coords::new(-self.q(), -self.s(), -self.r())
+    }
+
+    /// Returns the cell rotated by 60 degrees clockwise around `center`.
+    pub fn rotate60(&self, center: &Coords) -> Coords {
+        *center + (*self - *center).rotate_cw()
+    }
+
+    /// Returns the mirror image of the cell across the vertical axis that goes through `center`.
+    pub fn reflect(&self, center: &Coords) -> Coords {
+        *center + (*self - *center).reflect_q()
+    }
+
+    /// Returns the number of steps between two cells of the hexagon tiling.
+    pub fn distance(&self, other: &Coords) -> usize {
+        let d = *self - *other;
+        ((d.q().abs() + d.r().abs() + d.s().abs()) / 2) as usize
+    }
+
+    /// Returns the pixel position of the center of the hexagon, `size` being the distance from
+    /// the center to a corner. `y` grows towards bottom.
+    /// https://www.redblobgames.com/grids/hexagons/#hex-to-pixel (flat mode)
+    pub fn to_pixel(&self, size: f64) -> (f64, f64) {
+        let (q, r) = (self.q() as f64, self.r() as f64);
+        let x = size * 1.5 * q;
+        let y = size * 3f64.sqrt() * (r + q / 2.);
+        (x, y)
+    }
+
+    /// Returns the hexagon that contains a pixel position. This is the reverse of `to_pixel`.
+    /// https://www.redblobgames.com/grids/hexagons/#pixel-to-hex (flat mode)
+    pub fn from_pixel(x: f64, y: f64, size: f64) -> Coords {
+        let q = 2. / 3. * x / size;
+        let r = (-1. / 3. * x + 3f64.sqrt() / 3. * y) / size;
+        let s = -q - r;
+        // Cube rounding: round each coordinate and fix the one that moved the most
+        let (mut rq, mut rr, mut rs) = (q.round(), r.round(), s.round());
+        let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+        if dq > dr && dq > ds {
+            rq = -rr - rs;
+        } else if dr > ds {
+            rr = -rq - rs;
+        } else {
+            rs = -rq - rr;
+        }
+        Coords::new(rq as isize, rr as isize, rs as isize)
+    }
+}
+
+/// Formats as `(q, r, s)`.
+impl fmt::Display for Coords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {}, {})", self.q(), self.r(), self.s())
+    }
+}
+
+/// Same as the derived `Debug`, along with the computed `s`.
+impl fmt::Debug for Coords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Coords")
+            .field("q", &self.q)
+            .field("r", &self.r)
+            .field("s", &self.s())
+            .finish()
+    }
+}
+
+impl Serialize for Coords {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        [self.q(), self.r(), self.s()].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Coords {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Coords, D::Error> {
+        let [q, r, s] = <[i16; 3]>::deserialize(deserializer)?;
+        if q as isize + r as isize + s as isize != 0 {
+            return Err(de::Error::custom(format!(
+                "Invalid cube coordinates [{}, {}, {}], they should sum to 0",
+                q, r, s
+            )));
+        }
+        Ok(Coords { q, r })
     }
 }
 
@@ -97,17 +263,46 @@ impl std::ops::Sub for Coords {
     }
 }
 
-pub fn n_choose_k(n: u64, mut k: u64) -> Option<u64> {
+/// Pascal's triangle, from `n = 0` up to the first row with a binomial coefficient that overflows
+/// a `u64`, where it is `None`.
+static N_CHOOSE_K_TABLE: Lazy<Vec<Vec<Option<u64>>>> = Lazy::new(|| {
+    let mut table = vec![vec![Some(1u64)]];
+    while table.last().unwrap().iter().all(Option::is_some) {
+        let prev = table.last().unwrap();
+        let mut row = vec![Some(1)];
+        for k in 1..prev.len() {
+            row.push(prev[k - 1].zip(prev[k]).and_then(|(a, b)| a.checked_add(b)));
+        }
+        row.push(Some(1));
+        table.push(row);
+    }
+    table
+});
+
+/// Binomial coefficient, read from a precomputed table for the `n` it covers and computed with
+/// `n_choose_k_u128` beyond. Returns `None` on overflow.
+pub fn n_choose_k(n: u64, k: u64) -> Option<u64> {
     if k > n {
         panic!("Bad call to n_choose_k")
     };
+    match N_CHOOSE_K_TABLE.get(n as usize) {
+        Some(row) => row[k as usize],
+        None => n_choose_k_u128(n, k).and_then(|result| u64::try_from(result).ok()),
+    }
+}
+
+/// Same as `n_choose_k` with a wider accumulator, for scopes where `n_choose_k` overflows.
+pub fn n_choose_k_u128(n: u64, mut k: u64) -> Option<u128> {
+    if k > n {
+        panic!("Bad call to n_choose_k_u128")
+    };
     if k > n - k {
         k = n - k;
     }
-    let mut result: u64 = 1;
+    let mut result: u128 = 1;
     for i in 0..k {
-        let fact = n - i;
-        let quot = i + 1;
+        let fact = (n - i) as u128;
+        let quot = (i + 1) as u128;
         match result.checked_mul(fact) {
             None => return None,
             Some(res) => result = res / quot,
@@ -119,6 +314,11 @@ pub fn n_choose_k(n: u64, mut k: u64) -> Option<u64> {
 #[cfg(test)]
 mod tests {
     use misc::n_choose_k;
+    use misc::n_choose_k_u128;
+    use misc::Coords;
+    use misc::HexDir;
+    use serde_json;
+    use std::convert::TryFrom;
 
     #[test]
     pub fn test_n_choose_k() {
@@ -136,4 +336,205 @@ mod tests {
         assert_eq!(n_choose_k(7, 6).unwrap(), 7);
         assert_eq!(n_choose_k(7, 7).unwrap(), 1);
     }
+
+    #[test]
+    pub fn test_n_choose_k_table() {
+        // Within the table, up to its first row that overflows, and beyond
+        for n in 0..=80 {
+            for k in 0..=n {
+                let exact = n_choose_k_u128(n, k).unwrap();
+                assert_eq!(n_choose_k(n, k), u64::try_from(exact).ok());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_n_choose_k_u128() {
+        for n in 0..30 {
+            for k in 0..=n {
+                assert_eq!(
+                    n_choose_k_u128(n, k).unwrap(),
+                    n_choose_k(n, k).unwrap() as u128
+                );
+            }
+        }
+        // Overflows u64 but fits u128
+        assert_eq!(n_choose_k(68, 34), None);
+        assert_eq!(n_choose_k_u128(68, 34).unwrap(), 28453041475240576740);
+        assert_eq!(
+            n_choose_k_u128(100, 50).unwrap(),
+            100891344545564193334812497256
+        );
+        assert_eq!(n_choose_k_u128(200, 100), None);
+    }
+
+    #[test]
+    pub fn test_distance() {
+        let c = Coords::new(3, -5, 2);
+        assert_eq!(c.distance(&c), 0);
+        for n in c.neighbors6() {
+            assert_eq!(c.distance(&n), 1);
+            assert_eq!(n.distance(&c), 1);
+        }
+        let neighbors6 = c.neighbors6();
+        for n in c.neighbors18() {
+            if neighbors6.contains(&n) {
+                assert_eq!(c.distance(&n), 1);
+            } else {
+                assert_eq!(c.distance(&n), 2);
+            }
+        }
+        assert_eq!(c.distance(&Coords::new(0, 0, 0)), 5);
+    }
+
+    #[test]
+    #[allow(clippy::identity_op)] // Keeps the deltas of the neighbors aligned
+    pub fn test_ring_and_neighbors_within() {
+        let c = Coords::new(3, -5, 2);
+        assert_eq!(c.ring(0), vec![]);
+        assert_eq!(c.neighbors_within(0), vec![]);
+        for radius in 1..6 {
+            let ring = c.ring(radius);
+            assert_eq!(ring.len(), 6 * radius);
+            assert!(ring.iter().all(|n| c.distance(n) == radius));
+            // Consecutive cells of a ring are adjacent
+            for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                assert_eq!(a.distance(b), 1);
+            }
+            let within = c.neighbors_within(radius);
+            assert_eq!(within.len(), 3 * radius * (radius + 1));
+            let unique: std::collections::BTreeSet<_> = within.iter().collect();
+            assert_eq!(unique.len(), within.len());
+            assert!(within.iter().all(|n| (1..=radius).contains(&c.distance(n))));
+        }
+        assert_eq!(c.neighbors6().to_vec(), c.ring(1));
+        let (q, r, s) = (c.q(), c.r(), c.s());
+        assert_eq!(
+            c.neighbors6(),
+            [
+                Coords::new(q + 0, r - 1, s + 1), // top
+                Coords::new(q + 1, r - 1, s + 0), // top-right
+                Coords::new(q + 1, r + 0, s - 1), // bot-right
+                Coords::new(q + 0, r + 1, s - 1), // bot
+                Coords::new(q - 1, r + 1, s + 0), // bot-left
+                Coords::new(q - 1, r + 0, s + 1), // top-left
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_neighbor() {
+        let c = Coords::new(3, -5, 2);
+        let (q, r, s) = (c.q(), c.r(), c.s());
+        assert_eq!(c.neighbor(HexDir::Top), Coords::new(q, r - 1, s + 1));
+        assert_eq!(c.neighbor(HexDir::BottomLeft), Coords::new(q - 1, r + 1, s));
+        for (i, dir) in HexDir::all().iter().enumerate() {
+            assert_eq!(c.neighbor(*dir), c.neighbors6()[i]);
+            assert_eq!(c.neighbor(*dir).neighbor(dir.opposite()), c);
+            assert_eq!(dir.opposite().opposite(), *dir);
+        }
+    }
+
+    #[test]
+    pub fn test_rotate_cw_and_ccw() {
+        let origin = Coords::new(0, 0, 0);
+        let cells = origin.neighbors_within(3);
+        for c in cells.iter() {
+            let mut rotated = *c;
+            for _ in 0..6 {
+                rotated = rotated.rotate_cw();
+            }
+            assert_eq!(rotated, *c);
+            assert_eq!(c.rotate_cw().rotate_ccw(), *c);
+            assert_eq!(c.reflect_q().reflect_q(), *c);
+            for other in cells.iter() {
+                assert_eq!(
+                    c.rotate_cw().distance(&other.rotate_cw()),
+                    c.distance(other)
+                );
+                assert_eq!(
+                    c.rotate_ccw().distance(&other.rotate_ccw()),
+                    c.distance(other)
+                );
+            }
+        }
+        // The neighbors are ordered clockwise
+        let neighbors6 = origin.neighbors6();
+        for i in 0..6 {
+            assert_eq!(neighbors6[i].rotate_cw(), neighbors6[(i + 1) % 6]);
+            assert_eq!(neighbors6[i].rotate_ccw(), neighbors6[(i + 5) % 6]);
+        }
+    }
+
+    #[test]
+    pub fn test_rotate60_and_reflect() {
+        let center = Coords::new(3, -5, 2);
+        for c in center.neighbors_within(3) {
+            let mut rotated = c;
+            for _ in 0..6 {
+                rotated = rotated.rotate60(&center);
+                assert_eq!(rotated.distance(&center), c.distance(&center));
+            }
+            assert_eq!(rotated, c);
+            assert_eq!(c.reflect(&center).reflect(&center), c);
+        }
+        assert_eq!(center.rotate60(&center), center);
+        // The rings are ordered clockwise
+        let ring = center.ring(2);
+        for (i, c) in ring.iter().enumerate() {
+            assert_eq!(c.rotate60(&center), ring[(i + 2) % ring.len()]);
+        }
+        // top <-> top, top-right <-> top-left
+        let [top, top_right, _, _, _, top_left] = center.neighbors6();
+        assert_eq!(top.reflect(&center), top);
+        assert_eq!(top_right.reflect(&center), top_left);
+    }
+
+    #[test]
+    pub fn test_pixel_round_trip() {
+        let size = 10.;
+        assert_eq!(Coords::new(0, 0, 0).to_pixel(size), (0., 0.));
+        let (x, y) = Coords::new(0, 1, -1).to_pixel(size);
+        assert!(x.abs() < 1e-9 && (y - size * 3f64.sqrt()).abs() < 1e-9);
+        let center = Coords::new(3, -5, 2);
+        for c in center.neighbors_within(3) {
+            let (x, y) = c.to_pixel(size);
+            assert_eq!(Coords::from_pixel(x, y, size), c);
+            // Anything within the inner circle of the hexagon belongs to it
+            for (dx, dy) in [(4., 0.), (-4., 3.), (0., -8.)] {
+                assert_eq!(Coords::from_pixel(x + dx, y + dy, size), c);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_coords_json_round_trip() {
+        let c = Coords::new(3, -5, 2);
+        let json = serde_json::to_string(&c).unwrap();
+        assert_eq!(json, "[3,-5,2]");
+        let c2: Coords = serde_json::from_str(&json).unwrap();
+        assert_eq!(c, c2);
+        assert!(serde_json::from_str::<Coords>("[3,-5,1]").is_err());
+        assert!(serde_json::from_str::<Coords>("[3,-5]").is_err());
+    }
+
+    #[test]
+    pub fn test_try_new() {
+        let (min, max) = (i16::MIN as isize, i16::MAX as isize);
+        assert_eq!(Coords::try_new(1, 2, 3), None);
+        let c = Coords::try_new(max, min + 1, 0).unwrap();
+        assert_eq!((c.q(), c.r(), c.s()), (max, min + 1, 0));
+        assert_eq!(Coords::try_new(max + 1, min, 0), None);
+        assert!(Coords::try_new(max, 0, -max).is_some());
+        // `s` must fit as well
+        assert_eq!(Coords::try_new(max, max, -2 * max), None);
+        assert!(std::panic::catch_unwind(|| Coords::new(max + 1, 0, -max - 1)).is_err());
+    }
+
+    #[test]
+    pub fn test_coords_fmt() {
+        let c = Coords::new(3, -5, 2);
+        assert_eq!(c.to_string(), "(3, -5, 2)");
+        assert_eq!(format!("{:?}", c), "Coords { q: 3, r: -5, s: 2 }");
+    }
 }
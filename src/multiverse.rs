@@ -1,6 +1,9 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
 use defn::Color;
 use misc;
@@ -17,7 +20,10 @@ use misc::Coords;
 /// {e, f}: 2  // Both `e, f` are blue
 /// n: k       // `k` of the `n` coordinates are blue.
 ///               (i.e. n.len() choose k combinations)
-#[derive(Debug, Clone)]
+/// The solutions of a Layout are never enumerated one by one, this is what keeps constraints with
+/// large scopes (e.g. the global blue count) tractable: storing one bitset per solution would
+/// require `n.len() choose k` of them where a single entry suffices here.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Layout {
     pub binomial_coefs: BTreeMap<BTreeSet<Coords>, u16>,
 }
@@ -36,6 +42,7 @@ impl Layout {
         Layout { binomial_coefs }
     }
 
+    /// The number of solutions of the Layout, `None` on overflow.
     pub fn solution_count(&self) -> Option<u64> {
         let mut i: u64 = 1;
         for (coords_set, blue_count) in &self.binomial_coefs {
@@ -59,12 +66,8 @@ impl Layout {
         for kright in other.binomial_coefs.keys() {
             for c in kright {
                 match left_key_per_coords.get(c) {
-                    None => (),
-                    Some(kleft) => {
-                        if kleft != &kright {
-                            return false;
-                        }
-                    }
+                    Some(kleft) if kleft != &kright => return false,
+                    _ => (),
                 }
             }
         }
@@ -105,6 +108,41 @@ impl Layout {
         }
     }
 
+    /// The key that holds `coords` and its blue count. Panics if `coords` is out of the layout.
+    fn key_of(&self, coords: &Coords) -> (&BTreeSet<Coords>, u16) {
+        self.binomial_coefs
+            .iter()
+            .find(|(key, _)| key.contains(coords))
+            .map(|(key, blue_count)| (key, *blue_count))
+            .expect("Unexpected parameters to key_of")
+    }
+
+    /// Test if at least one solution of the Layout gives `color` to `coords`.
+    fn allows(&self, coords: &Coords, color: Color) -> bool {
+        let (key, blue_count) = self.key_of(coords);
+        match color {
+            Color::Blue => blue_count >= 1,
+            Color::Black => (blue_count as usize) < key.len(),
+        }
+    }
+
+    /// Narrow down the Layout to the solutions that give `color` to `coords`, which leaves the
+    /// layout. The key of `coords` loses it, along with a blue if `color` is blue. The Layout must
+    /// allow that color, see `allows`.
+    fn learn_mut(&mut self, coords: &Coords, color: Color) {
+        let (key, blue_count) = self.key_of(coords);
+        let mut key = key.clone();
+        self.binomial_coefs.remove(&key);
+        key.remove(coords);
+        if !key.is_empty() {
+            let blue_count = match color {
+                Color::Blue => blue_count - 1,
+                Color::Black => blue_count,
+            };
+            self.binomial_coefs.insert(key, blue_count);
+        }
+    }
+
     /// Fork all the same-keyed Layouts in the input Vec<Layout> so that they contain new_key.
     fn split(layouts: &Vec<Layout>, new_key: &BTreeSet<Coords>) -> Vec<Layout> {
         let mut res = vec![];
@@ -196,13 +234,13 @@ impl Layout {
         let mut res = vec![];
         let (left_lays, right_lays) = self.align(other);
         let left_keys: BTreeSet<_> = left_lays
-            .get(0)
+            .first()
             .expect("Left can't be empty here")
             .binomial_coefs
             .keys()
             .collect();
         let right_keys: BTreeSet<_> = right_lays
-            .get(0)
+            .first()
             .expect("Right can't be empty here")
             .binomial_coefs
             .keys()
@@ -226,10 +264,28 @@ impl Layout {
     }
 }
 
+/// A merge of multiverses was abandoned because its result would hold too many layouts.
+#[derive(Debug)]
+pub struct Explosion;
+
+impl Error for Explosion {}
+
+impl fmt::Display for Explosion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Explosion")
+    }
+}
+
+/// Where a Multiverse stands, see `Multiverse::state`.
 #[derive(PartialEq, Debug)]
 pub enum State {
+    /// The scope has cells and at least one layout: the constraint still has solutions to choose
+    /// from.
     Running,
+    /// The scope has cells but no layout is left: the constraint has no solution, the known cells
+    /// contradict it.
     Stuck,
+    /// The scope has no cells: every cell of the constraint is known, it has nothing left to say.
     Empty,
 }
 
@@ -239,6 +295,44 @@ pub enum State {
 /// Two differents layout in a multiverse are two ways to describe permutations of the same set of coords (i.e. the scope).
 /// Two layouts in a multiverse may describe overlapping sets of results, hence the fact that [solution_count_upper_bound] doesn't give the exact number of solutions.
 /// A multiverse may have no solutions (i.e. `State::Stuck`)
+///
+/// Each number of a grid gives a Multiverse, see the `constraint` module. Merging the ones that
+/// overlap and reading the invariants of the result is how the solver finds new cells:
+///
+/// ```
+/// use std::collections::{BTreeMap, BTreeSet};
+/// use hexcells_solver::multiverse::{Layout, Multiverse, State};
+/// use hexcells_solver::{Color, Coords};
+///
+/// // `blues` of the cells in `scope` are blue
+/// let count = |scope: &[Coords], blues| {
+///     let scope: BTreeSet<_> = scope.iter().cloned().collect();
+///     let layout = Layout::new(BTreeMap::from([(scope.clone(), blues)]));
+///     Multiverse::new(scope, vec![layout])
+/// };
+/// let (a, b, c) = (Coords::new(0, 0, 0), Coords::new(1, -1, 0), Coords::new(2, -2, 0));
+/// let left = count(&[a, b], 1);
+/// let right = count(&[b, c], 2);
+///
+/// // Alone, `left` can't tell which of `a` and `b` is blue
+/// assert!(left.invariants().is_empty());
+/// assert_eq!(right.invariants().len(), 2);
+///
+/// // Together, `b` is blue so `a` is black
+/// let mv = left.merge(&right);
+/// assert_eq!(mv.state(), State::Running);
+/// assert_eq!(
+///     mv.invariants(),
+///     BTreeMap::from([(a, Color::Black), (b, Color::Blue), (c, Color::Blue)])
+/// );
+///
+/// // Learning a cell against the constraints leaves no solution
+/// assert_eq!(mv.learn(&a, Color::Blue).state(), State::Stuck);
+/// ```
+///
+/// Two Multiverses are equal when they have the same scope and the same layouts, in any order and
+/// regardless of repeated layouts. Two different sets of layouts that happen to describe the same
+/// solutions are not equal.
 #[derive(Debug, Clone)]
 pub struct Multiverse {
     pub scope: BTreeSet<Coords>,
@@ -246,6 +340,29 @@ pub struct Multiverse {
 }
 
 impl Multiverse {
+    /// The layouts in a canonical order, without repetitions.
+    fn canonical_layouts(&self) -> BTreeSet<&Layout> {
+        self.layouts.iter().collect()
+    }
+}
+
+impl PartialEq for Multiverse {
+    fn eq(&self, other: &Multiverse) -> bool {
+        self.scope == other.scope && self.canonical_layouts() == other.canonical_layouts()
+    }
+}
+
+impl Eq for Multiverse {}
+
+impl Hash for Multiverse {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.scope.hash(state);
+        self.canonical_layouts().hash(state);
+    }
+}
+
+impl Multiverse {
+    /// All the `layouts` must cover exactly `scope`. No layout at all means no solution.
     pub fn new(scope: BTreeSet<Coords>, layouts: Vec<Layout>) -> Multiverse {
         for lay in &layouts {
             let lay_coords = lay.binomial_coefs.keys().fold(BTreeSet::new(), |acc, set| {
@@ -256,10 +373,13 @@ impl Multiverse {
         Multiverse { scope, layouts }
     }
 
+    /// The Multiverse without cells, the identity of `merge`.
     pub fn empty() -> Multiverse {
         Multiverse::new(BTreeSet::new(), vec![])
     }
 
+    /// The sum of the solutions of the layouts, `None` on overflow. Two layouts may share
+    /// solutions, so this is only an upper bound.
     pub fn solution_count_upper_bound(&self) -> Option<u64> {
         let mut i: u64 = 0;
         for lay in &self.layouts {
@@ -273,6 +393,7 @@ impl Multiverse {
         Some(i)
     }
 
+    /// See `State`. Panics on a multiverse with layouts but no scope, which can't be built.
     pub fn state(&self) -> State {
         match (self.scope.is_empty(), self.layouts.is_empty()) {
             (true, true) => State::Empty,
@@ -283,7 +404,8 @@ impl Multiverse {
     }
 
     /// The invariants of the Multiverse are the coords that have a constant
-    /// color across all the solutions of the Multiverse.
+    /// color across all the solutions of the Multiverse, along with that color. These are the
+    /// cells that can be revealed without guessing.
     /// The result is undefined if the multiverse is stuck (i.e. empty layouts)
     pub fn invariants(&self) -> BTreeMap<Coords, Color> {
         let mut blue_for_sure = self.scope.clone();
@@ -329,50 +451,100 @@ impl Multiverse {
         result
     }
 
+    /// The Multiverse of the solutions of both `self` and `other`: its scope is the union of
+    /// their scopes, and its solutions are the ones that satisfy both on their intersection.
+    /// Merging is commutative and associative, as far as the solutions are concerned, with
+    /// `Multiverse::empty()` as identity. A stuck operand gives a stuck result, and so do two
+    /// multiverses that contradict each other.
+    /// Beware that the number of layouts of the result can grow as the product of the ones of the
+    /// operands, see `try_merge`.
     pub fn merge(&self, other: &Multiverse) -> Multiverse {
+        self.try_merge(other, usize::MAX).expect("Unreachable")
+    }
+
+    /// Same as `merge`, but give up as soon as the result holds more than `max_layouts` layouts.
+    /// The number of layouts can grow as the product of the ones of `self` and `other`.
+    pub fn try_merge(
+        &self,
+        other: &Multiverse,
+        max_layouts: usize,
+    ) -> Result<Multiverse, Explosion> {
+        // Empty is the identity of the merge and Stuck absorbs everything else, only two running
+        // multiverses need to combine their layouts
+        let empty = match (self.state(), other.state()) {
+            (State::Empty, _) => return Ok(other.clone()),
+            (_, State::Empty) => return Ok(self.clone()),
+            (State::Stuck, _) | (_, State::Stuck) => true,
+            (State::Running, State::Running) => false,
+        };
         let scope = self.scope.union(&other.scope).cloned().collect();
-        match (self.state(), other.state()) {
-            (State::Empty, _) => return other.clone(),
-            (_, State::Empty) => return self.clone(),
-            (State::Stuck, _) | (_, State::Stuck) => return Multiverse::new(scope, vec![]),
-            (State::Running, State::Running) => (),
+        if empty {
+            return Ok(Multiverse::new(scope, vec![]));
         }
         let mut layouts = vec![];
         for left_lay in &self.layouts {
             for right_lay in &other.layouts {
                 layouts.append(&mut left_lay.merge(right_lay));
+                if layouts.len() > max_layouts {
+                    return Err(Explosion);
+                }
             }
         }
-        Multiverse::new(scope, layouts)
+        Ok(Multiverse::new(scope, layouts))
     }
 
+    /// Test if at least one solution of the Multiverse gives `color` to `coords`.
+    pub fn allows(&self, coords: &Coords, color: Color) -> bool {
+        assert!(self.scope.contains(coords));
+        self.layouts.iter().any(|lay| lay.allows(coords, color))
+    }
+
+    /// Narrow down the Multiverse to the solutions that give `color` to `coords`.
+    /// The result is stuck if none of them does, even when `coords` is the last cell of the scope.
     pub fn learn(&self, coords: &Coords, color: Color) -> Multiverse {
-        let mut scope = self.scope.clone();
-        let key = BTreeSet::from([*coords]);
-        if scope == key {
-            return Multiverse::empty();
-        }
-        assert!(scope.remove(coords));
-        let layouts = Layout::split(&self.layouts, &key);
-        let layouts = layouts
+        assert!(self.scope.contains(coords));
+        // Only the layouts that are kept get copied
+        let layouts = self
+            .layouts
             .iter()
-            .filter_map(|lay| {
-                match (color, lay.binomial_coefs[&key.clone()]) {
-                    (_, 2..=u16::MAX) => panic!("Unreachable"),
-                    (Color::Blue, 1) | (Color::Black, 0) => {
-                        // Keep that layout and remove that coords
-                        let mut bc = lay.binomial_coefs.clone();
-                        bc.remove(&key);
-                        Some(Layout::new(bc))
-                    }
-                    (Color::Blue, 0) | (Color::Black, 1) => {
-                        // The layout assumed the other color than the one currently
-                        // learned
-                        None
-                    }
-                }
+            .filter(|lay| lay.allows(coords, color))
+            .map(|lay| {
+                let mut lay = lay.clone();
+                lay.learn_mut(coords, color);
+                lay
             })
             .collect();
-        Multiverse::new(scope, layouts)
+        let mut mv = Multiverse {
+            scope: self.scope.clone(),
+            layouts,
+        };
+        mv.forget(coords);
+        mv
+    }
+
+    /// Same as `learn`, in place.
+    pub fn learn_mut(&mut self, coords: &Coords, color: Color) {
+        assert!(self.scope.contains(coords));
+        self.layouts.retain_mut(|lay| {
+            if !lay.allows(coords, color) {
+                // The layout assumed the other color than the one currently learned
+                return false;
+            }
+            lay.learn_mut(coords, color);
+            true
+        });
+        self.forget(coords);
+    }
+
+    /// Drop `coords` from the scope once the layouts no longer hold it.
+    fn forget(&mut self, coords: &Coords) {
+        if self.layouts.is_empty() {
+            // Keep the scope so that the state is stuck and not empty
+            return;
+        }
+        self.scope.remove(coords);
+        if self.scope.is_empty() {
+            self.layouts.clear();
+        }
     }
 }
@@ -1,58 +1,173 @@
 use itertools::Itertools;
+use multiverse::Explosion;
 use multiverse::Multiverse;
 use once_cell::sync::Lazy;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 
 use constraint;
 use defn;
 use defn::Cell;
 use defn::Color;
 use defn::Defn;
-use env;
+use defn::ParseError;
 use env::Env;
+use env::Instant;
+use env::Timeout;
 use misc::Coords;
 use multiverse::State;
 
 /// Solver progress. Finished when `unknowns` is empty.
-struct Progress {
+/// It can also be used to step through a grid interactively, see `next_hint`.
+/// It is serialized to checkpoint a long solve, see `SolverConfig::checkpoint_every`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
     blues: BTreeSet<Coords>,
     blacks: BTreeSet<Coords>,
     unknowns: BTreeSet<Coords>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Difficulty {
+/// Split an undirected graph, given as adjacency sets, into its connected components.
+fn connected_components(graph: &BTreeMap<Coords, BTreeSet<Coords>>) -> Vec<BTreeSet<Coords>> {
+    let mut components = vec![];
+    let mut seen = BTreeSet::new();
+    for start in graph.keys() {
+        if seen.contains(start) {
+            continue;
+        }
+        let mut component = BTreeSet::new();
+        let mut stack = vec![*start];
+        while let Some(k) = stack.pop() {
+            if component.insert(k) {
+                stack.extend(graph[&k].iter().filter(|k| !component.contains(k)));
+            }
+        }
+        seen.extend(component.iter().cloned());
+        components.push(component);
+    }
+    components
+}
+
+/// How hard a step of the solve is for a player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    /// Requires the global blue count, holds the number of visible constraints at that step.
     Global(u32),
+    /// Requires looking at that many visible constraints at once.
     Local(u32),
+    /// Requires assuming the color of a cell, holds the number of nested assumptions.
+    Trial(u32),
+}
+
+/// Which tiers of deductions `solve` runs, carried by the `Env`. The default runs the compound
+/// and global tiers, quietly, as a player without trial and error would.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolverConfig {
+    /// Combine several visible constraints at once, the `Local` steps beyond 1.
+    pub enable_compound: bool,
+    /// Fall back on the global blue count, the `Global` steps.
+    pub enable_global: bool,
+    /// Assume the color of a cell and look for a contradiction, the `Trial` steps. Off by
+    /// default.
+    pub enable_trial: bool,
+    /// Stop merging the visible constraints beyond groups of `max_group_size` constraints, i.e.
+    /// skip the `Local` steps of a higher difficulty. Unbounded by default.
+    /// This trades completeness for speed: the skipped deductions are left to the trial and
+    /// global tiers, so a grid may be rated harder or even reported `Unsolvable`.
+    pub max_group_size: Option<u32>,
+    /// Give up on the global tier once merging the visible constraints holds more than
+    /// `max_layouts` layouts, the solve then ends with `Outcome::Explosion`. Unbounded by
+    /// default, but then a pathological grid can exhaust the memory.
+    pub max_layouts: Option<usize>,
+    /// Attach the `Progress` to the `SolveProgressEvent` of every `checkpoint_every` iterations,
+    /// so that the solve can be picked up later with `resume`. Never by default.
+    pub checkpoint_every: Option<u32>,
+    /// Print the grid at each iteration of the solver loop.
+    pub verbose: bool,
+    /// Color the grid printed by `verbose` with ANSI escapes, for a terminal. Off by default.
+    pub color: bool,
+}
+
+impl Default for SolverConfig {
+    fn default() -> SolverConfig {
+        SolverConfig {
+            enable_compound: true,
+            enable_global: true,
+            enable_trial: false,
+            max_group_size: None,
+            max_layouts: None,
+            checkpoint_every: None,
+            verbose: false,
+            color: false,
+        }
+    }
+}
+
+/// Fired by `solve` at the start of each iteration of the solver loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolveProgressEvent {
+    /// Starts at 1.
+    pub iteration: u32,
+    /// Number of cells known so far, including the ones revealed from the start.
+    pub known: usize,
+    /// Number of cells left to discover.
+    pub unknown: usize,
+    /// Difficulty of the previous step, `None` on the first iteration.
+    pub difficulty: Option<Difficulty>,
+    /// The progress at the start of the iteration, only on the iterations that are a multiple of
+    /// `SolverConfig::checkpoint_every`.
+    pub checkpoint: Option<Progress>,
+}
+
+/// How much work a solve took, see `solve_with_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolveStats {
+    /// Number of iterations of the solver loop.
+    pub iterations: u32,
+    /// Number of multiverses merged while looking for compound invariants, the cached merges are
+    /// not counted.
+    pub merges: u64,
+    /// Number of merges found in the cache of the previous iterations instead of being computed.
+    pub cache_hits: u64,
+    /// Largest number of constraint groups held at once while looking for compound invariants.
+    pub peak_groups: usize,
+    /// Whether the global blue count was needed at least once.
+    pub global_used: bool,
+}
+
+/// Number of steps of a solve per difficulty, see `difficulty_histogram`. The keys of `local`,
+/// `global` and `trial` are the numbers held by the `Difficulty` variants.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DifficultyHistogram {
+    pub local: BTreeMap<u32, usize>,
+    pub global: BTreeMap<u32, usize>,
+    pub trial: BTreeMap<u32, usize>,
+    /// Total number of steps.
+    pub steps: usize,
 }
 
 impl Progress {
-    fn of_defn(defn: &Defn) -> Progress {
+    pub fn of_defn(defn: &Defn) -> Progress {
         let mut blues = BTreeSet::new();
         let mut blacks = BTreeSet::new();
-        let mut unknowns = BTreeSet::new();
-        let mut add = |coords: Coords, revealed: bool, color: Color| {
-            let _: bool = match (revealed, color) {
-                (false, _) => unknowns.insert(coords),
-                (true, Color::Black) => blacks.insert(coords),
-                (true, Color::Blue) => blues.insert(coords),
+        let unknowns = defn::unknowns(defn).map(|(coords, _)| coords).collect();
+        for (coords, cell) in defn::revealed(defn) {
+            let _: bool = match defn::color_of_cell(cell).expect("Unreachable") {
+                Color::Black => blacks.insert(coords),
+                Color::Blue => blues.insert(coords),
             };
-        };
-        for (coords, cell) in defn.iter() {
-            type C = defn::Cell;
-            match cell {
-                C::Empty => (),
-                C::Line { .. } => (),
-                C::Zone0 { revealed, color } => add(*coords, *revealed, *color),
-                C::Zone6 { revealed, .. } => add(*coords, *revealed, Color::Black),
-                C::Zone18 { revealed, .. } => add(*coords, *revealed, Color::Blue),
-            }
         }
         Progress {
             blues,
@@ -61,11 +176,65 @@ impl Progress {
         }
     }
 
-    fn is_solved(&self) -> bool {
+    pub fn is_solved(&self) -> bool {
+        self.unknowns.is_empty()
+    }
+
+    /// Same as `is_solved`, there is nothing left to discover.
+    pub fn is_empty(&self) -> bool {
         self.unknowns.is_empty()
     }
 
-    fn update(&mut self, findings: BTreeMap<Coords, Color>) {
+    /// The number of cells that are yet to be discovered.
+    pub fn unknown_count(&self) -> usize {
+        self.unknowns.len()
+    }
+
+    /// Whether the color of `coords` is known, either revealed from the start or discovered.
+    pub fn is_known(&self, coords: &Coords) -> bool {
+        self.blues.contains(coords) || self.blacks.contains(coords)
+    }
+
+    /// The known blue cells.
+    pub fn blues(&self) -> &BTreeSet<Coords> {
+        &self.blues
+    }
+
+    /// The known black cells.
+    pub fn blacks(&self) -> &BTreeSet<Coords> {
+        &self.blacks
+    }
+
+    /// The cells at which `self` doesn't match `defn`: the known cells that don't have that color
+    /// in `defn`, and the cells that are not exactly one of the colored cells of `defn` either
+    /// known or unknown.
+    fn mismatches(&self, defn: &Defn) -> BTreeSet<Coords> {
+        let color_of = |coords: &Coords| defn.get(coords).and_then(defn::color_of_cell);
+        let mut mismatches: BTreeSet<_> = self
+            .blues
+            .iter()
+            .filter(|coords| color_of(coords) != Some(Color::Blue))
+            .chain(
+                self.blacks
+                    .iter()
+                    .filter(|coords| color_of(coords) != Some(Color::Black)),
+            )
+            .chain(
+                self.unknowns
+                    .iter()
+                    .filter(|coords| color_of(coords).is_none() || self.is_known(coords)),
+            )
+            .cloned()
+            .collect();
+        mismatches.extend(defn.keys().filter(|coords| {
+            color_of(coords).is_some() && !self.is_known(coords) && !self.unknowns.contains(coords)
+        }));
+        mismatches
+    }
+
+    /// Move the cells of `findings` from the unknown ones to the known ones of their color. The
+    /// colors are not checked, hence only the solver calls it.
+    pub(crate) fn update(&mut self, findings: BTreeMap<Coords, Color>) {
         for (coords, color) in findings {
             self.unknowns.remove(&coords);
             match color {
@@ -85,105 +254,269 @@ impl Progress {
 /// The exhausted ones are revealed but don't carry uncertainty anymore.
 /// The visible ones is the active set of constraint for the solver. The the actual puzzle, there
 /// are the constraints that the player has to look at in order to discover new cells.
-struct Constraints {
+/// The merge cache holds the multiverses of the constraint groups built by `compound_invariants`,
+/// an entry is dropped as soon as one of the constraints of its group is narrowed, or when its
+/// group is bigger than the ones that gave the last compound invariants. The multiverses are
+/// shared with the groups so that a hit doesn't copy the layouts.
+/// Visible constraints identical to another one are only kept once, the aliases map the key that
+/// was kept to the keys of its duplicates so that the reasons still point to all of them.
+/// The index maps each cell to the keys of the constraints whose initial scope contains it, so
+/// that `narrow` only looks at the constraints affected by the known cells.
+#[derive(Clone)]
+pub(crate) struct Constraints {
     constraints_hidden: BTreeMap<Coords, Multiverse>,
     constraints_visible: BTreeMap<Coords, Multiverse>,
     constraints_exhausted: BTreeSet<Coords>,
+    merge_cache: BTreeMap<BTreeSet<Coords>, Arc<Multiverse>>,
+    constraints_of_cell: BTreeMap<Coords, BTreeSet<Coords>>,
+    aliases: BTreeMap<Coords, BTreeSet<Coords>>,
+    stats: SolveStats,
+}
+
+/// A constraint as it was in one of the collections of `Constraints`.
+#[derive(Clone)]
+enum SavedConstraint {
+    Hidden(Multiverse),
+    Visible(Multiverse),
+    Exhausted,
 }
 
+/// Cells discovered by the solver, along with their color and the constraints that were combined
+/// in order to discover them.
+pub(crate) type Invariants = BTreeMap<Coords, (Color, BTreeSet<Coords>)>;
+
 /// This is used to give a virtual coordinate to the global constraint
 static UNIQUE_COORDS: Lazy<Coords> = Lazy::new(|| Coords::new(999, 0, -999));
 
 impl Constraints {
-    fn of_defn(defn: &Defn) -> Constraints {
+    pub(crate) fn of_defn(defn: &Defn) -> Constraints {
         let mut constraints_hidden = BTreeMap::new();
         let mut constraints_visible = BTreeMap::new();
         let constraints_exhausted = BTreeSet::new();
-        for (coords, cell) in defn {
+        for (coords, cell) in defn::constraints(defn) {
             match cell {
-                Cell::Empty => (),
-                Cell::Zone0 { .. } => (),
+                Cell::Empty | Cell::Zone0 { .. } => panic!("Unreachable"),
                 Cell::Line { m, o } => {
-                    constraints_visible.insert(*coords, constraint::line(defn, *coords, *o, *m));
+                    constraints_visible.insert(coords, constraint::line(defn, coords, *o, *m));
                 }
                 Cell::Zone6 { m, .. } => {
-                    constraints_hidden.insert(*coords, constraint::zone6(defn, *coords, *m));
+                    constraints_hidden.insert(coords, constraint::zone6(defn, coords, *m));
                 }
                 Cell::Zone18 { .. } => {
-                    constraints_hidden.insert(*coords, constraint::zone18(defn, *coords));
+                    constraints_hidden.insert(coords, constraint::zone18(defn, coords));
                 }
             }
         }
+        // Two cells may give the same constraint, e.g. two lines pointing at the same cells
+        let mut aliases: BTreeMap<Coords, BTreeSet<Coords>> = BTreeMap::new();
+        let mut originals: HashMap<Multiverse, Coords> = HashMap::new();
+        constraints_visible.retain(|k, mv: &mut Multiverse| match originals.get(mv) {
+            Some(original) => {
+                aliases.entry(*original).or_default().insert(*k);
+                false
+            }
+            None => {
+                originals.insert(mv.clone(), *k);
+                true
+            }
+        });
         constraints_visible.insert(*UNIQUE_COORDS, constraint::global_blue_count(defn));
+        let mut constraints_of_cell = BTreeMap::new();
+        for (k, mv) in constraints_hidden.iter().chain(constraints_visible.iter()) {
+            for coords in mv.scope.iter() {
+                constraints_of_cell
+                    .entry(*coords)
+                    .or_insert_with(BTreeSet::new)
+                    .insert(*k);
+            }
+        }
         Constraints {
             constraints_hidden,
             constraints_visible,
             constraints_exhausted,
+            merge_cache: BTreeMap::new(),
+            constraints_of_cell,
+            aliases,
+            stats: SolveStats::default(),
+        }
+    }
+
+    /// Add the duplicates of the constraints dropped by `of_defn` to the reasons of `invariants`.
+    fn with_aliases(&self, mut invariants: Invariants) -> Invariants {
+        if self.aliases.is_empty() {
+            return invariants;
+        }
+        for (_, reasons) in invariants.values_mut() {
+            let duplicates: Vec<_> = reasons
+                .iter()
+                .filter_map(|k| self.aliases.get(k))
+                .flatten()
+                .cloned()
+                .collect();
+            reasons.extend(duplicates);
         }
+        invariants
+    }
+
+    /// The keys of the constraints that `reveal`, `narrow` and `gc` may change once `new_cells`
+    /// are known: the ones that contain one of them and the ones located on one of them.
+    fn affected(&self, new_cells: &BTreeSet<Coords>) -> BTreeSet<Coords> {
+        new_cells
+            .iter()
+            .filter_map(|coords| self.constraints_of_cell.get(coords))
+            .flatten()
+            .chain(new_cells.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// The current state of the constraints at `keys`, for `restore`.
+    fn save(&self, keys: &BTreeSet<Coords>) -> Vec<(Coords, SavedConstraint)> {
+        keys.iter()
+            .filter_map(|k| {
+                let saved = if let Some(mv) = self.constraints_hidden.get(k) {
+                    SavedConstraint::Hidden(mv.clone())
+                } else if let Some(mv) = self.constraints_visible.get(k) {
+                    SavedConstraint::Visible(mv.clone())
+                } else if self.constraints_exhausted.contains(k) {
+                    SavedConstraint::Exhausted
+                } else {
+                    // Not a constraint, e.g. a cell without number
+                    return None;
+                };
+                Some((*k, saved))
+            })
+            .collect()
     }
 
-    fn reveal(&mut self, visible_cells: &BTreeSet<Coords>) {
-        for k in self.constraints_hidden.keys().cloned().collect::<Vec<_>>() {
-            if visible_cells.contains(&k) {
-                let mv = self.constraints_hidden.remove(&k).expect("Unreachable");
-                self.constraints_visible.insert(k, mv);
+    /// Put back the constraints as they were when `save` was called.
+    fn restore(&mut self, saved: Vec<(Coords, SavedConstraint)>) {
+        for (k, saved) in saved {
+            self.constraints_hidden.remove(&k);
+            self.constraints_visible.remove(&k);
+            self.constraints_exhausted.remove(&k);
+            self.invalidate_merge_cache(&k);
+            match saved {
+                SavedConstraint::Hidden(mv) => {
+                    self.constraints_hidden.insert(k, mv);
+                }
+                SavedConstraint::Visible(mv) => {
+                    self.constraints_visible.insert(k, mv);
+                }
+                SavedConstraint::Exhausted => {
+                    self.constraints_exhausted.insert(k);
+                }
             }
         }
     }
 
-    fn narrow(&mut self, visible_cells: &BTreeSet<Coords>, progress: &Progress) {
-        for (_k, mv) in self.constraints_visible.iter_mut() {
-            let inter: BTreeSet<_> = mv.scope.intersection(visible_cells).cloned().collect();
-            if inter.is_empty() {
-                continue;
+    /// Drop the cached merges that involve the constraint `k`.
+    fn invalidate_merge_cache(&mut self, k: &Coords) {
+        self.merge_cache.retain(|kset, _| !kset.contains(k));
+    }
+
+    /// Make visible the hidden constraints located on `new_cells`, the cells that became known
+    /// since the previous call. Passing all the known cells is also correct, only slower.
+    pub(crate) fn reveal(&mut self, new_cells: &BTreeSet<Coords>) {
+        for k in new_cells {
+            if let Some(mv) = self.constraints_hidden.remove(k) {
+                self.constraints_visible.insert(*k, mv);
             }
-            for coords in inter.intersection(&progress.blues) {
-                *mv = mv.learn(coords, Color::Blue);
+        }
+    }
+
+    /// Teach the visible constraints the colors of the cells in `progress`. Only the constraints
+    /// that contain one of `new_cells` or that are located on one of them (i.e. that were just
+    /// revealed) can be affected, the others already learned all the known cells of their scope.
+    /// Passing all the known cells is also correct, only slower.
+    pub(crate) fn narrow(&mut self, new_cells: &BTreeSet<Coords>, progress: &Progress) {
+        let mut narrowed = vec![];
+        for k in self.affected(new_cells) {
+            let mv = match self.constraints_visible.get_mut(&k) {
+                Some(mv) => mv,
+                None => continue,
+            };
+            let known: Vec<_> = mv
+                .scope
+                .iter()
+                .filter(|coords| progress.is_known(coords))
+                .cloned()
+                .collect();
+            if known.is_empty() {
+                continue;
             }
-            for coords in inter.intersection(&progress.blacks) {
-                *mv = mv.learn(coords, Color::Black);
+            for coords in known {
+                let color = if progress.blues.contains(&coords) {
+                    Color::Blue
+                } else {
+                    Color::Black
+                };
+                mv.learn_mut(&coords, color);
             }
+            narrowed.push(k);
+        }
+        for k in narrowed {
+            self.invalidate_merge_cache(&k);
         }
     }
 
-    fn gc(&mut self) {
+    /// Fails with the coords of a constraint that has no solution left, if any.
+    pub(crate) fn gc(&mut self) -> Result<(), Coords> {
         for k in self.constraints_visible.keys().cloned().collect::<Vec<_>>() {
             match self.constraints_visible[&k].state() {
                 State::Running => (),
-                State::Stuck => panic!("The grid is bugged and has no soltions"),
+                State::Stuck => return Err(k),
                 State::Empty => {
                     self.constraints_visible
                         .remove(&k.clone())
                         .expect("Unreachable");
+                    self.invalidate_merge_cache(&k);
                     self.constraints_exhausted.insert(k);
                 }
             }
         }
+        Ok(())
     }
 
     fn is_solved(&self) -> bool {
         self.constraints_visible.is_empty() && self.constraints_hidden.is_empty()
     }
 
-    fn trivial_invariants(&self, defn: &Defn) -> BTreeMap<Coords, Color> {
-        let mut invariants = BTreeMap::new();
-        for mv in self.constraints_visible.values() {
+    /// Full pass checking the invariant maintained by `reveal` and `narrow`: the constraints
+    /// located on a known cell are not hidden anymore, and the scopes of the visible ones don't
+    /// contain known cells.
+    fn is_up_to_date(&self, progress: &Progress) -> bool {
+        self.constraints_hidden
+            .keys()
+            .all(|k| !progress.is_known(k))
+            && self
+                .constraints_visible
+                .values()
+                .all(|mv| mv.scope.iter().all(|coords| !progress.is_known(coords)))
+    }
+
+    /// The invariants of the visible constraints taken one by one. Cheap for each constraint, but
+    /// a huge board has many of them, the budget of `env` is checked in between.
+    pub(crate) fn trivial_invariants(
+        &self,
+        env: &mut Env,
+        defn: &Defn,
+    ) -> Result<Invariants, SolveError> {
+        let mut invariants: Invariants = BTreeMap::new();
+        for (k, mv) in &self.constraints_visible {
+            env.check_timeout()?;
             for (coords, color) in mv.invariants() {
-                if invariants.contains_key(&coords) {
-                    assert_eq!(color, invariants[&coords]);
-                }
-                invariants.insert(coords, color);
-                assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+                add_invariant(&mut invariants, defn, coords, color, &BTreeSet::from([*k]))?;
             }
         }
-        invariants
+        Ok(invariants)
     }
 
-    fn compound_invariants(
-        &self,
+    pub(crate) fn compound_invariants(
+        &mut self,
         env: &mut Env,
         defn: &Defn,
-    ) -> Result<(BTreeMap<Coords, Color>, Difficulty), Box<dyn Error>> {
+    ) -> Result<(Invariants, Difficulty), SolveError> {
         // First construct the graph over visible constraints.
         let mut connections: BTreeMap<Coords, BTreeSet<Coords>> = self
             .constraints_visible
@@ -202,26 +535,69 @@ impl Constraints {
                 connections.get_mut(k1).expect("Unreachable").insert(*k0);
             }
         }
+        connections.remove(&*UNIQUE_COORDS);
 
-        // Then build the set of compound invariants, starting with one visible constraint per
-        // group
-        let mut constraints_groups: BTreeMap<BTreeSet<Coords>, Multiverse> = self
-            .constraints_visible
+        // Then search each connected component of the graph independently. Groups never span two
+        // components, so the components that need the least merges give the invariants. Smaller
+        // components go first so that the larger ones can stop early.
+        let mut components = connected_components(&connections);
+        components.sort_by_key(|c| c.len());
+        let mut invariants: Invariants = BTreeMap::new();
+        let mut difficulty = env.max_group_size().unwrap_or(u32::MAX);
+        for component in &components {
+            let (found, found_difficulty) =
+                self.component_invariants(env, defn, component, &connections, difficulty)?;
+            if found.is_empty() {
+                continue;
+            }
+            if found_difficulty < difficulty {
+                invariants.clear();
+                difficulty = found_difficulty;
+            }
+            for (coords, found) in found {
+                invariants.entry(coords).or_insert(found);
+            }
+        }
+        if invariants.is_empty() {
+            difficulty = 2;
+        }
+        // The bigger groups are unlikely to be needed by the next steps and hold the most layouts
+        self.merge_cache
+            .retain(|kset, _| kset.len() <= difficulty as usize);
+        Ok((invariants, Difficulty::Local(difficulty)))
+    }
+
+    /// Look for the invariants of a connected component of the graph of visible constraints by
+    /// merging bigger and bigger groups of constraints. Returns the invariants found with the
+    /// smallest groups along with the size of these groups. Nothing is returned if no invariant is
+    /// found with groups of at most `max_difficulty` constraints. Fails with the keys of the first
+    /// group whose constraints contradict each other.
+    fn component_invariants(
+        &mut self,
+        env: &mut Env,
+        defn: &Defn,
+        component: &BTreeSet<Coords>,
+        connections: &BTreeMap<Coords, BTreeSet<Coords>>,
+        max_difficulty: u32,
+    ) -> Result<(Invariants, u32), SolveError> {
+        // Start with one visible constraint per group
+        let mut constraints_groups: BTreeMap<BTreeSet<Coords>, Arc<Multiverse>> = component
             .iter()
-            .map(|(k, v)| (BTreeSet::from([*k]), v.clone()))
+            .map(|k| {
+                let mv = Arc::new(self.constraints_visible[k].clone());
+                (BTreeSet::from([*k]), mv)
+            })
             .collect();
-        constraints_groups.remove(&BTreeSet::from([*UNIQUE_COORDS]));
-        connections.remove(&*UNIQUE_COORDS);
-
-        // Then escape if there are no visible constraints
-        let mut invariants = BTreeMap::new();
+        let mut invariants: Invariants = BTreeMap::new();
         let mut difficulty = 2;
-        if constraints_groups.is_empty() {
-            return Ok((invariants, Difficulty::Local(difficulty)));
-        }
 
-        // Then loop until one or more invariants are found or that all the graph has been collapsed
+        // Then loop until one or more invariants are found or that all the component has been
+        // collapsed
         loop {
+            if difficulty > max_difficulty {
+                return Ok((BTreeMap::new(), difficulty));
+            }
+
             // One loop consists of increasing the size of constraint groups by one.
             // The first loop starts with `constraints_groups` being one group per node of the graph
             // and ends with `constraints_groups` being one group per edge of the graph.
@@ -246,74 +622,418 @@ impl Constraints {
                         // A previous iteration already created that multiverse
                         continue;
                     }
-                    let mv_new = &self.constraints_visible[k_new];
-                    // `mv_old.merge(mv_new)` is computation intensive
-                    constraints_groups.insert(kset_new, mv_old.merge(mv_new));
+                    let mv = match self.merge_cache.get(&kset_new) {
+                        // A previous call already merged that group
+                        Some(mv) => {
+                            self.stats.cache_hits += 1;
+                            mv.clone()
+                        }
+                        None => {
+                            let mv_new = &self.constraints_visible[k_new];
+                            // `mv_old.merge(mv_new)` is computation intensive
+                            let mv = Arc::new(mv_old.merge(mv_new));
+                            self.stats.merges += 1;
+                            self.merge_cache.insert(kset_new.clone(), mv.clone());
+                            mv
+                        }
+                    };
+                    constraints_groups.insert(kset_new, mv);
                 }
             }
+            self.stats.peak_groups = self.stats.peak_groups.max(constraints_groups.len());
 
-            // Look for invariants
-            for mv in constraints_groups.values() {
+            // Look for invariants. The groups are visited in the order of their keys, so when
+            // several groups find the same cell, the one kept as the reason doesn't vary from run
+            // to run.
+            for (kset, mv) in &constraints_groups {
+                if mv.state() == State::Stuck {
+                    return Err(SolveError::Contradiction(kset.clone()));
+                }
                 for (coords, color) in mv.invariants() {
-                    if invariants.contains_key(&coords) {
-                        assert_eq!(color, invariants[&coords]);
-                    }
-                    invariants.insert(coords, color);
-                    assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+                    add_invariant(&mut invariants, defn, coords, color, kset)?;
                 }
             }
 
             // Stop if necessary
             if !invariants.is_empty() {
-                break;
+                return Ok((invariants, difficulty));
             }
             if constraints_groups.is_empty() {
-                break;
+                return Ok((invariants, difficulty));
             }
             difficulty += 1;
         }
-        Ok((invariants, Difficulty::Local(difficulty)))
     }
 
-    fn global_invariants(
+    /// Look for the easiest invariants, the same way a player would: first by looking at the
+    /// constraints one by one, then by combining more and more of them, then by using the global
+    /// blue count, and as a last resort by trial and error if `env` allows it. The result is empty
+    /// if the grid requires additional rules.
+    fn find_invariants(
+        &mut self,
+        env: &mut Env,
+        defn: &Defn,
+    ) -> Result<(Invariants, Difficulty), SolveError> {
+        let (invariants, difficulty) = self.find_invariants_deduplicated(env, defn)?;
+        Ok((self.with_aliases(invariants), difficulty))
+    }
+
+    /// `find_invariants` without the duplicates of the constraints in the reasons.
+    fn find_invariants_deduplicated(
+        &mut self,
+        env: &mut Env,
+        defn: &Defn,
+    ) -> Result<(Invariants, Difficulty), SolveError> {
+        // Each step gets a fresh per-step budget, the total budget keeps running
+        env.reset_timer();
+        env.check_timeout()?;
+
+        // Look for trivial invariants (i.e. previously unknown cells that can be infered by
+        // looking at a single constraint).
+        let invariants = self.trivial_invariants(env, defn)?;
+        if !invariants.is_empty() {
+            return Ok((invariants, Difficulty::Local(1)));
+        }
+
+        // Look for compound invariants, gradually increasing the level of cognitive load for the
+        // player. (global constraint is exclduded here because it is likely to cause
+        // combinatorial explosion)
+        if env.config().enable_compound {
+            let (invariants, difficulty) = self.compound_invariants(env, defn)?;
+            if !invariants.is_empty() {
+                return Ok((invariants, difficulty));
+            }
+        }
+
+        // Look for invariants using the global constraints
+        let global = if env.config().enable_global {
+            self.stats.global_used = true;
+            // The duplicates count as well, as they would for the player
+            let n = self.constraints_visible.len()
+                + self
+                    .constraints_visible
+                    .keys()
+                    .filter_map(|k| self.aliases.get(k))
+                    .map(|duplicates| duplicates.len())
+                    .sum::<usize>();
+            let difficulty = Difficulty::Global(n.try_into().unwrap());
+            match self.global_invariants(env, defn) {
+                Ok(invariants) if !invariants.is_empty() => return Ok((invariants, difficulty)),
+                Ok(invariants) => Ok((invariants, difficulty)),
+                Err(SolveError::Explosion) => Err(SolveError::Explosion),
+                Err(e) => return Err(e),
+            }
+        } else {
+            Ok((BTreeMap::new(), Difficulty::Local(2)))
+        };
+
+        // Look for invariants by trial and error, once the logical tiers found nothing or gave up
+        if env.config().enable_trial {
+            let invariants = self.trial_invariants(env, defn)?;
+            if !invariants.is_empty() {
+                return Ok((invariants, Difficulty::Trial(1)));
+            }
+        }
+        global
+    }
+
+    /// Assume that each unknown cell is blue, then black, and propagate that assumption through
+    /// the visible constraints. A cell has a color if the other one leads to a contradiction.
+    pub(crate) fn trial_invariants(
         &self,
         env: &mut Env,
         defn: &Defn,
-    ) -> Result<BTreeMap<Coords, Color>, Box<dyn Error>> {
-        let mut invariants = BTreeMap::new();
-        // Using rev() here is a quick and dirty hack to make sure that the
-        // global constraint is first in the fold. This greatly improves
-        // runtime.
-        let mut mv = Multiverse::empty();
-        for mv2 in self.constraints_visible.values().rev() {
+    ) -> Result<Invariants, SolveError> {
+        let mut invariants: Invariants = BTreeMap::new();
+        let reasons: BTreeSet<_> = self
+            .constraints_visible
+            .keys()
+            .filter(|k| **k != *UNIQUE_COORDS)
+            .cloned()
+            .collect();
+        let constraints: Vec<_> = self.constraints_visible.values().cloned().collect();
+        let cells: BTreeSet<_> = constraints
+            .iter()
+            .flat_map(|mv| mv.scope.iter().cloned())
+            .collect();
+        for coords in cells {
             env.check_timeout()?;
-            mv = mv.merge(mv2);
+            for (assumed, color) in [(Color::Blue, Color::Black), (Color::Black, Color::Blue)] {
+                let contradiction = match assign(&constraints, &coords, assumed) {
+                    None => true,
+                    Some(constraints) => propagate(constraints, &mut BTreeMap::new()).is_none(),
+                };
+                if contradiction {
+                    add_invariant(&mut invariants, defn, coords, color, &reasons)?;
+                    break;
+                }
+            }
         }
-        for (coords, color) in mv.invariants() {
-            if invariants.contains_key(&coords) {
-                assert_eq!(color, invariants[&coords]);
+        Ok(invariants)
+    }
+
+    pub(crate) fn global_invariants(
+        &self,
+        env: &mut Env,
+        defn: &Defn,
+    ) -> Result<Invariants, SolveError> {
+        let mut invariants: Invariants = BTreeMap::new();
+        // The global blue count has no cell to point at, the reasons are the other constraints
+        let reasons: BTreeSet<_> = self
+            .constraints_visible
+            .keys()
+            .filter(|k| **k != *UNIQUE_COORDS)
+            .cloned()
+            .collect();
+        // The merge is associative, the constraints are merged as a tree, see `reduce_merges`.
+        // Each subtree starts from the global constraint: bounding the blue count early greatly
+        // improves runtime and keeps the layouts below `max_layouts` whatever the thread count.
+        // rev() keeps the order in which the constraints were merged when the global one came
+        // first as the last key.
+        let max_layouts = env.config().max_layouts.unwrap_or(usize::MAX);
+        let env: &Env = env;
+        let seed = match self.constraints_visible.get(&*UNIQUE_COORDS) {
+            Some(mv) => (BTreeSet::new(), mv.clone()),
+            None => (BTreeSet::new(), Multiverse::empty()),
+        };
+        let leaves: Vec<_> = self
+            .constraints_visible
+            .iter()
+            .rev()
+            .filter(|(k, _)| **k != *UNIQUE_COORDS)
+            .map(|(k, mv)| (BTreeSet::from([*k]), mv.clone()))
+            .collect();
+        let merge = |(mut keys, mv): MergedGroup, (keys2, mv2): MergedGroup| {
+            env.check_timeout()?;
+            let mv = mv.try_merge(&mv2, max_layouts)?;
+            keys.extend(keys2);
+            if mv.state() == State::Stuck {
+                // The constraints of that subtree contradict each other
+                return Err(SolveError::Contradiction(keys));
             }
-            invariants.insert(coords, color);
-            assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+            Ok((keys, mv))
+        };
+        let (_, mv) = reduce_merges(seed, leaves, merge)?;
+        for (coords, color) in mv.invariants() {
+            add_invariant(&mut invariants, defn, coords, color, &reasons)?;
         }
         Ok(invariants)
     }
 }
 
+/// Record in `invariants` that `coords` is `color` because of the constraints at `reasons`, unless
+/// an earlier reason already found it. Fails with the constraints involved when that color
+/// disagrees with the earlier one or with the actual color of the cell, i.e. when the numbers of
+/// `defn` don't match its colors, e.g. because of a wrong `{n}` or `-n-` modifier. The global blue
+/// count is left out of the reasons, it is not a cell of the grid.
+fn add_invariant(
+    invariants: &mut Invariants,
+    defn: &Defn,
+    coords: Coords,
+    color: Color,
+    reasons: &BTreeSet<Coords>,
+) -> Result<(), SolveError> {
+    let mut involved: BTreeSet<_> = reasons
+        .iter()
+        .filter(|k| **k != *UNIQUE_COORDS)
+        .cloned()
+        .collect();
+    match invariants.get(&coords) {
+        Some((found, _)) if *found == color => return Ok(()),
+        Some((_, earlier)) => involved.extend(earlier),
+        None if defn::color_of_cell(&defn[&coords]) == Some(color) => {
+            invariants.insert(coords, (color, involved));
+            return Ok(());
+        }
+        None => (),
+    }
+    Err(SolveError::Contradiction(involved))
+}
+
+/// Constraints merged together along with their keys, the global blue count has no key.
+type MergedGroup = (BTreeSet<Coords>, Multiverse);
+
+/// Merge `leaves` as a tree of which each thread of rayon takes a subtree, in the order of
+/// `leaves`. Every subtree starts from `seed`, which must be safe to merge several times.
+/// With a single thread, or on wasm32 that has none, waking the pool up costs more than it saves
+/// and the leaves are merged one after the other.
+fn reduce_merges<F>(
+    seed: MergedGroup,
+    leaves: Vec<MergedGroup>,
+    merge: F,
+) -> Result<MergedGroup, SolveError>
+where
+    F: Fn(MergedGroup, MergedGroup) -> Result<MergedGroup, SolveError> + Send + Sync,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    if rayon::current_num_threads() > 1 {
+        let identity = || (BTreeSet::new(), Multiverse::empty());
+        return leaves
+            .into_par_iter()
+            .try_fold_with(seed, &merge)
+            .try_reduce(identity, &merge);
+    }
+    leaves.into_iter().try_fold(seed, merge)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Findings {
     difficulty: Difficulty,
     cells: BTreeSet<Coords>,
+    /// For each cell, the coords of the constraints that were combined in order to discover it.
+    /// The global blue count, which is not a cell of the grid, is left out.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "serialize_reasons",
+        deserialize_with = "deserialize_reasons"
+    )]
+    reasons: BTreeMap<Coords, BTreeSet<Coords>>,
+    /// Time spent by the solver on that step, only recorded when the `Env` asks for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    elapsed: Option<Duration>,
+}
+
+/// JSON objects only have string keys, the reasons are serialized as a list of pairs instead.
+fn serialize_reasons<S: Serializer>(
+    reasons: &BTreeMap<Coords, BTreeSet<Coords>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(reasons)
+}
+
+fn deserialize_reasons<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<Coords, BTreeSet<Coords>>, D::Error> {
+    let pairs: Vec<(Coords, BTreeSet<Coords>)> = Vec::deserialize(deserializer)?;
+    Ok(pairs.into_iter().collect())
+}
+
+impl Findings {
+    /// A step of difficulty 1 that discovers a single cell.
+    pub fn new_local(cell: Coords) -> Findings {
+        Findings {
+            difficulty: Difficulty::Local(1),
+            cells: BTreeSet::from([cell]),
+            reasons: BTreeMap::new(),
+            elapsed: None,
+        }
+    }
+
+    /// A step that discovers a single cell using the global blue count. The number of visible
+    /// constraints is unknown here, it is taken as 1.
+    pub fn new_global(cell: Coords) -> Findings {
+        Findings {
+            difficulty: Difficulty::Global(1),
+            cells: BTreeSet::from([cell]),
+            reasons: BTreeMap::new(),
+            elapsed: None,
+        }
+    }
+
+    /// How hard that step is for a player.
+    pub fn difficulty(&self) -> &Difficulty {
+        &self.difficulty
+    }
+
+    /// The cells discovered during that step.
+    pub fn cells(&self) -> &BTreeSet<Coords> {
+        &self.cells
+    }
+
+    /// The constraints that justify each of the cells discovered during that step.
+    pub fn reasons(&self) -> &BTreeMap<Coords, BTreeSet<Coords>> {
+        &self.reasons
+    }
+
+    /// The time the solver spent on that step, see `Env::with_step_timings`.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Outcome {
-    Timeout,
+    /// The solver gave up. Holds the findings made before the timeout.
+    Timeout(Vec<Findings>),
     Unsolvable,
     Solved(Vec<Findings>),
+    /// The grid is bugged and has no solution, `constraints` can't be satisfied together. The
+    /// global blue count has no cell and is never listed, it may take part in the contradiction.
+    Contradiction {
+        constraints: BTreeSet<Coords>,
+    },
+    /// The global tier exceeded `SolverConfig::max_layouts`. Holds the findings made before.
+    Explosion(Vec<Findings>),
+}
+
+/// The ways the functions of this module can fail.
+#[derive(Debug)]
+pub enum SolveError {
+    /// The runtime exceeded the limit of the `Env`.
+    Timeout,
+    /// The revealed cells, or the colors of the grid itself, contradict the constraints at these
+    /// coords, taken together. The global blue count is never listed.
+    Contradiction(BTreeSet<Coords>),
+    /// No coloring of the unknown cells satisfies all the constraints.
+    NoSolution,
+    /// Merging the constraints exceeded `SolverConfig::max_layouts`.
+    Explosion,
+    /// The `Progress` given to `resume` doesn't match the grid at these cells.
+    Checkpoint(BTreeSet<Coords>),
+    /// The string definition of the grid is invalid.
+    Parse(ParseError),
+}
+
+impl Error for SolveError {}
+
+impl fmt::Display for SolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SolveError::Timeout => write!(f, "Timeout"),
+            SolveError::Contradiction(constraints) => fmt_contradiction(f, constraints),
+            SolveError::NoSolution => write!(f, "The grid has no solution"),
+            SolveError::Explosion => write!(f, "Too many possibilities"),
+            SolveError::Checkpoint(cells) => write!(
+                f,
+                "The checkpoint doesn't match the grid at {}",
+                cells.iter().join(", ")
+            ),
+            SolveError::Parse(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Name the constraints of a contradiction, the same way for `SolveError` and `Outcome`.
+fn fmt_contradiction(f: &mut fmt::Formatter, constraints: &BTreeSet<Coords>) -> fmt::Result {
+    if constraints.len() == 1 {
+        write!(f, "Contradiction at constraint ")?;
+    } else {
+        write!(f, "Contradiction between constraints ")?;
+    }
+    write!(f, "{}", constraints.iter().join(", "))
 }
 
-#[allow(dead_code)]
+impl From<Timeout> for SolveError {
+    fn from(_: Timeout) -> SolveError {
+        SolveError::Timeout
+    }
+}
+
+impl From<Explosion> for SolveError {
+    fn from(_: Explosion) -> SolveError {
+        SolveError::Explosion
+    }
+}
+
+impl From<ParseError> for SolveError {
+    fn from(err: ParseError) -> SolveError {
+        SolveError::Parse(err)
+    }
+}
+
+/// The max local and global difficulties among the steps of a solve, `None` when no step has that
+/// kind of difficulty. The trial steps are ignored.
 pub fn difficulty_of_findings_vec(findings_vec: &Vec<Findings>) -> (Option<u32>, Option<u32>) {
     let mut max_local = None;
     let mut max_global = None;
@@ -325,20 +1045,206 @@ pub fn difficulty_of_findings_vec(findings_vec: &Vec<Findings>) -> (Option<u32>,
             Difficulty::Local(diff) => {
                 max_local = Some(max_local.map_or(diff, |prev_max: u32| prev_max.max(diff)));
             }
+            Difficulty::Trial(_) => (),
         }
     }
     (max_local, max_global)
 }
 
+/// Count the steps of a solve per difficulty.
+pub fn difficulty_histogram(findings_vec: &[Findings]) -> DifficultyHistogram {
+    let mut histogram = DifficultyHistogram {
+        steps: findings_vec.len(),
+        ..Default::default()
+    };
+    for findings in findings_vec {
+        let (counts, diff) = match findings.difficulty {
+            Difficulty::Global(diff) => (&mut histogram.global, diff),
+            Difficulty::Local(diff) => (&mut histogram.local, diff),
+            Difficulty::Trial(diff) => (&mut histogram.trial, diff),
+        };
+        *counts.entry(diff).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// Rate a solve from 1 (trivial) to 10 (expert). The score is `1 + 9 * x` where `x` in `[0, 1]` is
+/// the weighted sum of:
+/// - `0.2`: the number of steps, saturating at 60,
+/// - `0.4`: the max local difficulty, from 1 (a single constraint) saturating at 8,
+/// - `0.2`: whether the global blue count was required at all,
+/// - `0.2`: the share of steps that required the global blue count.
+///
+/// A trial step counts as a local step of the max difficulty.
+pub fn difficulty_score(findings_vec: &[Findings]) -> f64 {
+    let histogram = difficulty_histogram(findings_vec);
+    if histogram.steps == 0 {
+        return 1.;
+    }
+    let steps = (histogram.steps as f64 / 60.).min(1.);
+    let local = if !histogram.trial.is_empty() {
+        1.
+    } else {
+        let max_local = histogram.local.keys().next_back().copied().unwrap_or(1);
+        ((max_local.max(1) - 1) as f64 / 7.).min(1.)
+    };
+    let global_steps: usize = histogram.global.values().sum();
+    let global_used = if global_steps > 0 { 1. } else { 0. };
+    let global_share = global_steps as f64 / histogram.steps as f64;
+    1. + 9. * (0.2 * steps + 0.4 * local + 0.2 * global_used + 0.2 * global_share)
+}
+
+/// `cells` as `a`, `a and b`, `a, b and c`...
+fn enumerate_coords(cells: &[Coords]) -> String {
+    let names: Vec<_> = cells.iter().map(|c| c.to_string()).collect();
+    match names.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, init)) => format!("{} and {}", init.join(", "), last),
+    }
+}
+
+/// `cells` along with their color in `defn`, e.g. `a and b are blue, c is black`.
+fn describe_cells(defn: &Defn, cells: &BTreeSet<Coords>) -> String {
+    let mut parts = vec![];
+    for (color, name) in [(Color::Blue, "blue"), (Color::Black, "black")] {
+        let group: Vec<_> = cells
+            .iter()
+            .filter(|c| defn.get(c).and_then(defn::color_of_cell) == Some(color))
+            .cloned()
+            .collect();
+        match group.len() {
+            0 => (),
+            1 => parts.push(format!("{} is {}", enumerate_coords(&group), name)),
+            _ => parts.push(format!("{} are {}", enumerate_coords(&group), name)),
+        }
+    }
+    parts.join(", ")
+}
+
+impl Outcome {
+    /// Narrate the steps of the solve for a human, one line per step. The cells of a step are
+    /// grouped by the constraints that were combined in order to discover them.
+    pub fn walkthrough(&self, defn: &Defn) -> String {
+        let findings_vec = match self {
+            Outcome::Solved(findings_vec)
+            | Outcome::Timeout(findings_vec)
+            | Outcome::Explosion(findings_vec) => &findings_vec[..],
+            Outcome::Unsolvable | Outcome::Contradiction { .. } => &[],
+        };
+        let mut lines = vec![];
+        for (i, findings) in findings_vec.iter().enumerate() {
+            let (tier, diff) = match findings.difficulty {
+                Difficulty::Local(diff) => ("local", diff),
+                Difficulty::Global(diff) => ("global", diff),
+                Difficulty::Trial(diff) => ("trial", diff),
+            };
+            let mut groups: BTreeMap<Option<&BTreeSet<Coords>>, BTreeSet<Coords>> = BTreeMap::new();
+            for coords in &findings.cells {
+                groups
+                    .entry(findings.reasons.get(coords))
+                    .or_default()
+                    .insert(*coords);
+            }
+            let sentences: Vec<_> = groups
+                .into_iter()
+                .map(|(reasons, cells)| {
+                    let cells = describe_cells(defn, &cells);
+                    match (findings.difficulty, reasons) {
+                        (Difficulty::Global(_), _) => {
+                            format!("the remaining blue count tells that {}", cells)
+                        }
+                        (Difficulty::Local(_), Some(reasons)) if reasons.is_empty() => {
+                            format!("the remaining blue count tells that {}", cells)
+                        }
+                        (Difficulty::Trial(_), _) => {
+                            format!(
+                                "assuming the other colors leads to a contradiction, so {}",
+                                cells
+                            )
+                        }
+                        (Difficulty::Local(_), None) => cells,
+                        (Difficulty::Local(_), Some(reasons)) => {
+                            let constraints: Vec<_> = reasons
+                                .iter()
+                                .map(|k| match defn::label_of_cell(defn, k) {
+                                    Some(label) => format!("the {} at {}", label, k),
+                                    None => format!("the constraint at {}", k),
+                                })
+                                .collect();
+                            let constraints = match constraints.split_last() {
+                                Some((last, [])) => last.clone(),
+                                Some((last, init)) => {
+                                    format!("combining {} and {}", init.join(", "), last)
+                                }
+                                None => panic!("Unreachable"),
+                            };
+                            format!("{} tells that {}", constraints, cells)
+                        }
+                    }
+                })
+                .collect();
+            lines.push(format!(
+                "Step {} ({} difficulty {}): {}.",
+                i + 1,
+                tier,
+                diff,
+                sentences.join("; ")
+            ));
+        }
+        if !matches!(self, Outcome::Solved(_)) {
+            lines.push(format!("{}.", self));
+        }
+        lines.join("\n")
+    }
+
+    /// The hardest tier of deductions that the solve needed, in a few words, e.g. `needs compound
+    /// up to size 4`. Meant to sort puzzles, where `Display` gives the details.
+    pub fn technique_summary(&self) -> String {
+        let findings_vec = match self {
+            Outcome::Solved(findings_vec) => findings_vec,
+            Outcome::Unsolvable => return String::from("unsolvable"),
+            Outcome::Contradiction { .. } => return String::from("contradictory"),
+            Outcome::Timeout(_) => return String::from("unknown, timed out"),
+            Outcome::Explosion(_) => return String::from("unknown, too many possibilities"),
+        };
+        // The tiers run in that order, trial and error is the last resort
+        let histogram = difficulty_histogram(findings_vec);
+        if let Some(max_trial) = histogram.trial.keys().last() {
+            format!("needs trial and error up to depth {}", max_trial)
+        } else if let Some(max_global) = histogram.global.keys().last() {
+            format!("needs global over {} constraints", max_global)
+        } else {
+            match histogram.local.keys().last() {
+                Some(max_local) if *max_local > 1 => {
+                    format!("needs compound up to size {}", max_local)
+                }
+                _ => String::from("solvable with trivial only"),
+            }
+        }
+    }
+}
+
 impl fmt::Display for Outcome {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Outcome::Unsolvable => write!(f, "Requires additional rules"),
-            Outcome::Timeout => write!(f, "Timeout"),
+            Outcome::Contradiction { constraints } => fmt_contradiction(f, constraints),
+            Outcome::Timeout(findings_vec) => {
+                write!(f, "Timeout after steps:{}", findings_vec.len())
+            }
+            Outcome::Explosion(findings_vec) => {
+                write!(
+                    f,
+                    "Too many possibilities after steps:{}",
+                    findings_vec.len()
+                )
+            }
             Outcome::Solved(findings_vec) => {
                 let mut steps = 0;
                 let mut max_local = None;
                 let mut max_global = None;
+                let mut max_trial = None;
                 for findings in findings_vec {
                     steps += 1;
                     match findings.difficulty {
@@ -350,94 +1256,1979 @@ impl fmt::Display for Outcome {
                             max_local =
                                 Some(max_local.map_or(diff, |prev_max: u32| prev_max.max(diff)));
                         }
+                        Difficulty::Trial(diff) => {
+                            max_trial =
+                                Some(max_trial.map_or(diff, |prev_max: u32| prev_max.max(diff)));
+                        }
                     }
                 }
                 write!(
                     f,
                     "Solved steps:{} max-local-difficulty:{:?} max-global-difficulty:{:?}",
                     steps, max_local, max_global
-                )
+                )?;
+                if let Some(max_trial) = max_trial {
+                    write!(f, " max-trial-difficulty:{}", max_trial)?;
+                }
+                Ok(())
             }
         }
     }
 }
 
-pub fn solve(env: &mut Env, defn: &Defn, verbose: bool) -> Outcome {
-    let mut progress = Progress::of_defn(defn);
+/// Solve `defn` step by step, the way a player would, with the tiers of deductions allowed by the
+/// `SolverConfig` of `env`. `on_progress`, if any, is called at each iteration of the solver
+/// loop.
+pub fn solve(
+    env: &mut Env,
+    defn: &Defn,
+    on_progress: Option<&mut dyn FnMut(&SolveProgressEvent)>,
+) -> Outcome {
+    solve_with_stats(env, defn, on_progress).0
+}
+
+/// Same as `solve`, along with statistics about the work it took.
+pub fn solve_with_stats(
+    env: &mut Env,
+    defn: &Defn,
+    on_progress: Option<&mut dyn FnMut(&SolveProgressEvent)>,
+) -> (Outcome, SolveStats) {
+    solve_from(env, defn, Progress::of_defn(defn), on_progress)
+}
+
+/// Same as `solve`, starting from a `progress` checkpointed by an earlier solve of `defn`, see
+/// `SolverConfig::checkpoint_every`. The constraints are rebuilt from `defn` and narrowed down to
+/// the known cells of `progress`, the findings only hold the steps made after the checkpoint.
+/// A checkpoint usually comes from the disk, it is rejected if it doesn't match `defn`.
+pub fn resume(
+    env: &mut Env,
+    defn: &Defn,
+    progress: &Progress,
+    on_progress: Option<&mut dyn FnMut(&SolveProgressEvent)>,
+) -> Result<Outcome, SolveError> {
+    let mismatches = progress.mismatches(defn);
+    if !mismatches.is_empty() {
+        return Err(SolveError::Checkpoint(mismatches));
+    }
+    Ok(solve_from(env, defn, progress.clone(), on_progress).0)
+}
+
+fn solve_from(
+    env: &mut Env,
+    defn: &Defn,
+    mut progress: Progress,
+    mut on_progress: Option<&mut dyn FnMut(&SolveProgressEvent)>,
+) -> (Outcome, SolveStats) {
     let mut constraints = Constraints::of_defn(defn);
     let mut history = vec![];
-    let mut difficulty;
-    loop {
-        let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
-        if verbose {
-            println!(
-                "Solver loop with visibles:{}, unknown:{}",
-                visible_cells.len(),
-                progress.unknowns.len(),
+    let mut iterations = 0;
+    // The cells that became known since the previous iteration, all the known ones at first
+    let mut new_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+    let outcome = loop {
+        iterations += 1;
+        let step_start = Instant::now();
+        if env.config().verbose {
+            debug!(
+                "\n{}",
+                defn::render_progress(defn, &progress.blues, &progress.blacks, env.config().color)
             );
         }
+        if let Some(on_progress) = on_progress.as_mut() {
+            on_progress(&SolveProgressEvent {
+                iteration: iterations,
+                known: progress.blues.len() + progress.blacks.len(),
+                unknown: progress.unknowns.len(),
+                difficulty: history.last().map(|f: &Findings| f.difficulty),
+                checkpoint: match env.config().checkpoint_every {
+                    Some(n) if iterations % n == 0 => Some(progress.clone()),
+                    _ => None,
+                },
+            });
+        }
 
         // Step 1 - Transfer constraints from hidden to visible in order to reflect the status of
-        // `progress`.
-        constraints.reveal(&visible_cells);
+        // `progress`. Only the ones located on `new_cells` may be concerned.
+        constraints.reveal(&new_cells);
 
-        // Step 2 - Narrow down each of the visible constraints in order to reflect the status of
-        // `progress`.
-        constraints.narrow(&visible_cells, &progress);
+        // Step 2 - Narrow down the visible constraints in order to reflect the status of
+        // `progress`. Only the ones touching `new_cells` may be concerned.
+        constraints.narrow(&new_cells, &progress);
 
         // Step 3 - Transfer visible constraints to exhausted if they don't carry uncertainty
         // anymore (i.e. the ones that were narrowed while `progress` knows all they scope).
-        constraints.gc();
+        if let Err(at) = constraints.gc() {
+            break Outcome::Contradiction {
+                constraints: BTreeSet::from([at]),
+            };
+        }
+        debug_assert!(constraints.is_up_to_date(&progress));
 
         // Step 4 - Check if finished
         if progress.is_solved() {
             assert!(constraints.is_solved());
-            break;
+            break Outcome::Solved(history);
         } else {
             assert!(!constraints.is_solved());
         }
 
-        // Step 5.1 - Look for trivial invariants (i.e. previously unknown cells that can be infered
-        // by looking at a single constraint).
-        let mut invariants = constraints.trivial_invariants(defn);
-        difficulty = Difficulty::Local(1);
+        // Step 5 - Look for invariants, from the easiest to the hardest ones
+        let (invariants, difficulty) = match constraints.find_invariants(env, defn) {
+            Ok(x) => x,
+            Err(SolveError::Timeout) => break Outcome::Timeout(history),
+            Err(SolveError::Explosion) => break Outcome::Explosion(history),
+            Err(SolveError::Contradiction(constraints)) => {
+                break Outcome::Contradiction { constraints }
+            }
+            Err(err) => panic!("Unexpected error {}", err),
+        };
+        if invariants.is_empty() {
+            break Outcome::Unsolvable;
+        }
+        history.push(Findings {
+            difficulty,
+            cells: invariants.keys().cloned().collect(),
+            reasons: invariants
+                .iter()
+                .map(|(coords, (_, reasons))| (*coords, reasons.clone()))
+                .collect(),
+            elapsed: env.step_timings().then(|| step_start.elapsed()),
+        });
+
+        // Step 6 - Reflect findings in progress
+        new_cells = invariants.keys().cloned().collect();
+        progress.update(
+            invariants
+                .into_iter()
+                .map(|(coords, (color, _))| (coords, color))
+                .collect(),
+        );
+    };
+    let stats = SolveStats {
+        iterations,
+        ..constraints.stats
+    };
+    (outcome, stats)
+}
+
+/// Find the easiest deduction a player can make next, along with its difficulty. `progress_cells`
+/// holds the cells discovered by the player on top of the ones revealed from the start.
+/// Returns `None` once the grid is solved, on timeout, if the grid requires additional rules, or
+/// if `progress_cells` contradicts the grid.
+pub fn next_hint(
+    defn: &Defn,
+    progress_cells: &BTreeMap<Coords, Color>,
+    env: &mut Env,
+) -> Option<(Coords, Color, Difficulty)> {
+    let mut progress = Progress::of_defn(defn);
+    // A wrong color may not break any constraint on its own, the solver would then trip on it
+    for (coords, color) in progress_cells {
+        if !progress.unknowns.contains(coords) || defn::color_of_cell(&defn[coords]) != Some(*color)
+        {
+            return None;
+        }
+    }
+    progress.update(progress_cells.clone());
+    let mut constraints = Constraints::of_defn(defn);
+    let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+    constraints.reveal(&visible_cells);
+    constraints.narrow(&visible_cells, &progress);
+    constraints.gc().ok()?;
+    if progress.is_solved() {
+        return None;
+    }
+    let (invariants, difficulty) = constraints.find_invariants(env, defn).ok()?;
+    let (coords, (color, _)) = invariants.into_iter().next()?;
+    Some((coords, color, difficulty))
+}
+
+/// The ways a move of a `Session` can be rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MoveError {
+    /// The cell is not an unknown cell of the grid.
+    NotUnknown(Coords),
+    /// The cell has the other color in the solution.
+    WrongColor(Coords),
+    /// The move is right, but the constraints at these coords don't allow it: the numbers of the
+    /// grid don't match its colors, e.g. because of a wrong `{n}` or `-n-` modifier.
+    Contradiction(BTreeSet<Coords>),
+}
+
+impl Error for MoveError {}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoveError::NotUnknown(at) => write!(f, "Not an unknown cell {}", at),
+            MoveError::WrongColor(at) => write!(f, "Wrong color for {}", at),
+            MoveError::Contradiction(constraints) => fmt_contradiction(f, constraints),
+        }
+    }
+}
+
+/// Cells to reveal along with their color.
+pub type Moves = BTreeMap<Coords, Color>;
+
+/// A grid being played move by move, e.g. from a user interface. This is the state of the loop of
+/// `solve`, `apply` plays a move and `deductions` looks for the next ones.
+/// Each step keeps the constraints it changed as they were before, so that `undo_last` can put
+/// them back exactly.
+pub struct Session {
+    defn: Defn,
+    progress: Progress,
+    constraints: Constraints,
+    steps: Vec<(Moves, Vec<(Coords, SavedConstraint)>)>,
+}
+
+impl Session {
+    pub fn new(defn: Defn) -> Result<Session, SolveError> {
+        let progress = Progress::of_defn(&defn);
+        let mut constraints = Constraints::of_defn(&defn);
+        let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+        constraints.reveal(&visible_cells);
+        constraints.narrow(&visible_cells, &progress);
+        constraints.gc().map_err(contradiction_at)?;
+        Ok(Session {
+            defn,
+            progress,
+            constraints,
+            steps: vec![],
+        })
+    }
+
+    pub fn defn(&self) -> &Defn {
+        &self.defn
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    pub fn is_solved(&self) -> bool {
+        self.progress.is_solved()
+    }
+
+    /// Reveal `coords` as `color`, provided that it is an unknown cell of that color in the
+    /// solution.
+    pub fn apply(&mut self, coords: Coords, color: Color) -> Result<(), MoveError> {
+        self.apply_all(BTreeMap::from([(coords, color)]))
+    }
+
+    /// Reveal all of `moves` as a single step, e.g. the result of `deductions`. Nothing is
+    /// revealed if one of them is rejected, or if they contradict the constraints.
+    pub fn apply_all(&mut self, moves: Moves) -> Result<(), MoveError> {
+        for (coords, color) in &moves {
+            if !self.progress.unknowns.contains(coords) {
+                return Err(MoveError::NotUnknown(*coords));
+            }
+            if defn::color_of_cell(&self.defn[coords]) != Some(*color) {
+                return Err(MoveError::WrongColor(*coords));
+            }
+        }
+        let new_cells: BTreeSet<_> = moves.keys().cloned().collect();
+        let saved = self
+            .constraints
+            .save(&self.constraints.affected(&new_cells));
+        self.progress.update(moves.clone());
+        self.constraints.reveal(&new_cells);
+        self.constraints.narrow(&new_cells, &self.progress);
+        self.steps.push((moves, saved));
+        if let Err(at) = self.constraints.gc() {
+            self.undo_last();
+            return Err(MoveError::Contradiction(BTreeSet::from([at])));
+        }
+        Ok(())
+    }
+
+    /// Revert the last step of `apply` or `apply_all`, returns its moves. `None` if there is no
+    /// step left.
+    pub fn undo_last(&mut self) -> Option<Moves> {
+        let (moves, saved) = self.steps.pop()?;
+        for coords in moves.keys() {
+            self.progress.blues.remove(coords);
+            self.progress.blacks.remove(coords);
+            self.progress.unknowns.insert(*coords);
+        }
+        self.constraints.restore(saved);
+        Some(moves)
+    }
 
-        // Step 5.2 - Look for compound invariants, gradually increasing the level of cognitive load
-        // for the player. (global constraint is exclduded here because it is likely to cause
-        // combinatorial explosion, see step 5.3 for this)
+    /// The easiest moves that can be deduced from the revealed cells, along with their
+    /// difficulty. `None` once the grid is solved or if it requires additional rules.
+    pub fn deductions(&mut self, env: &mut Env) -> Result<Option<(Moves, Difficulty)>, SolveError> {
+        if self.progress.is_solved() {
+            return Ok(None);
+        }
+        let (invariants, difficulty) = self.constraints.find_invariants(env, &self.defn)?;
         if invariants.is_empty() {
-            env.reset_timer();
-            (invariants, difficulty) = match constraints.compound_invariants(env, defn) {
-                Ok(x) => x,
-                Err(err) => match err.downcast::<env::Timeout>() {
-                    Ok(_) => return Outcome::Timeout,
-                    Err(_) => panic!("compound_invariants failed"),
-                },
-            };
+            return Ok(None);
         }
+        let moves = invariants
+            .into_iter()
+            .map(|(coords, (color, _))| (coords, color))
+            .collect();
+        Ok(Some((moves, difficulty)))
+    }
+}
+
+/// Every constraint of `defn`, the global one included, narrowed down to the cells that are
+/// revealed from the start. The player only sees the number of a hidden cell once that cell is
+/// uncovered, so its constraint only holds in the colorings that give that cell its actual color,
+/// see `constraint::conditional`.
+pub(crate) fn all_constraints(defn: &Defn) -> Result<Vec<Multiverse>, SolveError> {
+    let progress = Progress::of_defn(defn);
+    let mut constraints = Constraints::of_defn(defn);
+    let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+    let hidden = std::mem::take(&mut constraints.constraints_hidden);
+    for (k, mv) in hidden {
+        let color = defn::color_of_cell(&defn[&k]).expect("Unreachable");
+        // The conditional constraint also covers its own cell
+        constraints
+            .constraints_of_cell
+            .entry(k)
+            .or_default()
+            .insert(k);
+        constraints
+            .constraints_visible
+            .insert(k, constraint::conditional(k, color, &mv));
+    }
+    constraints.narrow(&visible_cells, &progress);
+    constraints.gc().map_err(contradiction_at)?;
+    Ok(constraints.constraints_visible.into_values().collect())
+}
+
+/// The error of `Constraints::gc`, when the constraint at `at` can't be satisfied on its own.
+fn contradiction_at(at: Coords) -> SolveError {
+    SolveError::Contradiction(BTreeSet::from([at]))
+}
+
+/// Give `color` to `coords` in all of `constraints`, or `None` if one of them does not allow it.
+fn assign(constraints: &[Multiverse], coords: &Coords, color: Color) -> Option<Vec<Multiverse>> {
+    constraints
+        .iter()
+        .map(|mv| match mv.scope.contains(coords) {
+            false => Some(mv.clone()),
+            true if mv.allows(coords, color) => Some(mv.learn(coords, color)),
+            true => None,
+        })
+        .collect()
+}
 
-        // Step 5.3 - Look for invariants using the global constraints
+/// Learn the invariants of each constraint in all the others until none is left, the cells
+/// colored that way are added to `coloring`. Returns `None` if the constraints contradict.
+fn propagate(
+    mut constraints: Vec<Multiverse>,
+    coloring: &mut BTreeMap<Coords, Color>,
+) -> Option<Vec<Multiverse>> {
+    loop {
+        constraints.retain(|mv| mv.state() != State::Empty);
+        if constraints.iter().any(|mv| mv.state() == State::Stuck) {
+            return None;
+        }
+        let invariants: BTreeMap<_, _> =
+            constraints.iter().flat_map(|mv| mv.invariants()).collect();
         if invariants.is_empty() {
-            difficulty =
-                Difficulty::Global(constraints.constraints_visible.len().try_into().unwrap());
-            invariants = match constraints.global_invariants(env, defn) {
-                Ok(x) => x,
-                Err(err) => match err.downcast::<env::Timeout>() {
-                    Ok(_) => return Outcome::Timeout,
-                    Err(_) => panic!("compound_invariants failed"),
+            return Some(constraints);
+        }
+        for (coords, color) in invariants {
+            // `None` if two constraints disagree on that color
+            constraints = assign(&constraints, &coords, color)?;
+            coloring.insert(coords, color);
+        }
+    }
+}
+
+/// Depth first search of the colorings that satisfy all of `constraints`, `coloring` being the
+/// cells colored so far. The invariants of each constraint are learned by all the others before
+/// guessing the color of a cell, which prunes the inconsistent branches early.
+/// Each solution is handed to `on_solution`, the search stops as soon as it returns `true`, in
+/// which case `Ok(true)` is returned.
+fn search_solutions(
+    env: &mut Env,
+    constraints: Vec<Multiverse>,
+    mut coloring: BTreeMap<Coords, Color>,
+    on_solution: &mut dyn FnMut(BTreeMap<Coords, Color>) -> bool,
+) -> Result<bool, Timeout> {
+    env.check_timeout()?;
+    let constraints = match propagate(constraints, &mut coloring) {
+        Some(constraints) => constraints,
+        None => return Ok(false),
+    };
+    // The global constraint covers all the cells, they are all colored when nothing is left
+    if constraints.is_empty() {
+        return Ok(on_solution(coloring));
+    }
+    let coords = *constraints
+        .iter()
+        .min_by_key(|mv| mv.scope.len())
+        .and_then(|mv| mv.scope.iter().next())
+        .expect("Unreachable");
+    for color in [Color::Blue, Color::Black] {
+        if let Some(constraints) = assign(&constraints, &coords, color) {
+            let mut coloring = coloring.clone();
+            coloring.insert(coords, color);
+            if search_solutions(env, constraints, coloring, on_solution)? {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Enumerate the colorings of the unknown cells of `defn` that satisfy all of its constraints,
+/// stopping after `limit` of them. A well-formed puzzle has exactly one.
+/// Merging all the constraints at once explodes on actual levels, the colorings are searched
+/// cell by cell instead. `env` bounds the runtime.
+pub fn enumerate_solutions(
+    defn: &Defn,
+    env: &mut Env,
+    limit: usize,
+) -> Result<Vec<BTreeMap<Coords, Color>>, SolveError> {
+    let mut solutions = vec![];
+    if limit > 0 {
+        let constraints = all_constraints(defn)?;
+        search_solutions(env, constraints, BTreeMap::new(), &mut |coloring| {
+            solutions.push(coloring);
+            solutions.len() >= limit
+        })?;
+    }
+    Ok(solutions)
+}
+
+/// Count the colorings of the unknown cells of `defn` that satisfy all of its constraints, the
+/// search stops once `limit` of them are found. `count_solutions(defn, env, 2)` tells apart the
+/// puzzles without solution, the well-formed ones, and the ambiguous ones.
+pub fn count_solutions(defn: &Defn, env: &mut Env, limit: u64) -> Result<u64, SolveError> {
+    let mut count = 0;
+    if limit > 0 {
+        let constraints = all_constraints(defn)?;
+        search_solutions(env, constraints, BTreeMap::new(), &mut |_| {
+            count += 1;
+            count >= limit
+        })?;
+    }
+    Ok(count)
+}
+
+/// Complete the coloring of `defn` by guessing when no deduction is left, for the grids that
+/// `solve` can't finish. The result holds all the colored cells, revealed from the start or not.
+/// `env` bounds the runtime.
+pub fn solve_backtracking(
+    defn: &Defn,
+    env: &mut Env,
+) -> Result<BTreeMap<Coords, Color>, SolveError> {
+    let progress = Progress::of_defn(defn);
+    let mut coloring = enumerate_solutions(defn, env, 1)?
+        .pop()
+        .ok_or(SolveError::NoSolution)?;
+    coloring.extend(progress.blues.iter().map(|coords| (*coords, Color::Blue)));
+    coloring.extend(progress.blacks.iter().map(|coords| (*coords, Color::Black)));
+    Ok(coloring)
+}
+
+/// Tell whether `defn` has a single solution that the deductions of `solve` can't reach, i.e.
+/// whether the player has to guess at some point. `Ok(false)` if `solve` finishes the grid or if
+/// the grid has several solutions. The trial and error tier of `solve` counts as guessing.
+pub fn requires_guessing(defn: &Defn, env: &mut Env) -> Result<bool, SolveError> {
+    let mut deduction_env = env.clone().with_trials(false);
+    match solve(&mut deduction_env, defn, None) {
+        Outcome::Solved(_) => Ok(false),
+        Outcome::Timeout(_) => Err(SolveError::Timeout),
+        Outcome::Explosion(_) => Err(SolveError::Explosion),
+        Outcome::Contradiction { constraints } => Err(SolveError::Contradiction(constraints)),
+        Outcome::Unsolvable => has_unique_solution(defn, env),
+    }
+}
+
+/// Check that `defn` is well-posed, i.e. that its constraints leave a single coloring of the
+/// unknown cells. Fails if the grid is contradictory.
+pub fn has_unique_solution(defn: &Defn, env: &mut Env) -> Result<bool, SolveError> {
+    match count_solutions(defn, env, 2)? {
+        0 => Err(SolveError::NoSolution),
+        1 => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Check a coloring of `defn`, e.g. the one submitted by a player, by counting the blue cells of
+/// each `Zone6`, `Zone18` and `Line` cell and checking their modifiers, then the global blue count.
+/// `assignment` needs the unknown cells, the revealed ones keep their color when missing.
+/// Fails with the constraint cells that are violated, along with the cells that are missing or
+/// that can't take the assigned color (revealed from the start, or bearing the number of the
+/// other color). An empty list means that only the global blue count is violated.
+pub fn check_solution(
+    defn: &Defn,
+    assignment: &BTreeMap<Coords, Color>,
+) -> Result<(), Vec<Coords>> {
+    // The cells of `assignment` that aren't colored cells of the grid
+    let mut violated: BTreeSet<_> = assignment
+        .keys()
+        .filter(|coords| defn.get(coords).and_then(defn::color_of_cell).is_none())
+        .cloned()
+        .collect();
+    let revealed: BTreeSet<_> = defn::revealed(defn).map(|(coords, _)| coords).collect();
+    // The grid as colored by `assignment`, the cells with a wrong color lose their number
+    let mut played = defn.clone();
+    for (coords, cell) in defn {
+        let color = match defn::color_of_cell(cell) {
+            Some(color) => color,
+            None => continue,
+        };
+        let revealed = revealed.contains(coords);
+        let assigned = match assignment.get(coords) {
+            Some(assigned) => *assigned,
+            None if revealed => color,
+            None => {
+                violated.insert(*coords);
+                Color::Black
+            }
+        };
+        if assigned == color {
+            continue;
+        }
+        if revealed || !matches!(cell, Cell::Zone0 { .. }) {
+            violated.insert(*coords);
+        }
+        played.insert(
+            *coords,
+            Cell::Zone0 {
+                revealed,
+                color: assigned,
+            },
+        );
+    }
+    for (coords, _) in defn::constraints(defn) {
+        let count_holds =
+            defn::number_of_cell(&played, &coords) == defn::number_of_cell(defn, &coords);
+        if !count_holds || !constraint::modifier_holds(&played, coords) {
+            violated.insert(coords);
+        }
+    }
+    if violated.is_empty() && defn::cell_counts(&played).blue() == defn::cell_counts(defn).blue() {
+        Ok(())
+    } else {
+        Err(violated.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use defn::DefnBuilder;
+    use generator;
+
+    static FIXTURES: [&str; 4] = [
+        include_str!("../extra/ml_1.txt"),
+        include_str!("../extra/ml_2.txt"),
+        include_str!("../extra/ml_3.txt"),
+        include_str!("../extra/the_trial.txt"),
+    ];
+
+    /// A hidden cell without a number.
+    fn hidden(color: Color) -> Cell {
+        Cell::Zone0 {
+            revealed: false,
+            color,
+        }
+    }
+
+    /// The history of a solve of `defn` that must succeed.
+    fn solved(env: &mut Env, defn: &Defn) -> Vec<Findings> {
+        match solve(env, defn, None) {
+            Outcome::Solved(history) => history,
+            _ => panic!("Unexpected outcome"),
+        }
+    }
+
+    #[test]
+    pub fn test_merge_cache() {
+        // Replay the solver loop and check at each step that the cached merges yield the same
+        // compound invariants as merging from scratch.
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let mut env = Env::new(60);
+            let mut progress = Progress::of_defn(&defn);
+            let mut constraints = Constraints::of_defn(&defn);
+            while !progress.is_solved() {
+                let visible_cells: BTreeSet<_> =
+                    progress.blacks.union(&progress.blues).cloned().collect();
+                constraints.reveal(&visible_cells);
+                constraints.narrow(&visible_cells, &progress);
+                constraints.gc().unwrap();
+                let mut invariants = constraints.trivial_invariants(&mut env, &defn).unwrap();
+                if invariants.is_empty() {
+                    let before = constraints.stats.clone();
+                    let mut uncached = constraints.clone();
+                    uncached.merge_cache.clear();
+                    let expected = uncached.compound_invariants(&mut env, &defn).unwrap();
+                    let actual = constraints.compound_invariants(&mut env, &defn).unwrap();
+                    assert_eq!(expected.0, actual.0);
+                    assert_eq!(expected.1, actual.1);
+                    // Every merge is either computed again or found in the cache
+                    assert_eq!(
+                        constraints.stats.merges - before.merges + constraints.stats.cache_hits
+                            - before.cache_hits,
+                        uncached.stats.merges - before.merges
+                    );
+                    // Only the groups up to the size that gave the invariants are kept
+                    let Difficulty::Local(difficulty) = actual.1 else {
+                        panic!("Unexpected difficulty")
+                    };
+                    assert!(constraints
+                        .merge_cache
+                        .keys()
+                        .all(|kset| kset.len() <= difficulty as usize));
+                    invariants = actual.0;
+                }
+                if invariants.is_empty() {
+                    invariants = constraints.global_invariants(&mut env, &defn).unwrap();
+                }
+                assert!(!invariants.is_empty());
+                progress.update(
+                    invariants
+                        .into_iter()
+                        .map(|(c, (color, _))| (c, color))
+                        .collect(),
+                );
+            }
+            if strdefn == FIXTURES[3] {
+                // The trial needs several compound steps, some merges survive between them
+                assert!(constraints.stats.cache_hits > 0);
+            }
+        }
+    }
+
+    /// The compound tier as it was before the graph got split into connected components: the
+    /// groups grow over the whole graph at once. Returns the invariants and the size of the groups
+    /// that found them.
+    fn whole_graph_invariants(constraints: &Constraints) -> (BTreeMap<Coords, Color>, u32) {
+        let visible: BTreeMap<_, _> = constraints
+            .constraints_visible
+            .iter()
+            .filter(|(k, _)| **k != *UNIQUE_COORDS)
+            .collect();
+        let connections: BTreeMap<_, BTreeSet<_>> = visible
+            .iter()
+            .map(|(k, mv)| {
+                let neighbors = visible
+                    .iter()
+                    .filter(|(k2, mv2)| k != *k2 && !mv.scope.is_disjoint(&mv2.scope))
+                    .map(|(k2, _)| **k2)
+                    .collect();
+                (**k, neighbors)
+            })
+            .collect();
+        let mut groups: BTreeMap<BTreeSet<Coords>, Multiverse> = visible
+            .iter()
+            .map(|(k, mv)| (BTreeSet::from([**k]), (*mv).clone()))
+            .collect();
+        let mut difficulty = 2;
+        loop {
+            for kset_old in groups.keys().cloned().collect::<Vec<_>>() {
+                let mv_old = groups.remove(&kset_old).unwrap();
+                for k_new in kset_old.iter().flat_map(|k| &connections[k]) {
+                    let mut kset_new = kset_old.clone();
+                    if kset_new.insert(*k_new) && !groups.contains_key(&kset_new) {
+                        groups.insert(kset_new, mv_old.merge(visible[k_new]));
+                    }
+                }
+            }
+            let invariants: BTreeMap<_, _> =
+                groups.values().flat_map(|mv| mv.invariants()).collect();
+            if !invariants.is_empty() || groups.is_empty() {
+                return (invariants, difficulty);
+            }
+            difficulty += 1;
+        }
+    }
+
+    #[test]
+    pub fn test_connected_components_search() {
+        // Replay the solver loop and check at each compound step that searching the connected
+        // components one by one finds the same invariants as growing groups over the whole graph
+        let mut compound_steps = 0;
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let mut env = Env::new(60);
+            let mut progress = Progress::of_defn(&defn);
+            let mut constraints = Constraints::of_defn(&defn);
+            while !progress.is_solved() {
+                let visible_cells: BTreeSet<_> =
+                    progress.blacks.union(&progress.blues).cloned().collect();
+                constraints.reveal(&visible_cells);
+                constraints.narrow(&visible_cells, &progress);
+                constraints.gc().unwrap();
+                let mut invariants = constraints.trivial_invariants(&mut env, &defn).unwrap();
+                if invariants.is_empty() {
+                    let (expected, expected_difficulty) = whole_graph_invariants(&constraints);
+                    let (actual, difficulty) =
+                        constraints.compound_invariants(&mut env, &defn).unwrap();
+                    let colors: BTreeMap<_, _> = actual
+                        .iter()
+                        .map(|(coords, (color, _))| (*coords, *color))
+                        .collect();
+                    assert_eq!(colors, expected);
+                    if !expected.is_empty() {
+                        // Without invariants the whole graph loop reports the size it gave up at
+                        assert_eq!(difficulty, Difficulty::Local(expected_difficulty));
+                        compound_steps += 1;
+                    }
+                    invariants = actual;
+                }
+                if invariants.is_empty() {
+                    invariants = constraints.global_invariants(&mut env, &defn).unwrap();
+                }
+                progress.update(
+                    invariants
+                        .into_iter()
+                        .map(|(c, (color, _))| (c, color))
+                        .collect(),
+                );
+            }
+        }
+        assert!(compound_steps > 0);
+    }
+
+    #[test]
+    pub fn test_global_reduction() {
+        // The parallel reduction finds the same invariants as merging one constraint after the
+        // other, at each global step of the solves
+        let mut global_steps = 0;
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let mut session = Session::new(defn.clone()).unwrap();
+            let mut env = Env::new(60);
+            while let Some((moves, difficulty)) = session.deductions(&mut env).unwrap() {
+                if let Difficulty::Global(_) = difficulty {
+                    let constraints = &session.constraints;
+                    let sequential = constraints
+                        .constraints_visible
+                        .values()
+                        .rev()
+                        .fold(Multiverse::empty(), |mv, mv2| mv.merge(mv2))
+                        .invariants();
+                    // Several threads even on a single core, so that the tree is split
+                    let pool = rayon::ThreadPoolBuilder::new()
+                        .num_threads(4)
+                        .build()
+                        .unwrap();
+                    let parallel: BTreeMap<_, _> = pool
+                        .install(|| constraints.global_invariants(&mut env.clone(), &defn))
+                        .unwrap()
+                        .into_iter()
+                        .map(|(coords, (color, _))| (coords, color))
+                        .collect();
+                    assert_eq!(parallel, sequential);
+                    global_steps += 1;
+                }
+                session.apply_all(moves).unwrap();
+            }
+        }
+        assert!(global_steps > 0);
+    }
+
+    #[test]
+    pub fn test_global_thread_count() {
+        // Without the compound tier the global one merges the whole grid, each subtree is bounded
+        // by the global blue count and the outcome is the same whatever the number of threads
+        for strdefn in &FIXTURES[..3] {
+            let defn = defn::of_string(strdefn).unwrap();
+            let outcomes: Vec<_> = [1, 2, 4]
+                .iter()
+                .map(|threads| {
+                    let pool = rayon::ThreadPoolBuilder::new()
+                        .num_threads(*threads)
+                        .build()
+                        .unwrap();
+                    let mut env = Env::new(60).with_config(SolverConfig {
+                        enable_compound: false,
+                        ..Default::default()
+                    });
+                    let outcome = pool.install(|| solve(&mut env, &defn, None));
+                    assert!(matches!(outcome, Outcome::Solved(_)));
+                    serde_json::to_string(&outcome).unwrap()
+                })
+                .collect();
+            assert_eq!(outcomes[0], outcomes[1]);
+            assert_eq!(outcomes[0], outcomes[2]);
+        }
+    }
+
+    #[test]
+    pub fn test_enumerate_solutions() {
+        for strdefn in &FIXTURES[..3] {
+            let defn = defn::of_string(strdefn).unwrap();
+            let solutions = enumerate_solutions(&defn, &mut Env::new(60), 10).unwrap();
+            assert_eq!(solutions.len(), 1);
+            let progress = Progress::of_defn(&defn);
+            assert_eq!(
+                solutions[0].keys().cloned().collect::<BTreeSet<_>>(),
+                progress.unknowns
+            );
+            for (coords, color) in &solutions[0] {
+                assert_eq!(Some(*color), defn::color_of_cell(&defn[coords]));
+            }
+            assert!(enumerate_solutions(&defn, &mut Env::new(60), 0)
+                .unwrap()
+                .is_empty());
+        }
+
+        // Two unknown cells, one of them is blue, nothing tells which one
+        let c = |q| Coords::new(q, 0, -q);
+        let defn = Defn::from([(c(0), hidden(Color::Blue)), (c(2), hidden(Color::Black))]);
+        let solutions = enumerate_solutions(&defn, &mut Env::new(60), 10).unwrap();
+        assert_eq!(solutions.len(), 2);
+        assert_ne!(solutions[0], solutions[1]);
+        assert_eq!(
+            enumerate_solutions(&defn, &mut Env::new(60), 1)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    pub fn test_count_solutions() {
+        for strdefn in &FIXTURES[..3] {
+            let defn = defn::of_string(strdefn).unwrap();
+            assert_eq!(count_solutions(&defn, &mut Env::new(60), 2).unwrap(), 1);
+            assert_eq!(count_solutions(&defn, &mut Env::new(60), 0).unwrap(), 0);
+        }
+
+        // Three unknown cells, two of them are blue, nothing tells which ones
+        let c = |q| Coords::new(q, 0, -q);
+        let defn = Defn::from([
+            (c(0), hidden(Color::Blue)),
+            (c(2), hidden(Color::Blue)),
+            (c(4), hidden(Color::Black)),
+        ]);
+        assert_eq!(count_solutions(&defn, &mut Env::new(60), 10).unwrap(), 3);
+        assert_eq!(count_solutions(&defn, &mut Env::new(60), 2).unwrap(), 2);
+    }
+
+    #[test]
+    pub fn test_has_unique_solution() {
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        assert!(has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+
+        // 4 blues amongst 5 cells, the column hints are needed to know which one is black
+        let c = |q, r| Coords::new(q, r, -q - r);
+        let mut defn = Defn::from([
+            (c(0, 1), hidden(Color::Blue)),
+            (c(0, 2), hidden(Color::Blue)),
+            (c(2, 0), hidden(Color::Blue)),
+            (c(2, 1), hidden(Color::Black)),
+            (c(2, 2), hidden(Color::Blue)),
+        ]);
+        assert!(!has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+        let line = |m| Cell::Line {
+            m,
+            o: defn::Orientation::Bottom,
+        };
+        defn.insert(c(0, 0), line(defn::Modifier::Anywhere));
+        assert!(!has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+        defn.insert(c(2, -1), line(defn::Modifier::Separated));
+        assert!(has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+
+        // The numbers of the hidden cells are out of sight until their cells are uncovered
+        let any = generator::DifficultyRange { min: 1., max: 10. };
+        let mut defn = generator::generate(42, 3, any, 100, &mut Env::new(60)).unwrap();
+        for (coords, cell) in defn.clone() {
+            if defn::color_of_cell(&cell).is_some() {
+                generator::set_revealed(&mut defn, &coords, false);
+            }
+        }
+        assert!(matches!(
+            solve(&mut Env::new(60), &defn, None),
+            Outcome::Unsolvable
+        ));
+        assert!(!has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+    }
+
+    #[test]
+    pub fn test_check_solution() {
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let truth: BTreeMap<_, _> = defn
+            .iter()
+            .filter_map(|(coords, cell)| Some((*coords, defn::color_of_cell(cell)?)))
+            .collect();
+        assert_eq!(check_solution(&defn, &truth), Ok(()));
+        let solution = enumerate_solutions(&defn, &mut Env::new(60), 1).unwrap();
+        assert_eq!(check_solution(&defn, &solution[0]), Ok(()));
+
+        // Flipping an unknown cell violates exactly the constraints that count it
+        let constraints = Constraints::of_defn(&defn);
+        let counting = |coords: &Coords| -> BTreeSet<Coords> {
+            let all = constraints
+                .constraints_hidden
+                .iter()
+                .chain(constraints.constraints_visible.iter());
+            all.filter(|(k, mv)| **k != *UNIQUE_COORDS && mv.scope.contains(coords))
+                .map(|(k, _)| *k)
+                .collect()
+        };
+        let progress = Progress::of_defn(&defn);
+        let flipped = *progress
+            .unknowns
+            .iter()
+            .find(|coords| !counting(coords).is_empty())
+            .unwrap();
+        let mut assignment = truth.clone();
+        let color = match truth[&flipped] {
+            Color::Blue => Color::Black,
+            Color::Black => Color::Blue,
+        };
+        assignment.insert(flipped, color);
+        let violated = check_solution(&defn, &assignment).unwrap_err();
+        assert_eq!(
+            violated.into_iter().collect::<BTreeSet<_>>(),
+            counting(&flipped)
+        );
+
+        // The missing cells and the revealed ones with another color are reported
+        let mut assignment = truth.clone();
+        assignment.remove(&flipped);
+        assert!(check_solution(&defn, &assignment)
+            .unwrap_err()
+            .contains(&flipped));
+        let revealed = *progress.blacks.iter().next().unwrap();
+        let mut assignment = truth.clone();
+        assignment.insert(revealed, Color::Blue);
+        assert!(check_solution(&defn, &assignment)
+            .unwrap_err()
+            .contains(&revealed));
+
+        // Without any number, only the global blue count can be violated
+        let (a, b) = (Coords::new(0, 0, 0), Coords::new(0, 1, -1));
+        let defn = DefnBuilder::new()
+            .set(a, hidden(Color::Blue))
+            .set(b, hidden(Color::Black))
+            .build()
+            .unwrap();
+        let swapped = BTreeMap::from([(a, Color::Black), (b, Color::Blue)]);
+        assert_eq!(check_solution(&defn, &swapped), Ok(()));
+        let all_blue = BTreeMap::from([(a, Color::Blue), (b, Color::Blue)]);
+        assert_eq!(check_solution(&defn, &all_blue), Err(vec![]));
+    }
+
+    #[test]
+    pub fn test_session() {
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let mut session = Session::new(defn.clone()).unwrap();
+            let mut env = Env::new(60);
+            let mut steps = 0;
+            while let Some((moves, _)) = session.deductions(&mut env).unwrap() {
+                for (coords, color) in moves {
+                    let other = match color {
+                        Color::Blue => Color::Black,
+                        Color::Black => Color::Blue,
+                    };
+                    assert_eq!(
+                        session.apply(coords, other),
+                        Err(MoveError::WrongColor(coords))
+                    );
+                    session.apply(coords, color).unwrap();
+                    assert_eq!(
+                        session.apply(coords, color),
+                        Err(MoveError::NotUnknown(coords))
+                    );
+                }
+                steps += 1;
+            }
+            assert!(session.is_solved());
+            assert_eq!(steps, solved(&mut Env::new(60), &defn).len());
+        }
+    }
+
+    #[test]
+    pub fn test_session_undo() {
+        let defn = defn::of_string(FIXTURES[3]).unwrap();
+        let mut session = Session::new(defn).unwrap();
+        let mut env = Env::new(60);
+        let start = session.progress().clone();
+        let first = session.deductions(&mut env).unwrap();
+        assert_eq!(session.undo_last(), None);
+
+        let mut states = vec![];
+        for _ in 0..3 {
+            let (moves, _) = session.deductions(&mut env).unwrap().unwrap();
+            states.push((session.progress().clone(), moves.clone()));
+            session.apply_all(moves).unwrap();
+        }
+        while let Some((progress, moves)) = states.pop() {
+            assert_eq!(session.undo_last(), Some(moves.clone()));
+            assert_eq!(session.progress(), &progress);
+            assert!(session.constraints.is_up_to_date(&progress));
+            // The same moves are found again
+            assert_eq!(session.deductions(&mut env).unwrap().unwrap().0, moves);
+        }
+        assert_eq!(session.undo_last(), None);
+        assert_eq!(session.progress(), &start);
+        assert_eq!(session.deductions(&mut env).unwrap(), first);
+
+        // A rejected batch changes nothing
+        let (mut moves, _) = first.unwrap();
+        let coords = *moves.keys().next().unwrap();
+        let known = *start.blacks().iter().next().unwrap();
+        moves.insert(known, Color::Black);
+        assert_eq!(session.apply_all(moves), Err(MoveError::NotUnknown(known)));
+        assert!(session.progress().unknowns.contains(&coords));
+        assert_eq!(session.undo_last(), None);
+    }
+
+    #[test]
+    pub fn test_requires_guessing() {
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        assert!(!requires_guessing(&defn, &mut Env::new(60)).unwrap());
+
+        // 1 blue amongst 3 cells in a row, nothing tells which one until a black cell shows its
+        // number. Each of the outer cells rules out the other one once uncovered.
+        let c = |q| Coords::new(q, 0, -q);
+        let zone6 = Cell::Zone6 {
+            revealed: false,
+            m: defn::Modifier::Anywhere,
+        };
+        let mut defn = Defn::from([(c(0), zone6), (c(1), hidden(Color::Blue)), (c(2), zone6)]);
+        assert!(matches!(
+            solve(&mut Env::new(60), &defn, None),
+            Outcome::Unsolvable
+        ));
+        assert!(has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+        assert!(requires_guessing(&defn, &mut Env::new(60)).unwrap());
+
+        // Without the number of one of them, that one may be the blue cell
+        defn.insert(c(2), hidden(Color::Black));
+        assert!(!has_unique_solution(&defn, &mut Env::new(60)).unwrap());
+        assert!(!requires_guessing(&defn, &mut Env::new(60)).unwrap());
+    }
+
+    #[test]
+    pub fn test_solve_backtracking() {
+        for strdefn in &FIXTURES[..3] {
+            let defn = defn::of_string(strdefn).unwrap();
+            let coloring = solve_backtracking(&defn, &mut Env::new(60)).unwrap();
+            let expected: BTreeMap<_, _> = defn
+                .iter()
+                .filter_map(|(coords, cell)| Some((*coords, defn::color_of_cell(cell)?)))
+                .collect();
+            assert_eq!(coloring, expected);
+        }
+        let defn = defn::of_string(FIXTURES[3]).unwrap();
+        assert!(matches!(
+            solve_backtracking(&defn, &mut Env::new(0)),
+            Err(SolveError::Timeout)
+        ));
+    }
+
+    #[test]
+    pub fn test_next_hint() {
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let findings = solved(&mut Env::new(60), &defn);
+            let (coords, color, difficulty) =
+                next_hint(&defn, &BTreeMap::new(), &mut Env::new(60)).unwrap();
+            assert!(findings[0].cells().contains(&coords));
+            assert_eq!(difficulty, findings[0].difficulty);
+            assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+
+            // Following the hints one by one solves the grid
+            let mut progress_cells = BTreeMap::new();
+            while let Some((coords, color, _)) =
+                next_hint(&defn, &progress_cells, &mut Env::new(60))
+            {
+                assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+                assert!(progress_cells.insert(coords, color).is_none());
+            }
+            assert_eq!(
+                progress_cells.len(),
+                Progress::of_defn(&defn).unknowns.len()
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_next_hint_wrong_move() {
+        // A revealed 1 next to a blue and a black cell, the player marks the blue one black
+        let center = Coords::new(0, 0, 0);
+        let [top, top_right, ..] = center.neighbors6();
+        let defn = DefnBuilder::new()
+            .set(
+                center,
+                Cell::Zone6 {
+                    revealed: true,
+                    m: defn::Modifier::Anywhere,
                 },
+            )
+            .set(top, hidden(Color::Blue))
+            .set(top_right, hidden(Color::Black))
+            .build()
+            .unwrap();
+        let mut env = Env::new(60);
+        // The two cells can't be told apart until one is played
+        assert_eq!(next_hint(&defn, &BTreeMap::new(), &mut env), None);
+        let wrong = BTreeMap::from([(top, Color::Black)]);
+        assert_eq!(next_hint(&defn, &wrong, &mut env), None);
+        // Neither a revealed nor an off-board cell can be played
+        let revealed = BTreeMap::from([(center, Color::Black)]);
+        assert_eq!(next_hint(&defn, &revealed, &mut env), None);
+        let off_board = BTreeMap::from([(Coords::new(5, 0, -5), Color::Black)]);
+        assert_eq!(next_hint(&defn, &off_board, &mut env), None);
+        let right = BTreeMap::from([(top_right, Color::Black)]);
+        assert_eq!(
+            next_hint(&defn, &right, &mut env),
+            Some((top, Color::Blue, Difficulty::Local(1)))
+        );
+    }
+
+    #[test]
+    pub fn test_reasons() {
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let findings = solved(&mut Env::new(60), &defn);
+            for f in &findings {
+                assert_eq!(
+                    &f.reasons.keys().cloned().collect::<BTreeSet<_>>(),
+                    f.cells()
+                );
+                for reasons in f.reasons.values() {
+                    assert!(!reasons.contains(&*UNIQUE_COORDS));
+                    assert!(reasons.iter().all(|k| defn.contains_key(k)));
+                    if let Difficulty::Local(d) = f.difficulty {
+                        // One constraint per level of difficulty
+                        assert_eq!(reasons.len(), d as usize);
+                    }
+                }
+            }
+            let json = serde_json::to_string(&findings).unwrap();
+            let findings2: Vec<Findings> = serde_json::from_str(&json).unwrap();
+            for (f, f2) in findings.iter().zip(&findings2) {
+                assert_eq!(f.reasons, f2.reasons);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_duplicate_constraints() {
+        // Two lines stacked on the same column see the same cells
+        let at = |i| Coords::new(0, i, -i);
+        let line = Cell::Line {
+            o: defn::Orientation::Bottom,
+            m: defn::Modifier::Anywhere,
+        };
+        let defn = DefnBuilder::new()
+            .set(at(-1), line)
+            .set(at(0), line)
+            .set(at(1), hidden(Color::Blue))
+            .set(at(2), hidden(Color::Blue))
+            .build()
+            .unwrap();
+        let constraints = Constraints::of_defn(&defn);
+        assert_eq!(
+            constraints.constraints_visible.keys().collect::<Vec<_>>(),
+            vec![&at(-1), &*UNIQUE_COORDS]
+        );
+        assert_eq!(
+            constraints.aliases,
+            BTreeMap::from([(at(-1), BTreeSet::from([at(0)]))])
+        );
+        let findings = solved(&mut Env::new(60), &defn);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].difficulty, Difficulty::Local(1));
+        for reasons in findings[0].reasons.values() {
+            assert_eq!(reasons, &BTreeSet::from([at(-1), at(0)]));
+        }
+    }
+
+    #[test]
+    pub fn test_isolated_reasons() {
+        // No number sees these cells, only the global blue count decides them
+        let defn = DefnBuilder::new()
+            .set(Coords::new(0, 0, 0), hidden(Color::Blue))
+            .set(Coords::new(4, 0, -4), hidden(Color::Blue))
+            .set(Coords::new(-4, 4, 0), hidden(Color::Blue))
+            .build()
+            .unwrap();
+        let findings = solved(&mut Env::new(60), &defn);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].difficulty, Difficulty::Local(1));
+        assert_eq!(findings[0].reasons.len(), 3);
+        for reasons in findings[0].reasons.values() {
+            assert!(reasons.is_empty());
+        }
+        let text = Outcome::Solved(findings).walkthrough(&defn);
+        assert!(text.contains("the remaining blue count tells that"));
+        assert!(!text.contains(&format!("{}", *UNIQUE_COORDS)));
+    }
+
+    #[test]
+    pub fn test_solve_stats() {
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let (outcome, stats) = solve_with_stats(&mut Env::new(60), &defn, None);
+            let history = match outcome {
+                Outcome::Solved(history) => history,
+                _ => panic!("Unexpected outcome"),
             };
-            if invariants.is_empty() {
-                return Outcome::Unsolvable;
+            // One iteration per step, plus the one noticing that the grid is solved
+            assert_eq!(stats.iterations as usize, history.len() + 1);
+            let compound = history
+                .iter()
+                .any(|f| matches!(f.difficulty, Difficulty::Local(2..)));
+            if compound {
+                assert!(stats.merges > 0);
+                assert!(stats.peak_groups > 0);
             }
+            let global = history
+                .iter()
+                .any(|f| matches!(f.difficulty, Difficulty::Global(_)));
+            assert_eq!(stats.global_used, global);
         }
-        history.push(Findings {
-            difficulty,
-            cells: invariants.keys().cloned().collect(),
+    }
+
+    #[test]
+    pub fn test_progress_events() {
+        let defn = defn::of_string(FIXTURES[1]).unwrap();
+        let mut events = vec![];
+        let mut on_progress = |event: &SolveProgressEvent| events.push(event.clone());
+        let (outcome, stats) = solve_with_stats(&mut Env::new(60), &defn, Some(&mut on_progress));
+        let history = match outcome {
+            Outcome::Solved(history) => history,
+            _ => panic!("Unexpected outcome"),
+        };
+        assert_eq!(events.len(), stats.iterations as usize);
+        assert_eq!(events[0].difficulty, None);
+        assert_eq!(events.last().unwrap().unknown, 0);
+        for (i, event) in events.iter().enumerate() {
+            assert_eq!(event.iteration as usize, i + 1);
+            assert_eq!(
+                event.known + event.unknown,
+                events[0].known + events[0].unknown
+            );
+            if i > 0 {
+                assert_eq!(event.difficulty, Some(history[i - 1].difficulty));
+                assert!(event.unknown < events[i - 1].unknown);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_resume() {
+        let defn = defn::of_string(FIXTURES[1]).unwrap();
+        let mut checkpoints = vec![];
+        let mut on_progress = |event: &SolveProgressEvent| {
+            if let Some(progress) = &event.checkpoint {
+                checkpoints.push((event.iteration, serde_json::to_string(progress).unwrap()));
+            }
+        };
+        let mut env = Env::new(60).with_config(SolverConfig {
+            checkpoint_every: Some(3),
+            ..SolverConfig::default()
         });
+        let history = match solve(&mut env, &defn, Some(&mut on_progress)) {
+            Outcome::Solved(history) => history,
+            _ => panic!("Unexpected outcome"),
+        };
+        assert!(!checkpoints.is_empty());
+        for (iteration, json) in checkpoints {
+            assert_eq!(iteration % 3, 0);
+            let progress: Progress = serde_json::from_str(&json).unwrap();
+            match resume(&mut Env::new(60), &defn, &progress, None).unwrap() {
+                Outcome::Solved(history2) => {
+                    // The steps before the checkpoint were made by the first solve
+                    let skipped = iteration as usize - 1;
+                    assert_eq!(history2.len(), history.len() - skipped);
+                    for (f, f2) in history[skipped..].iter().zip(&history2) {
+                        assert_eq!(f.cells(), f2.cells());
+                        assert_eq!(f.difficulty, f2.difficulty);
+                    }
+                }
+                _ => panic!("Unexpected outcome"),
+            }
+        }
+    }
 
-        // Step 6 - Reflect findings in progress
-        progress.update(invariants);
+    #[test]
+    pub fn test_resume_mismatch() {
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let mut progress = Progress::of_defn(&defn);
+        assert!(progress.mismatches(&defn).is_empty());
+
+        // A checkpoint of another grid
+        let other = defn::of_string(FIXTURES[1]).unwrap();
+        assert!(matches!(
+            resume(&mut Env::new(60), &other, &progress, None),
+            Err(SolveError::Checkpoint(_))
+        ));
+
+        // A cell with the wrong color
+        let coords = *progress.unknowns.iter().next().unwrap();
+        let color = match defn::color_of_cell(&defn[&coords]).unwrap() {
+            Color::Blue => Color::Black,
+            Color::Black => Color::Blue,
+        };
+        progress.update(BTreeMap::from([(coords, color)]));
+        match resume(&mut Env::new(60), &defn, &progress, None) {
+            Err(SolveError::Checkpoint(cells)) => assert_eq!(cells, BTreeSet::from([coords])),
+            _ => panic!("Unexpected result"),
+        }
+
+        // A cell that is neither known nor unknown
+        let mut progress = Progress::of_defn(&defn);
+        progress.unknowns.remove(&coords);
+        match resume(&mut Env::new(60), &defn, &progress, None) {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                format!("The checkpoint doesn't match the grid at {}", coords)
+            ),
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    pub fn test_total_budget() {
+        // The third iteration starts once the total budget is spent, far below the per-step budget
+        let defn = defn::of_string(FIXTURES[3]).unwrap();
+        let budget = Duration::from_secs(1);
+        let mut env = Env::new(60).with_total_budget(budget);
+        let mut on_progress = |event: &SolveProgressEvent| {
+            if event.iteration == 3 {
+                std::thread::sleep(budget);
+            }
+        };
+        let (outcome, stats) = solve_with_stats(&mut env, &defn, Some(&mut on_progress));
+        match outcome {
+            Outcome::Timeout(history) => assert!(!history.is_empty()),
+            _ => panic!("Unexpected outcome"),
+        }
+        assert_eq!(stats.iterations, 3);
+
+        let mut env = Env::new(60).with_total_budget(Duration::from_secs(60));
+        let mut on_progress =
+            |_: &SolveProgressEvent| std::thread::sleep(Duration::from_millis(20));
+        let outcome = solve(&mut env, &defn, Some(&mut on_progress));
+        assert!(matches!(outcome, Outcome::Solved(_)));
+    }
+
+    #[test]
+    pub fn test_trivial_timeout() {
+        // The first step of the fixture is trivial, it still respects the budget
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let findings = solved(&mut Env::new(60), &defn);
+        assert_eq!(findings[0].difficulty, Difficulty::Local(1));
+        let mut env = Env::new(60);
+        env.cancel();
+        let constraints = Constraints::of_defn(&defn);
+        assert!(constraints.trivial_invariants(&mut env, &defn).is_err());
+        match solve(&mut env, &defn, None) {
+            Outcome::Timeout(history) => assert!(history.is_empty()),
+            _ => panic!("Unexpected outcome"),
+        }
+    }
+
+    #[test]
+    pub fn test_trials() {
+        let cells = |history: Vec<Findings>| {
+            history
+                .iter()
+                .flat_map(|f| f.cells().iter().cloned())
+                .collect::<BTreeSet<_>>()
+        };
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let without = solved(&mut Env::new(60), &defn);
+            let with = solved(&mut Env::new(60).with_trials(true), &defn);
+            assert_eq!(cells(with), cells(without));
+        }
+
+        // The global blue count solves that grid, trial and error is never needed
+        let defn = defn::of_string(include_str!("../extra/ihoc.txt")).unwrap();
+        let json = |env: &mut Env| serde_json::to_string(&solve(env, &defn, None)).unwrap();
+        assert_eq!(
+            json(&mut Env::new(60).with_trials(true)),
+            json(&mut Env::new(60))
+        );
+
+        // Trial and error takes over when the global tier is disabled or explodes
+        let has_trials = |env: &mut Env| match solve_with_stats(env, &defn, None) {
+            (Outcome::Solved(history), stats) => {
+                assert!(history.iter().any(|f| f.difficulty == Difficulty::Trial(1)));
+                stats.global_used
+            }
+            _ => panic!("Unexpected outcome"),
+        };
+        let mut env = Env::new(60).with_config(SolverConfig {
+            enable_global: false,
+            enable_trial: true,
+            ..Default::default()
+        });
+        assert!(!has_trials(&mut env));
+        // Without compound deductions the global blue count of that grid explodes, see
+        // `test_solver_config`
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let mut config = SolverConfig {
+            enable_compound: false,
+            max_layouts: Some(1),
+            ..Default::default()
+        };
+        let outcome = solve(&mut Env::new(60).with_config(config.clone()), &defn, None);
+        assert!(matches!(outcome, Outcome::Explosion(_)));
+        config.enable_trial = true;
+        match solve(&mut Env::new(60).with_config(config), &defn, None) {
+            Outcome::Solved(history) | Outcome::Explosion(history) => {
+                assert!(history.iter().any(|f| f.difficulty == Difficulty::Trial(1)))
+            }
+            _ => panic!("Unexpected outcome"),
+        }
+    }
+
+    #[test]
+    pub fn test_deterministic() {
+        for strdefn in &FIXTURES[..3] {
+            let defn = defn::of_string(strdefn).unwrap();
+            let json = || {
+                let outcome = solve(&mut Env::new(60), &defn, None);
+                serde_json::to_string(&outcome).unwrap()
+            };
+            assert_eq!(json(), json());
+        }
+    }
+
+    #[test]
+    pub fn test_max_group_size() {
+        let max_local =
+            |env: &mut Env, defn: &Defn| difficulty_of_findings_vec(&solved(env, defn)).0;
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        assert_eq!(max_local(&mut Env::new(60), &defn), Some(2));
+        let mut env = Env::new(60).with_max_group_size(Some(2));
+        assert_eq!(max_local(&mut env, &defn), Some(2));
+
+        // Without groups, the global blue count takes over
+        let mut env = Env::new(60).with_max_group_size(Some(1));
+        assert_eq!(max_local(&mut env, &defn), Some(1));
+        let (_, stats) = solve_with_stats(&mut env, &defn, None);
+        assert!(stats.global_used);
+    }
+
+    /// Keeps the messages logged by each thread, so that the tests running in parallel don't see
+    /// each other's messages.
+    struct CaptureLogger;
+
+    thread_local! {
+        static CAPTURED: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(vec![]) };
+    }
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED.with(|captured| captured.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    pub fn test_quiet() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let captured = |verbose| {
+            CAPTURED.with(|captured| captured.borrow_mut().clear());
+            let mut env = Env::new(60).with_config(SolverConfig {
+                verbose,
+                ..SolverConfig::default()
+            });
+            assert!(matches!(solve(&mut env, &defn, None), Outcome::Solved(_)));
+            CAPTURED.with(|captured| captured.borrow().len())
+        };
+        assert_eq!(captured(false), 0);
+        assert!(captured(true) > 0);
+    }
+
+    #[test]
+    pub fn test_solver_config() {
+        let config = |config: SolverConfig| Env::new(60).with_config(config);
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+
+        // Without compound deductions, the global blue count takes over
+        let mut env = config(SolverConfig {
+            enable_compound: false,
+            ..Default::default()
+        });
+        let (outcome, stats) = solve_with_stats(&mut env, &defn, None);
+        assert!(matches!(outcome, Outcome::Solved(_)));
+        assert!(stats.global_used);
+
+        // Trivial deductions alone don't make it
+        let mut env = config(SolverConfig {
+            enable_compound: false,
+            enable_global: false,
+            ..Default::default()
+        });
+        assert!(matches!(solve(&mut env, &defn, None), Outcome::Unsolvable));
+
+        // That grid never needs the global blue count
+        let mut env = config(SolverConfig {
+            enable_global: false,
+            ..Default::default()
+        });
+        assert!(matches!(solve(&mut env, &defn, None), Outcome::Solved(_)));
+
+        // The global blue count alone holds more than one layout
+        let mut env = config(SolverConfig {
+            enable_compound: false,
+            max_layouts: Some(1),
+            ..Default::default()
+        });
+        assert!(matches!(
+            solve(&mut env, &defn, None),
+            Outcome::Explosion(_)
+        ));
+    }
+
+    #[test]
+    pub fn test_walkthrough() {
+        for strdefn in FIXTURES {
+            let defn = defn::of_string(strdefn).unwrap();
+            let outcome = solve(&mut Env::new(60), &defn, None);
+            let walkthrough = outcome.walkthrough(&defn);
+            let history = match &outcome {
+                Outcome::Solved(history) => history,
+                _ => panic!("Unexpected outcome"),
+            };
+            let lines: Vec<_> = walkthrough.lines().collect();
+            assert_eq!(lines.len(), history.len());
+            for (i, (line, findings)) in lines.iter().zip(history).enumerate() {
+                assert!(line.starts_with(&format!("Step {} (", i + 1)));
+                for coords in findings.cells() {
+                    assert!(line.contains(&coords.to_string()));
+                }
+            }
+        }
+
+        // The steps that use the global blue count have no constraint to point at
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let blacks = Progress::of_defn(&defn).unknowns;
+        let blacks: Vec<_> = blacks
+            .iter()
+            .filter(|c| defn::color_of_cell(&defn[c]) == Some(Color::Black))
+            .collect();
+        let outcome = Outcome::Solved(vec![Findings::new_global(*blacks[0])]);
+        assert_eq!(
+            outcome.walkthrough(&defn),
+            format!(
+                "Step 1 (global difficulty 1): the remaining blue count tells that {} is black.",
+                blacks[0]
+            )
+        );
+        assert_eq!(
+            Outcome::Unsolvable.walkthrough(&defn),
+            "Requires additional rules."
+        );
+    }
+
+    #[test]
+    pub fn test_step_timings() {
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let history = solved(&mut Env::new(60), &defn);
+        assert!(history.iter().all(|f| f.elapsed().is_none()));
+        assert!(!serde_json::to_string(&history).unwrap().contains("elapsed"));
+
+        let mut env = Env::new(60).with_step_timings(true);
+        let history = solved(&mut env, &defn);
+        assert!(history.iter().all(|f| f.elapsed().is_some()));
+        let json = serde_json::to_string(&history).unwrap();
+        let parsed: Vec<Findings> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[0].elapsed(), history[0].elapsed());
+    }
+
+    #[test]
+    pub fn test_contradiction() {
+        let defn = defn::of_string(FIXTURES[1]).unwrap();
+        let unknowns = Progress::of_defn(&defn).unknowns;
+        // Every unknown cell with the wrong color
+        let wrong_cells: BTreeMap<_, _> = unknowns
+            .iter()
+            .map(|coords| match defn::color_of_cell(&defn[coords]) {
+                Some(Color::Blue) => (*coords, Color::Black),
+                _ => (*coords, Color::Blue),
+            })
+            .collect();
+        let mut progress = Progress::of_defn(&defn);
+        progress.update(wrong_cells.clone());
+        let mut constraints = Constraints::of_defn(&defn);
+        let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+        constraints.reveal(&visible_cells);
+        constraints.narrow(&visible_cells, &progress);
+        let at = constraints.gc().unwrap_err();
+        assert_eq!(constraints.constraints_visible[&at].state(), State::Stuck);
+        assert!(next_hint(&defn, &wrong_cells, &mut Env::new(60)).is_none());
+        let outcome = Outcome::Contradiction {
+            constraints: BTreeSet::from([at]),
+        };
+        assert!(outcome
+            .to_string()
+            .starts_with("Contradiction at constraint ("));
+    }
+
+    #[test]
+    pub fn test_conflicting_constraints() {
+        use multiverse::Layout;
+        let (a, b, c) = (
+            Coords::new(0, 0, 0),
+            Coords::new(0, 1, -1),
+            Coords::new(0, 2, -2),
+        );
+        let defn = DefnBuilder::new()
+            .set(a, hidden(Color::Black))
+            .set(b, hidden(Color::Blue))
+            .set(c, hidden(Color::Blue))
+            .build()
+            .unwrap();
+        let count = |scope: BTreeSet<Coords>, blues| {
+            let layout = Layout::new(BTreeMap::from([(scope.clone(), blues)]));
+            Multiverse::new(scope, vec![layout])
+        };
+        // Each of them agrees with the global blue count, not with the other one
+        let (k0, k1) = (Coords::new(5, 0, -5), Coords::new(6, 0, -6));
+        let mut constraints = Constraints::of_defn(&defn);
+        constraints
+            .constraints_visible
+            .insert(k0, count(BTreeSet::from([a, b]), 2));
+        constraints
+            .constraints_visible
+            .insert(k1, count(BTreeSet::from([a]), 0));
+        let expected = BTreeSet::from([k0, k1]);
+
+        let mut env = Env::new(60);
+        match constraints.compound_invariants(&mut env, &defn) {
+            Err(SolveError::Contradiction(constraints)) => assert_eq!(constraints, expected),
+            _ => panic!("Unexpected result"),
+        }
+        match constraints.global_invariants(&mut env, &defn) {
+            Err(SolveError::Contradiction(constraints)) => assert_eq!(constraints, expected),
+            _ => panic!("Unexpected result"),
+        }
+        let outcome = Outcome::Contradiction {
+            constraints: expected,
+        };
+        assert_eq!(
+            outcome.to_string(),
+            format!("Contradiction between constraints {}, {}", k0, k1)
+        );
+    }
+
+    #[test]
+    pub fn test_unsatisfiable_modifiers() {
+        // A `-1-` line over 1 blue and 2 blacks
+        let c = |q, r| Coords::new(q, r, -q - r);
+        let mut defn = Defn::from([
+            (
+                c(0, 0),
+                Cell::Line {
+                    m: defn::Modifier::Separated,
+                    o: defn::Orientation::Bottom,
+                },
+            ),
+            (c(0, 1), hidden(Color::Blue)),
+            (c(0, 2), hidden(Color::Black)),
+            (c(0, 3), hidden(Color::Black)),
+        ]);
+        let expected = BTreeSet::from([c(0, 0)]);
+        match solve(&mut Env::new(60), &defn, None) {
+            Outcome::Contradiction { constraints } => assert_eq!(constraints, expected),
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        }
+        assert!(matches!(
+            has_unique_solution(&defn, &mut Env::new(60)),
+            Err(SolveError::Contradiction(_))
+        ));
+
+        // A revealed `-1-` zone, then a `{1}` one that always holds
+        let zone6 = |m| Cell::Zone6 { revealed: true, m };
+        defn.insert(c(0, 0), zone6(defn::Modifier::Separated));
+        match solve(&mut Env::new(60), &defn, None) {
+            Outcome::Contradiction { constraints } => assert_eq!(constraints, expected),
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        }
+        defn.insert(c(0, 0), zone6(defn::Modifier::Together));
+        assert!(matches!(
+            solve(&mut Env::new(60), &defn, None),
+            Outcome::Solved(_)
+        ));
+    }
+
+    /// A revealed `{2}` whose two blue neighbors are on opposite sides, `DefnBuilder` would reject
+    /// it. Its center is at `(16, 0, -16)`, the top neighbor is hidden and the bottom one revealed.
+    fn wrong_modifier() -> Defn {
+        let center = Coords::new(16, 0, -16);
+        let n = center.neighbors6();
+        let mut defn = Defn::from([(
+            center,
+            Cell::Zone6 {
+                revealed: true,
+                m: defn::Modifier::Together,
+            },
+        )]);
+        for coords in n {
+            defn.insert(coords, hidden(Color::Black));
+        }
+        defn.insert(n[0], hidden(Color::Blue));
+        defn.insert(
+            n[3],
+            Cell::Zone0 {
+                revealed: true,
+                color: Color::Blue,
+            },
+        );
+        defn
+    }
+
+    #[test]
+    pub fn test_wrong_modifier() {
+        let defn = wrong_modifier();
+        let center = Coords::new(16, 0, -16);
+        assert!(!constraint::modifier_holds(&defn, center));
+        let expected = BTreeSet::from([center]);
+        match solve(&mut Env::new(60), &defn, None) {
+            Outcome::Contradiction { constraints } => assert_eq!(constraints, expected),
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        }
+
+        // Each tier finds that the blue neighbor is black
+        let progress = Progress::of_defn(&defn);
+        let mut constraints = Constraints::of_defn(&defn);
+        let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+        constraints.reveal(&visible_cells);
+        constraints.narrow(&visible_cells, &progress);
+        constraints.gc().unwrap();
+        let mut env = Env::new(60);
+        let results = [
+            constraints.trivial_invariants(&mut env, &defn),
+            constraints.global_invariants(&mut env, &defn),
+            constraints.trial_invariants(&mut env, &defn),
+        ];
+        for result in results {
+            match result {
+                Err(SolveError::Contradiction(constraints)) => assert_eq!(constraints, expected),
+                _ => panic!("Unexpected result"),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_session_contradiction() {
+        let defn = defn::of_string(&defn::to_string(&wrong_modifier())).unwrap();
+        let center = Coords::new(16, 0, -16);
+        let blue = center.neighbors6()[0];
+        let mut session = Session::new(defn).unwrap();
+        let start = session.progress().clone();
+        assert_eq!(
+            session.apply(blue, Color::Blue),
+            Err(MoveError::Contradiction(BTreeSet::from([center])))
+        );
+        assert_eq!(session.progress(), &start);
+        assert!(session.constraints.is_up_to_date(&start));
+        assert_eq!(session.undo_last(), None);
+        assert_eq!(
+            session.apply(blue, Color::Blue).unwrap_err().to_string(),
+            format!("Contradiction at constraint {}", center)
+        );
+    }
+
+    #[test]
+    pub fn test_solve_error() {
+        let defn = defn::of_string(FIXTURES[1]).unwrap();
+        let err = count_solutions(&defn, &mut Env::new(0), 2).unwrap_err();
+        assert!(matches!(err, SolveError::Timeout));
+        let err = enumerate_solutions(&defn, &mut Env::new(0), 2).unwrap_err();
+        assert!(matches!(err, SolveError::Timeout));
+
+        let err = SolveError::from(defn::of_string("").unwrap_err());
+        assert!(matches!(err, SolveError::Parse(ParseError::LineCount(1))));
+        assert_eq!(
+            err.to_string(),
+            "Wrong number of line in strdefn. Got 1, expected 38, or 33 without header"
+        );
+    }
+
+    #[test]
+    pub fn test_progress() {
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let mut progress = Progress::of_defn(&defn);
+        let unknowns = progress.unknowns.clone();
+        assert_eq!(progress.unknown_count(), unknowns.len());
+        assert!(!progress.is_empty());
+        for (coords, cell) in &defn {
+            match defn::color_of_cell(cell) {
+                None => assert!(!progress.is_known(coords)),
+                Some(_) => assert_eq!(progress.is_known(coords), !unknowns.contains(coords)),
+            }
+        }
+
+        // Discover the unknown cells one by one
+        for (i, coords) in unknowns.iter().enumerate() {
+            let color = defn::color_of_cell(&defn[coords]).unwrap();
+            assert!(!progress.is_known(coords));
+            progress.update(BTreeMap::from([(*coords, color)]));
+            assert!(progress.is_known(coords));
+            assert_eq!(progress.unknown_count(), unknowns.len() - i - 1);
+            match color {
+                Color::Blue => assert!(progress.blues().contains(coords)),
+                Color::Black => assert!(progress.blacks().contains(coords)),
+            }
+            assert!(progress.blues().is_disjoint(progress.blacks()));
+        }
+        assert!(progress.is_empty());
+        assert!(progress.is_solved());
+    }
+
+    #[test]
+    pub fn test_findings_constructors() {
+        let c = Coords::new(1, -1, 0);
+        let local = Findings::new_local(c);
+        assert_eq!(local.difficulty(), &Difficulty::Local(1));
+        assert_eq!(local.cells(), &BTreeSet::from([c]));
+        assert!(local.reasons().is_empty());
+        let global = Findings::new_global(c);
+        assert_eq!(global.difficulty(), &Difficulty::Global(1));
+        assert_eq!(global.cells(), &BTreeSet::from([c]));
+        assert_eq!(
+            difficulty_of_findings_vec(&vec![local, global]),
+            (Some(1), Some(1))
+        );
+    }
+
+    #[test]
+    pub fn test_difficulty_score() {
+        let c = Coords::new(1, -1, 0);
+        let with = |difficulty, n| -> Vec<Findings> {
+            (0..n)
+                .map(|_| Findings {
+                    difficulty,
+                    ..Findings::new_local(c)
+                })
+                .collect()
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert_eq!(difficulty_score(&[]), 1.);
+        let easy = difficulty_score(&with(Difficulty::Local(1), 1));
+        assert!(easy > 1. && easy < 2.);
+        let hard = with(Difficulty::Local(8), 60);
+        assert!(close(difficulty_score(&hard), 1. + 9. * 0.6));
+        let expert = with(Difficulty::Global(3), 60);
+        assert!(close(difficulty_score(&expert), 1. + 9. * 0.6));
+        let mut all = with(Difficulty::Local(8), 60);
+        all.extend(expert);
+        let score = difficulty_score(&all);
+        assert!(score > difficulty_score(&hard) && score < 10.);
+        assert!(close(
+            difficulty_score(&with(Difficulty::Trial(1), 1)),
+            difficulty_score(&with(Difficulty::Local(8), 1))
+        ));
+    }
+
+    #[test]
+    pub fn test_technique_summary() {
+        let c = Coords::new(1, -1, 0);
+        let solved = |difficulties: &[Difficulty]| {
+            let findings_vec = difficulties
+                .iter()
+                .map(|difficulty| Findings {
+                    difficulty: *difficulty,
+                    ..Findings::new_local(c)
+                })
+                .collect();
+            Outcome::Solved(findings_vec).technique_summary()
+        };
+        let (local, global, trial) = (Difficulty::Local, Difficulty::Global, Difficulty::Trial);
+        assert_eq!(solved(&[]), "solvable with trivial only");
+        assert_eq!(solved(&[local(1), local(1)]), "solvable with trivial only");
+        assert_eq!(
+            solved(&[local(1), local(4), local(2)]),
+            "needs compound up to size 4"
+        );
+        assert_eq!(
+            solved(&[local(3), trial(1), local(1)]),
+            "needs trial and error up to depth 1"
+        );
+        assert_eq!(
+            solved(&[global(12), global(5), local(2)]),
+            "needs global over 12 constraints"
+        );
+        assert_eq!(
+            solved(&[global(12), trial(1), global(5), local(2)]),
+            "needs trial and error up to depth 1"
+        );
+        assert_eq!(Outcome::Unsolvable.technique_summary(), "unsolvable");
+        assert_eq!(
+            Outcome::Timeout(vec![]).technique_summary(),
+            "unknown, timed out"
+        );
+
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, None);
+        assert_eq!(outcome.technique_summary(), "needs compound up to size 2");
+    }
+
+    #[test]
+    pub fn test_difficulty_histogram() {
+        let c = Coords::new(1, -1, 0);
+        let findings_vec = vec![
+            Findings::new_local(c),
+            Findings::new_global(c),
+            Findings::new_local(c),
+        ];
+        let histogram = difficulty_histogram(&findings_vec);
+        assert_eq!(histogram.steps, 3);
+        assert_eq!(histogram.local, BTreeMap::from([(1, 2)]));
+        assert_eq!(histogram.global, BTreeMap::from([(1, 1)]));
+        assert!(histogram.trial.is_empty());
+
+        let defn = defn::of_string(FIXTURES[0]).unwrap();
+        match solve(&mut Env::new(60), &defn, None) {
+            Outcome::Solved(findings_vec) => {
+                let histogram = difficulty_histogram(&findings_vec);
+                assert_eq!(histogram.steps, findings_vec.len());
+                let total: usize = histogram
+                    .local
+                    .values()
+                    .chain(histogram.global.values())
+                    .sum();
+                assert_eq!(total, findings_vec.len());
+                let max = |counts: &BTreeMap<u32, usize>| counts.keys().next_back().copied();
+                assert_eq!(
+                    (max(&histogram.local), max(&histogram.global)),
+                    difficulty_of_findings_vec(&findings_vec)
+                );
+            }
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    pub fn test_connected_components() {
+        let c = |q| Coords::new(q, 0, -q);
+        let graph = BTreeMap::from([
+            (c(0), BTreeSet::from([c(1)])),
+            (c(1), BTreeSet::from([c(0), c(2)])),
+            (c(2), BTreeSet::from([c(1)])),
+            (c(3), BTreeSet::new()),
+            (c(4), BTreeSet::from([c(5)])),
+            (c(5), BTreeSet::from([c(4)])),
+        ]);
+        assert_eq!(
+            connected_components(&graph),
+            vec![
+                BTreeSet::from([c(0), c(1), c(2)]),
+                BTreeSet::from([c(3)]),
+                BTreeSet::from([c(4), c(5)]),
+            ]
+        );
+        assert!(connected_components(&BTreeMap::new()).is_empty());
     }
-    Outcome::Solved(history)
 }
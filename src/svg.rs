@@ -0,0 +1,183 @@
+//! Rendering of a puzzle and of its solution as a standalone SVG document.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use defn;
+use defn::Cell;
+use defn::Color;
+use defn::Defn;
+use solver::Findings;
+
+/// Distance from the center of a hexagon to its corners, in pixels.
+const SIZE: f64 = 20.;
+
+fn fill_of_color(color: Color) -> &'static str {
+    match color {
+        Color::Blue => "#05a4eb",
+        Color::Black => "#3e3e3e",
+    }
+}
+
+/// The 6 corners of a flat-top hexagon centered on `(x, y)`, as an SVG `points` attribute.
+fn hexagon_points(x: f64, y: f64) -> String {
+    (0..6)
+        .map(|i| {
+            let angle = (60. * i as f64).to_radians();
+            // Slightly smaller than `SIZE` to leave a gap between neighbors
+            let size = SIZE * 0.92;
+            format!(
+                "{:.2},{:.2}",
+                x + size * angle.cos(),
+                y + size * angle.sin()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Render `defn` as an SVG document, showing every cell with its final color. The cells
+/// discovered by the `i`-th element of `findings` are outlined with a hue that depends on `i` so
+/// that the solve order can be followed, hovering a cell shows its step.
+pub fn render_svg(defn: &Defn, findings: &[Findings]) -> String {
+    let mut step_of_cell = BTreeMap::new();
+    for (i, f) in findings.iter().enumerate() {
+        for coords in f.cells() {
+            step_of_cell.insert(*coords, i);
+        }
+    }
+    let centers: Vec<_> = defn
+        .iter()
+        .filter(|(_, cell)| !matches!(cell, Cell::Empty))
+        .map(|(coords, _)| coords.to_pixel(SIZE))
+        .collect();
+    let (mut xmin, mut ymin, mut xmax, mut ymax) = (0f64, 0f64, 0f64, 0f64);
+    if !centers.is_empty() {
+        xmin = centers.iter().map(|p| p.0).fold(f64::INFINITY, f64::min) - SIZE;
+        ymin = centers.iter().map(|p| p.1).fold(f64::INFINITY, f64::min) - SIZE;
+        xmax = centers
+            .iter()
+            .map(|p| p.0)
+            .fold(f64::NEG_INFINITY, f64::max)
+            + SIZE;
+        ymax = centers
+            .iter()
+            .map(|p| p.1)
+            .fold(f64::NEG_INFINITY, f64::max)
+            + SIZE;
+    }
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{:.2} {:.2} {:.2} {:.2}" width="{:.0}" height="{:.0}">"#,
+        xmin,
+        ymin,
+        xmax - xmin,
+        ymax - ymin,
+        xmax - xmin,
+        ymax - ymin
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="white"/>"#,
+        xmin,
+        ymin,
+        xmax - xmin,
+        ymax - ymin
+    );
+    for (coords, cell) in defn {
+        let (x, y) = coords.to_pixel(SIZE);
+        if let Some(color) = defn::color_of_cell(cell) {
+            let stroke = match step_of_cell.get(coords) {
+                None => String::from(r#"stroke="none""#),
+                Some(i) => {
+                    let hue = 300. * *i as f64 / findings.len().max(1) as f64;
+                    format!(r#"stroke="hsl({:.0}, 90%, 50%)" stroke-width="3""#, hue)
+                }
+            };
+            let title = match step_of_cell.get(coords) {
+                None => String::new(),
+                Some(i) => format!("<title>step {}</title>", i + 1),
+            };
+            let _ = writeln!(
+                svg,
+                r#"<polygon points="{}" fill="{}" {}>{}</polygon>"#,
+                hexagon_points(x, y),
+                fill_of_color(color),
+                stroke,
+                title
+            );
+        }
+        if let Some(label) = defn::label_of_cell(defn, coords) {
+            let fill = match cell {
+                Cell::Line { .. } => "#3e3e3e",
+                _ => "white",
+            };
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.2}" y="{:.2}" fill="{}" font-family="sans-serif" font-size="{:.0}" text-anchor="middle" dominant-baseline="central">{}</text>"#,
+                x,
+                y,
+                fill,
+                SIZE * 0.7,
+                label
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use defn;
+    use env::Env;
+    use solver::{solve, Outcome};
+    use svg::render_svg;
+
+    #[test]
+    pub fn test_render_svg() {
+        let defn = defn::of_string(include_str!("../extra/ml_1.txt")).unwrap();
+        let svg = render_svg(&defn, &[]);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        let colored = defn
+            .values()
+            .filter(|cell| defn::color_of_cell(cell).is_some())
+            .count();
+        assert_eq!(svg.matches("<polygon").count(), colored);
+        let numbered = defn
+            .keys()
+            .filter(|c| defn::number_of_cell(&defn, c).is_some())
+            .count();
+        assert_eq!(svg.matches("<text").count(), numbered);
+    }
+
+    #[test]
+    pub fn test_render_svg_steps() {
+        let defn = defn::of_string(include_str!("../extra/ml_1.txt")).unwrap();
+        let findings = match solve(&mut Env::new(60), &defn, None) {
+            Outcome::Solved(findings) => findings,
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        };
+        let svg = render_svg(&defn, &findings);
+        // Each discovered cell is outlined with the hue and the title of its step
+        for (i, f) in findings.iter().enumerate() {
+            let hue = 300. * i as f64 / findings.len() as f64;
+            let stroke = format!(r#"stroke="hsl({:.0}, 90%, 50%)" stroke-width="3""#, hue);
+            let title = format!("<title>step {}</title>", i + 1);
+            let polygons = svg
+                .lines()
+                .filter(|l| l.contains(&stroke) && l.contains(&title));
+            assert_eq!(polygons.count(), f.cells().len());
+        }
+        // The cells revealed from the start have no outline nor title
+        let revealed = defn::revealed(&defn).count();
+        assert_eq!(svg.matches(r#"stroke="none""#).count(), revealed);
+        assert_eq!(
+            svg.matches("<title>").count(),
+            defn::unknowns(&defn).count()
+        );
+    }
+}
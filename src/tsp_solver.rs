@@ -1,14 +1,15 @@
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
-
+use rand::{Rng, SeedableRng};
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use defn;
+use defn::Defn;
+use env::Env;
 use misc::Coords;
-
-use crate::defn::{Defn, Cell};
-use crate::solver::{Outcome, Findings, Constraints, Progress};
-use crate::env::Env;
-
-use rand::Rng;
-use std::collections::{BTreeMap, BTreeSet};
+use solver::{Constraints, Findings, Outcome, Progress};
 
 /// Ein Individuum stellt eine mögliche Reihenfolge von Koordinaten dar
 #[derive(Clone, Debug)]
@@ -30,39 +31,23 @@ impl TspIndividual {
 pub fn generate_initial_population(
     defn: &Defn,
     population_size: usize,
+    rng: &mut StdRng,
 ) -> Vec<TspIndividual> {
     // Wähle nur die unaufgedeckten, lösbaren Zellen aus
-    let mut solvable_cells: Vec<Coords> = defn
-        .iter()
-        .filter_map(|(coords, cell)| {
-            match cell {
-                Cell::Zone0 { revealed: false, .. }
-                | Cell::Zone6 { revealed: false, .. }
-                | Cell::Zone18 { revealed: false } => Some(*coords),
-                _ => None,
-            }
-        })
-        .collect();
+    let mut solvable_cells: Vec<Coords> = defn::unknowns(defn).map(|(coords, _)| coords).collect();
 
     let mut population = Vec::with_capacity(population_size);
     for _ in 0..population_size {
-        let mut rng = thread_rng();
-        solvable_cells.shuffle(&mut rng);
+        solvable_cells.shuffle(rng);
         population.push(TspIndividual::new(solvable_cells.clone()));
     }
 
     population
 }
 
-
-
 /// Bewertet ein Individuum – je weniger Schritte, desto besser.
 /// Gibt `None` zurück, wenn der Lösungsversuch scheitert (z. B. Reihenfolge unbrauchbar).
-pub fn evaluate_fitness(
-    individual: &mut TspIndividual,
-    defn: &Defn,
-    env: &mut Env,
-) -> Option<u32> {
+pub fn evaluate_fitness(individual: &mut TspIndividual, defn: &Defn, env: &mut Env) -> Option<u32> {
     let mut progress = Progress::of_defn(defn);
     let mut constraints = Constraints::of_defn(defn);
     let mut steps = 0;
@@ -71,57 +56,57 @@ pub fn evaluate_fitness(
     let mut last_unknowns = progress.unknown_count();
     let mut attempts = 0;
 
-    println!("FITNESS: evaluate_fitness gestartet für Individuum");
+    trace!("FITNESS: evaluate_fitness gestartet für Individuum");
 
     while !progress.is_empty() && attempts < max_steps {
-        println!(
+        trace!(
             "-> Step {}: {} Zellen noch ungelöst",
             steps,
             progress.unknown_count()
         );
         attempts += 1;
 
-        let visible_cells: BTreeSet<_> = progress.blacks().union(progress.blues()).cloned().collect();
+        let visible_cells: BTreeSet<_> =
+            progress.blacks().union(progress.blues()).cloned().collect();
         constraints.reveal(&visible_cells);
         constraints.narrow(&visible_cells, &progress);
-        constraints.gc();
-
-        let mut updated = false;
-
-        for coords in &individual.order {
-            if progress.is_known(coords) {
-                continue;
-            }
-
-            // Versuche: triviale Invarianten
-            let mut invariants = constraints.trivial_invariants(defn);
+        if constraints.gc().is_err() {
+            break;
+        }
 
-            // Wenn keine trivialen -> versuche compound
-            if invariants.is_empty() {
-                env.reset_timer();
-                if let Ok((compound, _)) = constraints.compound_invariants(env, defn) {
-                    invariants = compound;
-                }
+        // Die Invarianten hängen nur vom Fortschritt ab, nicht von der Reihenfolge: einmal pro
+        // Schritt berechnen. Versuche: triviale Invarianten
+        env.reset_timer();
+        let mut invariants = constraints
+            .trivial_invariants(env, defn)
+            .unwrap_or_default();
+
+        // Wenn keine trivialen -> versuche compound
+        if invariants.is_empty() {
+            if let Ok((compound, _)) = constraints.compound_invariants(env, defn) {
+                invariants = compound;
             }
+        }
 
-            // Wenn keine compound -> versuche globale Invarianten
-            if invariants.is_empty() {
-                if let Ok(global_invariants) = constraints.global_invariants(env, defn) {
-                    invariants = global_invariants;
-                }
+        // Wenn keine compound -> versuche globale Invarianten
+        if invariants.is_empty() {
+            if let Ok(global_invariants) = constraints.global_invariants(env, defn) {
+                invariants = global_invariants;
             }
+        }
 
-            // Falls etwas gefunden -> anwenden
-            if let Some(color) = invariants.get(coords) {
-                progress.update(BTreeMap::from([(*coords, *color)]));
+        // Die erste noch unbekannte Zelle der Reihenfolge unter den Invarianten anwenden
+        let found = individual
+            .order
+            .iter()
+            .filter(|coords| !progress.is_known(coords))
+            .find_map(|coords| invariants.get(coords).map(|(color, _)| (*coords, *color)));
+        match found {
+            Some((coords, color)) => {
+                progress.update(BTreeMap::from([(coords, color)]));
                 steps += 1;
-                updated = true;
-                break;
             }
-        }
-
-        if !updated {
-            break;
+            None => break,
         }
 
         let current_unknowns = progress.unknown_count();
@@ -132,63 +117,122 @@ pub fn evaluate_fitness(
     }
 
     if progress.is_solved() {
-        println!("GEFUNDEN: Lösung in {} Schritten gefunden", steps);
+        trace!("GEFUNDEN: Lösung in {} Schritten gefunden", steps);
         individual.fitness = Some(steps);
         return Some(steps);
     }
 
-    println!("INDIVID NOT SOLVEABLE: Individuum konnte nicht gelöst werden.");
+    trace!("INDIVID NOT SOLVEABLE: Individuum konnte nicht gelöst werden.");
     individual.fitness = None;
     None
 }
 
+/// Bereits bewertete Reihenfolgen. Die Fitness hängt nur von der Reihenfolge und von `defn` ab,
+/// ein Eintrag bleibt also immer gültig.
+pub type FitnessCache = HashMap<Vec<Coords>, Option<u32>>;
+
+/// Wie `evaluate_fitness`, aber eine bereits bewertete Reihenfolge wird nicht erneut gelöst.
+pub fn evaluate_fitness_cached(
+    individual: &mut TspIndividual,
+    defn: &Defn,
+    env: &mut Env,
+    cache: &mut FitnessCache,
+) -> Option<u32> {
+    if let Some(fitness) = cache.get(&individual.order) {
+        individual.fitness = *fitness;
+        return *fitness;
+    }
+    let fitness = evaluate_fitness(individual, defn, env);
+    cache.insert(individual.order.clone(), fitness);
+    fitness
+}
 
+/// Bewertet alle Individuen parallel, jedes mit seiner eigenen Kopie von `env`. Bereits
+/// bewertete Reihenfolgen kommen aus dem Cache, die neuen werden danach eingetragen.
+pub fn evaluate_population(
+    population: &mut [TspIndividual],
+    defn: &Defn,
+    env: &Env,
+    cache: &mut FitnessCache,
+) {
+    // wasm32 has no threads, the individuals are evaluated one after the other
+    #[cfg(not(target_arch = "wasm32"))]
+    let individuals = population.par_iter_mut();
+    #[cfg(target_arch = "wasm32")]
+    let individuals = population.iter_mut();
+    individuals.for_each(|individual| match cache.get(&individual.order) {
+        Some(fitness) => individual.fitness = *fitness,
+        None => {
+            evaluate_fitness(individual, defn, &mut env.clone());
+        }
+    });
+    for individual in population {
+        cache.insert(individual.order.clone(), individual.fitness);
+    }
+}
 
 /// Wählt ein Individuum mit der besten Fitness aus `k` zufälligen Kandidaten.
 /// Gibt `None` zurück, wenn keine Fitness vorhanden ist (z. B. bei ungültiger Lösung).
-pub fn select_parent(population: &[TspIndividual], k: usize) -> Option<&TspIndividual> {
-    let mut rng = thread_rng();
+pub fn select_parent<'a>(
+    population: &'a [TspIndividual],
+    k: usize,
+    rng: &mut StdRng,
+) -> Option<&'a TspIndividual> {
     let candidates: Vec<_> = population
-        .choose_multiple(&mut rng, k)
+        .choose_multiple(rng, k)
         .filter(|ind| ind.fitness.is_some())
         .collect();
 
-    candidates.into_iter().min_by_key(|ind| ind.fitness.unwrap())
+    candidates
+        .into_iter()
+        .min_by_key(|ind| ind.fitness.unwrap())
+}
+
+/// Zufälliger Abschnitt (start..=end) einer Reihenfolge der Länge `len`.
+fn random_segment(len: usize, rng: &mut StdRng) -> (usize, usize) {
+    let i = rng.gen_range(0..len);
+    let j = rng.gen_range(0..len);
+    if i < j {
+        (i, j)
+    } else {
+        (j, i)
+    }
 }
 
 /// Führt Order Crossover (OX) zwischen zwei Eltern durch und erzeugt ein Kind.
 /// Die Reihenfolge bleibt eine gültige Permutation.
-pub fn crossover(parent1: &TspIndividual, parent2: &TspIndividual) -> TspIndividual {
+pub fn crossover(
+    parent1: &TspIndividual,
+    parent2: &TspIndividual,
+    rng: &mut StdRng,
+) -> TspIndividual {
     let len = parent1.order.len();
-    let mut rng = rand::thread_rng();
 
     // Zufälliger Abschnitt (start..=end) von parent1
-    let (start, end) = {
-        let i = rng.gen_range(0..len);
-        let j = rng.gen_range(0..len);
-        if i < j { (i, j) } else { (j, i) }
-    };
+    let (start, end) = random_segment(len, rng);
 
     //Abschnitt von Parent 1 kopieren
     let mut child_order: Vec<Option<Coords>> = vec![None; len];
-    for i in start..=end {
-        child_order[i] = Some(parent1.order[i]);
+    for (slot, coords) in child_order[start..=end]
+        .iter_mut()
+        .zip(&parent1.order[start..=end])
+    {
+        *slot = Some(*coords);
     }
 
-    //Fehlende Werte aus Parent 2 vorbereiten
+    //Fehlende Werte aus Parent 2 vorbereiten, per HashSet in O(n) statt O(n^2)
+    let placed: HashSet<Coords> = parent1.order[start..=end].iter().cloned().collect();
     let missing_values: Vec<Coords> = parent2
         .order
         .iter()
-        .filter(|c| !child_order.contains(&Some(**c)))
+        .filter(|c| !placed.contains(c))
         .cloned()
         .collect();
 
     //Rest auffüllen
     let mut iter = missing_values.into_iter();
-    for i in 0..len {
-        if child_order[i].is_none() {
-            child_order[i] = Some(iter.next().expect("Fehlender Wert bei Crossover"));
-        }
+    for slot in child_order.iter_mut().filter(|slot| slot.is_none()) {
+        *slot = Some(iter.next().expect("Fehlender Wert bei Crossover"));
     }
 
     // Final: unwrap() der Option<Coords> -> garantiert safe
@@ -197,10 +241,43 @@ pub fn crossover(parent1: &TspIndividual, parent2: &TspIndividual) -> TspIndivid
     TspIndividual::new(final_order)
 }
 
+/// Führt Partially Mapped Crossover (PMX) zwischen zwei Eltern durch und erzeugt ein Kind.
+/// Anders als bei `crossover` behalten die Zellen außerhalb des Abschnitts von parent1 möglichst
+/// ihre absolute Position aus parent2. Die Reihenfolge bleibt eine gültige Permutation.
+pub fn crossover_pmx(
+    parent1: &TspIndividual,
+    parent2: &TspIndividual,
+    rng: &mut StdRng,
+) -> TspIndividual {
+    let len = parent1.order.len();
+
+    // Zufälliger Abschnitt (start..=end) von parent1, an derselben Stelle übernommen
+    let (start, end) = random_segment(len, rng);
+    let mut child_order = parent2.order.clone();
+    child_order[start..=end].copy_from_slice(&parent1.order[start..=end]);
+
+    // Steht eine Zelle von parent2 schon im Abschnitt, wird sie über die Abbildung
+    // parent1[j] -> parent2[j] ersetzt, bis sie außerhalb davon liegt
+    let position: HashMap<Coords, usize> = parent1
+        .order
+        .iter()
+        .enumerate()
+        .map(|(i, coords)| (*coords, i))
+        .collect();
+    for i in (0..start).chain(end + 1..len) {
+        let mut coords = parent2.order[i];
+        while let Some(j) = position.get(&coords).filter(|j| (start..=end).contains(*j)) {
+            coords = parent2.order[*j];
+        }
+        child_order[i] = coords;
+    }
+
+    TspIndividual::new(child_order)
+}
+
 /// Mutiert ein Individuum mit gegebener Wahrscheinlichkeit.
 /// Swap-Mutation: Tausche zwei zufällige Zellen.
-pub fn mutate(individual: &mut TspIndividual, mutation_rate: f64) {
-    let mut rng = thread_rng();
+pub fn mutate(individual: &mut TspIndividual, mutation_rate: f64, rng: &mut StdRng) {
     if rng.gen::<f64>() < mutation_rate {
         let len = individual.order.len();
         if len < 2 {
@@ -217,26 +294,206 @@ pub fn mutate(individual: &mut TspIndividual, mutation_rate: f64) {
     }
 }
 
+/// Mutiert ein Individuum mit gegebener Wahrscheinlichkeit.
+/// Inversions-Mutation: Kehre einen zufälligen Abschnitt um, die Nachbarschaften darin bleiben
+/// erhalten.
+pub fn mutate_inversion(individual: &mut TspIndividual, mutation_rate: f64, rng: &mut StdRng) {
+    if rng.gen::<f64>() < mutation_rate {
+        let len = individual.order.len();
+        if len < 2 {
+            return;
+        }
+
+        let i = rng.gen_range(0..len);
+        let mut j = rng.gen_range(0..len);
+        while j == i {
+            j = rng.gen_range(0..len);
+        }
+
+        individual.order[i.min(j)..=i.max(j)].reverse();
+    }
+}
+
+/// Die Mutation, die `evolve` auf jedes Kind anwendet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mutation {
+    /// `mutate`
+    #[default]
+    Swap,
+    /// `mutate_inversion`
+    Inversion,
+}
+
+/// Das Crossover, mit dem `evolve` die Kinder erzeugt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Crossover {
+    /// `crossover`
+    #[default]
+    Order,
+    /// `crossover_pmx`
+    PartiallyMapped,
+}
+
+/// Die Mutationsrate, mit der `evolve` die Kinder mutiert.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MutationRate {
+    /// Dieselbe Rate in jeder Generation.
+    Fixed(f64),
+    /// Startet bei `base` und passt sich nach jeder Generation an die `fitness_diversity` der
+    /// Population an: liegt sie unter `threshold`, ist die Population dabei zu konvergieren und
+    /// die Rate verdoppelt sich bis höchstens `max`, um aus lokalen Optima herauszufinden.
+    /// Sonst halbiert sich die Rate wieder bis mindestens `min`.
+    Adaptive {
+        base: f64,
+        min: f64,
+        max: f64,
+        threshold: f64,
+    },
+}
+
+impl MutationRate {
+    /// Die Rate der ersten Generation.
+    pub fn initial(&self) -> f64 {
+        match self {
+            MutationRate::Fixed(rate) => *rate,
+            MutationRate::Adaptive { base, .. } => *base,
+        }
+    }
+
+    /// Die Rate der nächsten Generation, nach einer Generation mit `rate` und `diversity`.
+    pub fn next(&self, rate: f64, diversity: f64) -> f64 {
+        match self {
+            MutationRate::Fixed(rate) => *rate,
+            MutationRate::Adaptive {
+                min,
+                max,
+                threshold,
+                ..
+            } => {
+                if diversity < *threshold {
+                    (rate * 2.).min(*max)
+                } else {
+                    (rate / 2.).max(*min)
+                }
+            }
+        }
+    }
+}
+
+/// Streuung der Fitness in der Population: Standardabweichung geteilt durch den Mittelwert der
+/// gültigen Fitnesswerte. 0 wenn alle gleich sind oder weniger als zwei gültig sind.
+pub fn fitness_diversity(population: &[TspIndividual]) -> f64 {
+    let values: Vec<f64> = population
+        .iter()
+        .filter_map(|ind| ind.fitness)
+        .map(f64::from)
+        .collect();
+    if values.len() < 2 {
+        return 0.;
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    if mean == 0. {
+        return 0.;
+    }
+    let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    variance.sqrt() / mean
+}
+
+/// Das Individuum mit der besten gültigen Fitness, `None` wenn keines gültig ist.
+fn best_of(population: &[TspIndividual]) -> Option<TspIndividual> {
+    population
+        .iter()
+        .filter(|ind| ind.fitness.is_some())
+        .min_by_key(|ind| ind.fitness)
+        .cloned()
+}
+
+/// Die Parameter von `evolve`, die Standardwerte sind die von `run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvolveConfig {
+    /// Anzahl der Individuen jeder Generation.
+    pub population_size: usize,
+    /// Höchstzahl der Generationen.
+    pub generations: usize,
+    /// Größe der Turniere von `select_parent`.
+    pub tournament_k: usize,
+    pub mutation_rate: MutationRate,
+    pub crossover: Crossover,
+    pub mutation: Mutation,
+    /// Anzahl der besten Individuen, die unverändert in die nächste Generation kommen.
+    pub elitism: usize,
+    /// Die Evolution endet vorzeitig, sobald ein Individuum diese Fitness erreicht oder
+    /// unterbietet.
+    pub target_fitness: Option<u32>,
+    /// Die Evolution endet vorzeitig nach so vielen Generationen ohne Verbesserung.
+    pub patience: Option<usize>,
+    /// Mit demselben `seed` verläuft die Evolution identisch, ohne `seed` wird der
+    /// Zufallsgenerator aus dem Betriebssystem initialisiert.
+    pub seed: Option<u64>,
+}
+
+impl Default for EvolveConfig {
+    fn default() -> EvolveConfig {
+        EvolveConfig {
+            population_size: 50,
+            generations: 100,
+            tournament_k: 5,
+            mutation_rate: MutationRate::Fixed(0.1),
+            crossover: Crossover::default(),
+            mutation: Mutation::default(),
+            elitism: 2,
+            target_fitness: None,
+            patience: None,
+            seed: None,
+        }
+    }
+}
 
 /// Führt den genetischen Algorithmus über mehrere Generationen aus.
-/// Gibt das beste gefundene Individuum zurück.
-pub fn evolve(
-    defn: &Defn,
-    env: &mut Env,
-    population_size: usize,
-    generations: usize,
-    tournament_k: usize,
-    mutation_rate: f64,
-    elitism: usize,
-) -> Option<TspIndividual> {
+/// Gibt das beste über alle Generationen gefundene Individuum zurück.
+pub fn evolve(defn: &Defn, env: &mut Env, config: &EvolveConfig) -> Option<TspIndividual> {
+    let EvolveConfig {
+        population_size,
+        generations,
+        tournament_k,
+        mutation_rate,
+        crossover: crossover_op,
+        mutation,
+        elitism,
+        target_fitness,
+        patience,
+        seed,
+    } = *config;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
     // Initiale Population erzeugen und bewerten
-    let mut population = generate_initial_population(defn, population_size);
-    for individual in &mut population {
-        evaluate_fitness(individual, defn, env);
-    }
+    let mut cache = FitnessCache::new();
+    let mut population = generate_initial_population(defn, population_size, &mut rng);
+    evaluate_population(&mut population, defn, env, &mut cache);
+    let mut rate = mutation_rate.initial();
+    let mut best = best_of(&population);
+    let mut stagnant = 0;
 
     for gen in 0..generations {
-        println!("GENERATION STARTED: Generation {} gestartet...", gen);
+        // Frühzeitiger Abbruch, wenn das Ziel erreicht ist
+        if let (Some(target), Some(fitness)) =
+            (target_fitness, best.as_ref().and_then(|b| b.fitness))
+        {
+            if fitness <= target {
+                debug!(
+                    "TARGET REACHED: Ziel {} erreicht mit {} Schritten.",
+                    target, fitness
+                );
+                break;
+            }
+        }
+        debug!(
+            "GENERATION STARTED: Generation {} gestartet, Mutation: {:.2}...",
+            gen, rate
+        );
 
         let mut next_gen = Vec::new();
 
@@ -246,61 +503,79 @@ pub fn evolve(
 
         //  Eltern + Crossover + Mutation
         while next_gen.len() < population_size {
-            let parent1 = select_parent(&population, tournament_k)?;
-            let parent2 = select_parent(&population, tournament_k)?;
-
-            let mut child = crossover(parent1, parent2);
-            mutate(&mut child, mutation_rate);
-            evaluate_fitness(&mut child, defn, env);
+            let parent1 = select_parent(&population, tournament_k, &mut rng)?;
+            let parent2 = select_parent(&population, tournament_k, &mut rng)?;
+
+            let mut child = match crossover_op {
+                Crossover::Order => crossover(parent1, parent2, &mut rng),
+                Crossover::PartiallyMapped => crossover_pmx(parent1, parent2, &mut rng),
+            };
+            match mutation {
+                Mutation::Swap => mutate(&mut child, rate, &mut rng),
+                Mutation::Inversion => mutate_inversion(&mut child, rate, &mut rng),
+            }
             next_gen.push(child);
         }
+        // Die Eliten sind schon bewertet
+        evaluate_population(&mut next_gen[elitism..], defn, env, &mut cache);
 
         population = next_gen;
+        rate = mutation_rate.next(rate, fitness_diversity(&population));
+
+        // Stagnation zählen, bis `patience` Generationen ohne Verbesserung vergangen sind
+        match (best_of(&population), &best) {
+            (Some(candidate), Some(b)) if candidate.fitness >= b.fitness => stagnant += 1,
+            (None, _) => stagnant += 1,
+            (candidate, _) => {
+                best = candidate;
+                stagnant = 0;
+            }
+        }
+        if patience.is_some_and(|patience| stagnant >= patience) {
+            debug!("STAGNATION: {} Generationen ohne Verbesserung.", stagnant);
+            break;
+        }
     }
 
-    // Bestes Ergebnis zurückgeben
-    population.into_iter().min_by_key(|ind| ind.fitness.unwrap_or(u32::MAX))
+    // Bestes Ergebnis zurückgeben, ohne gültiges Individuum das der letzten Generation
+    best.or_else(|| {
+        population
+            .into_iter()
+            .min_by_key(|ind| ind.fitness.unwrap_or(u32::MAX))
+    })
 }
 
-
-/// führt den TSP_Solver aus
-pub fn run(env: &mut Env, defn: &Defn, verbose: bool) -> Outcome {
-    let population_size = 50;
-    let generations = 100;
-    let tournament_k = 5;
-    let mutation_rate = 0.1;
-    let elitism = 2;
+/// führt den TSP_Solver aus, siehe `evolve` für `seed`
+pub fn run(env: &mut Env, defn: &Defn, verbose: bool, seed: Option<u64>) -> Outcome {
+    let config = EvolveConfig {
+        seed,
+        ..EvolveConfig::default()
+    };
 
     if verbose {
-        println!("RUNNING: TSP-Solver läuft...");
-        println!(
+        info!("RUNNING: TSP-Solver läuft...");
+        info!(
             "-> Population: {}, Generationen: {}, Mutation: {:.2}, Elitismus: {}",
-            population_size, generations, mutation_rate, elitism
+            config.population_size,
+            config.generations,
+            config.mutation_rate.initial(),
+            config.elitism
         );
     }
 
-    let best = evolve(
-        defn,
-        env,
-        population_size,
-        generations,
-        tournament_k,
-        mutation_rate,
-        elitism,
-    );
+    let best = evolve(defn, env, &config);
 
     match best {
         Some(individual) => {
-            let steps = individual.fitness.unwrap_or(u32::MAX);
-            println!("FOUND: Beste Lösung gefunden mit {} Schritten.", steps);
-    
             if verbose {
-                println!("ORDER: Besuchsreihenfolge der Zellen:");
+                let steps = individual.fitness.unwrap_or(u32::MAX);
+                info!("FOUND: Beste Lösung gefunden mit {} Schritten.", steps);
+                info!("ORDER: Besuchsreihenfolge der Zellen:");
                 for (i, coords) in individual.order.iter().enumerate() {
-                    println!("  {:2}. {:?}", i + 1, coords);
+                    info!("  {:2}. {}", i + 1, coords);
                 }
             }
-    
+
             let findings_vec: Vec<Findings> = individual
                 .order
                 .iter()
@@ -309,8 +584,221 @@ pub fn run(env: &mut Env, defn: &Defn, verbose: bool) -> Outcome {
             Outcome::Solved(findings_vec)
         }
         None => {
-            println!("ERROR: Keine gültige Lösung gefunden.");
+            warn!("ERROR: Keine gültige Lösung gefunden.");
             Outcome::Unsolvable
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use defn;
+
+    #[test]
+    pub fn test_fitness_cache() {
+        let defn = defn::of_string(include_str!("../extra/ml_1.txt")).unwrap();
+        let mut env = Env::new(60);
+        let mut cache = FitnessCache::new();
+        let mut individual =
+            generate_initial_population(&defn, 1, &mut StdRng::seed_from_u64(0)).remove(0);
+        let fitness = evaluate_fitness_cached(&mut individual, &defn, &mut env, &mut cache);
+        assert!(fitness.is_some());
+        assert_eq!(cache.len(), 1);
+
+        // Same order, served by the cache
+        let mut twin = TspIndividual::new(individual.order.clone());
+        assert_eq!(
+            evaluate_fitness_cached(&mut twin, &defn, &mut env, &mut cache),
+            fitness
+        );
+        assert_eq!(twin.fitness, fitness);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(evaluate_fitness(&mut twin, &defn, &mut env), fitness);
+    }
+
+    #[test]
+    pub fn test_evaluate_population() {
+        let defn = defn::of_string(include_str!("../extra/ml_1.txt")).unwrap();
+        let env = Env::new(60);
+        let mut cache = FitnessCache::new();
+        let mut population = generate_initial_population(&defn, 8, &mut StdRng::seed_from_u64(0));
+        evaluate_population(&mut population, &defn, &env, &mut cache);
+        for individual in &mut population {
+            assert!(individual.fitness.is_some());
+            assert_eq!(cache[&individual.order], individual.fitness);
+            let fitness = individual.fitness;
+            assert_eq!(
+                evaluate_fitness(individual, &defn, &mut env.clone()),
+                fitness
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_crossover_is_a_permutation() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let population = generate_initial_population(&defn, 10, &mut rng);
+        let cells: BTreeSet<_> = population[0].order.iter().cloned().collect();
+        assert_eq!(cells.len(), population[0].order.len());
+        for _ in 0..100 {
+            for op in [crossover, crossover_pmx] {
+                let child = op(&population[0], &population[1], &mut rng);
+                assert_eq!(child.order.len(), cells.len());
+                assert_eq!(child.order.iter().cloned().collect::<BTreeSet<_>>(), cells);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_crossover_pmx() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let population = generate_initial_population(&defn, 2, &mut rng);
+        let (parent1, parent2) = (&population[0], &population[1]);
+        for _ in 0..100 {
+            let child = crossover_pmx(parent1, parent2, &mut rng);
+            // Each cell exactly once
+            let mut counts = BTreeMap::new();
+            for coords in child.order.iter() {
+                *counts.entry(*coords).or_insert(0) += 1;
+            }
+            assert_eq!(counts.len(), parent1.order.len());
+            assert!(counts.values().all(|count| *count == 1));
+        }
+
+        // Identical parents give the same child
+        let child = crossover_pmx(parent1, parent1, &mut rng);
+        assert_eq!(child.order, parent1.order);
+    }
+
+    #[test]
+    pub fn test_mutation_rate() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut population = generate_initial_population(&defn, 4, &mut rng);
+        assert_eq!(fitness_diversity(&population), 0.);
+        for (ind, fitness) in population.iter_mut().zip([2, 2, 2, 2]) {
+            ind.fitness = Some(fitness);
+        }
+        assert_eq!(fitness_diversity(&population), 0.);
+        for (ind, fitness) in population.iter_mut().zip([1, 3, 1, 3]) {
+            ind.fitness = Some(fitness);
+        }
+        assert_eq!(fitness_diversity(&population), 0.5);
+
+        let fixed = MutationRate::Fixed(0.1);
+        assert_eq!(fixed.initial(), 0.1);
+        assert_eq!(fixed.next(0.1, 0.), 0.1);
+
+        let adaptive = MutationRate::Adaptive {
+            base: 0.1,
+            min: 0.05,
+            max: 0.3,
+            threshold: 0.2,
+        };
+        let mut rate = adaptive.initial();
+        assert_eq!(rate, 0.1);
+        // Converged population: up to `max`
+        for expected in [0.2, 0.3, 0.3] {
+            rate = adaptive.next(rate, 0.1);
+            assert_eq!(rate, expected);
+        }
+        // Diverse again: down to `min`
+        for expected in [0.15, 0.075, 0.05] {
+            rate = adaptive.next(rate, 0.5);
+            assert!((rate - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    pub fn test_evolve_early_stop() {
+        let defn = defn::of_string(include_str!("../extra/ml_1.txt")).unwrap();
+        let evolve = |generations, target_fitness, patience| {
+            let config = EvolveConfig {
+                population_size: 4,
+                generations,
+                tournament_k: 2,
+                elitism: 1,
+                target_fitness,
+                patience,
+                seed: Some(0),
+                ..EvolveConfig::default()
+            };
+            evolve(&defn, &mut Env::new(60), &config).unwrap()
+        };
+
+        // The initial population already reaches any target, the evolution stops right away
+        let best = evolve(usize::MAX, Some(u32::MAX), None);
+        let initial = {
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut population = generate_initial_population(&defn, 4, &mut rng);
+            evaluate_population(
+                &mut population,
+                &defn,
+                &Env::new(60),
+                &mut FitnessCache::new(),
+            );
+            best_of(&population).unwrap()
+        };
+        assert_eq!(best.order, initial.order);
+
+        // Without improvement, the evolution stops after `patience` generations
+        let best = evolve(usize::MAX, None, Some(3));
+        assert!(best.fitness.unwrap() <= initial.fitness.unwrap());
+    }
+
+    #[test]
+    pub fn test_seed() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let run = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut population = generate_initial_population(&defn, 10, &mut rng);
+            population[0].fitness = Some(1);
+            population[1].fitness = Some(2);
+            let parent = select_parent(&population, 10, &mut rng).unwrap().clone();
+            let mut child = crossover(&parent, &population[1], &mut rng);
+            mutate(&mut child, 1., &mut rng);
+            population.push(child);
+            population
+                .into_iter()
+                .map(|ind| ind.order)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    pub fn test_mutate_inversion() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let original = generate_initial_population(&defn, 1, &mut rng).remove(0);
+        let cells: BTreeSet<_> = original.order.iter().cloned().collect();
+
+        let mut individual = original.clone();
+        mutate_inversion(&mut individual, 0., &mut rng);
+        assert_eq!(individual.order, original.order);
+
+        for _ in 0..100 {
+            let mut individual = original.clone();
+            mutate_inversion(&mut individual, 1., &mut rng);
+            assert_ne!(individual.order, original.order);
+            assert_eq!(
+                individual.order.iter().cloned().collect::<BTreeSet<_>>(),
+                cells
+            );
+            // A single reversed segment, the cells outside of it are untouched
+            let start = (0..cells.len())
+                .find(|&i| individual.order[i] != original.order[i])
+                .unwrap();
+            let end = (0..cells.len())
+                .rfind(|&i| individual.order[i] != original.order[i])
+                .unwrap();
+            let mut reversed = original.order[start..=end].to_vec();
+            reversed.reverse();
+            assert_eq!(individual.order[start..=end], reversed[..]);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! JavaScript entry point of the solver, built with the `wasm` feature.
+
+use serde_json;
+use wasm_bindgen::prelude::*;
+
+use defn;
+use env::Env;
+use solver;
+
+/// The per-step budget of `solve_json`, in seconds.
+const TIMEOUT_SECS: u64 = 60;
+
+/// Parse `puzzle` with `of_string`, solve it and return the `Outcome` as JSON. A puzzle that can't
+/// be parsed gives `{"error": <message>}`.
+#[wasm_bindgen]
+pub fn solve_json(puzzle: &str) -> String {
+    let defn = match defn::of_string(puzzle) {
+        Ok(defn) => defn,
+        Err(err) => return serde_json::json!({ "error": err.to_string() }).to_string(),
+    };
+    let outcome = solver::solve(&mut Env::new(TIMEOUT_SECS), &defn, None);
+    serde_json::to_string(&outcome).expect("Unreachable")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use solver::Outcome;
+    use wasm::solve_json;
+
+    #[test]
+    pub fn test_solve_json() {
+        let json = solve_json(include_str!("../extra/ml_1.txt"));
+        let outcome: Outcome = serde_json::from_str(&json).unwrap();
+        assert!(matches!(outcome, Outcome::Solved(_)));
+
+        let json: serde_json::Value = serde_json::from_str(&solve_json("")).unwrap();
+        assert!(json["error"].is_string());
+    }
+}